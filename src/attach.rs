@@ -0,0 +1,75 @@
+use std::io::{ErrorKind, Result};
+use std::path::Path;
+
+use log::{debug, error, warn};
+
+use crate::channel::{connect_failed, MessageChannel};
+use crate::messages as msg;
+use crate::raw::blocking::connect;
+use crate::relay;
+use crate::runtime;
+use crate::socket::Socket;
+
+pub(crate) struct Args<'a> {
+    pub connect: &'a Path,
+    pub selector: msg::JobSelector,
+}
+
+async fn follow(socket: Socket, selector: msg::JobSelector) -> Result<i32> {
+    let mut channel = MessageChannel::new(socket);
+    let result: msg::AttachResult = channel
+        .request(&msg::RequestInput::Attach(selector))
+        .await?;
+    if !result.found {
+        error!("no job with {}", describe(selector));
+        return Ok(1);
+    }
+    if !result.captured {
+        error!("job with {} does not relay its output", describe(selector));
+        return Ok(1);
+    }
+
+    let socket = channel.socket();
+    let mut buffer = vec![0; 4096];
+    loop {
+        let received = socket.recv(&mut buffer).await?;
+        if received == 0 {
+            warn!("server disconnected");
+            return Err(ErrorKind::ConnectionAborted.into());
+        }
+        match msg::decode_request_ref(&buffer[..received])? {
+            msg::Attached::Data(stream, data) => {
+                relay::write_output(stream, data)?
+            }
+            msg::Attached::Ended(state) => {
+                debug!("job ended {:?}", state);
+                return Ok(match state {
+                    msg::JobState::Exited(code) => code,
+                    msg::JobState::Signaled(sig) => 128 + sig,
+                    msg::JobState::Running => {
+                        warn!("fell behind job output, stopped following");
+                        1
+                    }
+                    msg::JobState::Unknown => 1,
+                });
+            }
+        }
+    }
+}
+
+fn describe(selector: msg::JobSelector) -> String {
+    match selector {
+        msg::JobSelector::Id(id) => format!("id {}", id),
+        msg::JobSelector::Pid(pid) => format!("pid {}", pid),
+    }
+}
+
+pub(crate) fn command(args: &Args) -> Result<i32> {
+    debug!("connecting to {:?}", args.connect);
+    match connect(args.connect) {
+        Ok(fd) => runtime::new()?.block_on(async {
+            follow(Socket::from_fd(fd)?, args.selector).await
+        }),
+        Err(err) => Ok(connect_failed(args.connect, &err)),
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use futures::channel::mpsc;
+use lazy_static::lazy_static;
+use log::debug;
+
+use crate::messages::Files;
+
+// Output kept for an attach to start with, whole chunks are dropped
+const BACKLOG_SIZE: usize = 64 * 1024;
+
+// Chunks queued for a subscriber before it is dropped as too slow
+const SUBSCRIBER_QUEUE: usize = 64;
+
+pub(crate) type Chunk = (Files, Vec<u8>);
+
+#[derive(Default)]
+struct Capture {
+    backlog: VecDeque<Chunk>,
+    size: usize,
+    subscribers: Vec<mpsc::Sender<Chunk>>,
+}
+
+fn captures() -> &'static Mutex<HashMap<u64, Capture>> {
+    lazy_static! {
+        static ref GLOBALS: Mutex<HashMap<u64, Capture>> =
+            Mutex::new(HashMap::new());
+    }
+
+    &GLOBALS
+}
+
+/// Starts keeping relayed output of `job`.
+pub(crate) fn open(job: u64) {
+    captures().lock().unwrap().insert(job, Capture::default());
+}
+
+/// Ends subscriptions to `job`, they see their stream finish.
+pub(crate) fn close(job: u64) {
+    captures().lock().unwrap().remove(&job);
+}
+
+pub(crate) fn publish(job: u64, stream: Files, data: &[u8]) {
+    let mut captures = captures().lock().unwrap();
+    let capture = match captures.get_mut(&job) {
+        Some(capture) => capture,
+        None => return,
+    };
+
+    // a subscriber falling behind is dropped rather than slowing the job
+    let subscribers = std::mem::take(&mut capture.subscribers);
+    capture.subscribers = subscribers
+        .into_iter()
+        .filter_map(|mut subscriber| {
+            match subscriber.try_send((stream, data.to_vec())) {
+                Ok(()) => Some(subscriber),
+                Err(err) => {
+                    if err.is_full() {
+                        debug!("job={} dropping slow subscriber", job);
+                    }
+                    None
+                }
+            }
+        })
+        .collect();
+
+    capture.size += data.len();
+    capture.backlog.push_back((stream, data.to_vec()));
+    while capture.size > BACKLOG_SIZE {
+        match capture.backlog.pop_front() {
+            Some((_, chunk)) => capture.size -= chunk.len(),
+            None => break,
+        }
+    }
+}
+
+/// Output of `job` kept so far and a stream of what follows, `None`
+/// unless the job relays its output.
+pub(crate) fn subscribe(
+    job: u64,
+) -> Option<(Vec<Chunk>, mpsc::Receiver<Chunk>)> {
+    let mut captures = captures().lock().unwrap();
+    let capture = captures.get_mut(&job)?;
+    let (subscriber, chunks) = mpsc::channel(SUBSCRIBER_QUEUE);
+    capture.subscribers.push(subscriber);
+    Some((capture.backlog.iter().cloned().collect(), chunks))
+}
@@ -0,0 +1,93 @@
+use std::io::{Error as IoError, ErrorKind, Result};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::messages as msg;
+use crate::raw::blocking;
+use crate::socket::Socket;
+
+const RECV_SIZE: usize = 65536;
+
+/// Logs why connecting to the server at `path` failed, returns the exit
+/// code clients give for it.
+pub(crate) fn connect_failed(path: &Path, err: &IoError) -> i32 {
+    let socket = path.to_string_lossy();
+    let is_socket = || match std::fs::metadata(path) {
+        Ok(meta) => meta.file_type().is_socket(),
+        Err(_) => false,
+    };
+    match err.kind() {
+        ErrorKind::NotFound => error!("no server at {}", socket),
+        // nothing is left behind in the abstract namespace
+        ErrorKind::ConnectionRefused if blocking::is_abstract(path) => {
+            error!("no server at {}", socket)
+        }
+        ErrorKind::ConnectionRefused if is_socket() => error!(
+            "stale socket {} (no listener), is the server running?",
+            socket
+        ),
+        ErrorKind::PermissionDenied => {
+            error!("permission denied connecting to {}", socket)
+        }
+        _ => error!(
+            "failed to connect\n    \
+             socket: {}\n    \
+             error:  {}",
+            socket, err,
+        ),
+    }
+    128
+}
+
+/// Serde messages over a `Socket`, one message per packet or frame.
+pub(crate) struct MessageChannel {
+    socket: Socket,
+    buffer: Vec<u8>,
+}
+
+impl MessageChannel {
+    pub fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            buffer: Vec::with_capacity(16),
+        }
+    }
+
+    pub async fn send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        self.buffer.clear();
+        msg::encode_request(&mut self.buffer, message)?;
+        self.socket.send(&self.buffer).await.map(drop)
+    }
+
+    /// Returns `None` once the peer closed the connection.
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        self.buffer.clear();
+        self.buffer.resize(RECV_SIZE, 0);
+        let received = self.socket.recv(&mut self.buffer).await?;
+        debug!("response received {:?} bytes", received);
+        match received {
+            0 => Ok(None),
+            size => msg::decode_request(&self.buffer[..size]).map(Some),
+        }
+    }
+
+    pub async fn request<Q, R>(&mut self, request: &Q) -> Result<R>
+    where
+        Q: Serialize,
+        R: DeserializeOwned,
+    {
+        self.send(request).await?;
+        match self.recv().await? {
+            Some(response) => Ok(response),
+            None => Err(ErrorKind::ConnectionAborted.into()),
+        }
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+}
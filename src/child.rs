@@ -3,12 +3,20 @@ use crate::messages::{self as msg, Files, StartMode};
 use crate::raw::{Fd, RawFd};
 use crate::system;
 use crate::tty;
-use std::io::Error as IoError;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::process::CommandExt;
-use std::path::PathBuf;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
+// Passed descriptors are close-on-exec in the server, the child gets
+// dup2'ed copies on 0, 1 and 2 which survive exec. `fds` holds only
+// the streams in `req`, in that order, the rest become /dev/null.
 pub(crate) fn setup_command_streams(
     command: &mut Command,
     req: Files,
@@ -59,76 +67,606 @@ fn kill_self_if_parent_exits(
     Ok(())
 }
 
-fn prepare(req: &msg::ProcessRequest, parent: system::Pid) -> Command {
-    let mut cmd = Command::new(&req.program);
-    cmd.args(req.argv);
+// pre_exec failures reach the parent as a bare errno, the failed step
+// is packed into the bits above it.
+const STAGE_SHIFT: i32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    SetGid = 1,
+    SetUid,
+    Chdir,
+    Fchdir,
+    DeathSignal,
+    DetachTerminal,
+    ProcessGroup,
+    Session,
+    Nohup,
+    NoCore,
+    ControllingTerminal,
+    ResourceLimits,
+    Descriptors,
+    SetGroups,
+    Chroot,
+}
+
+impl Stage {
+    const ALL: [Stage; 15] = [
+        Stage::SetGroups,
+        Stage::SetGid,
+        Stage::Chroot,
+        Stage::SetUid,
+        Stage::Chdir,
+        Stage::Fchdir,
+        Stage::Descriptors,
+        Stage::DeathSignal,
+        Stage::DetachTerminal,
+        Stage::ProcessGroup,
+        Stage::Session,
+        Stage::ControllingTerminal,
+        Stage::Nohup,
+        Stage::NoCore,
+        Stage::ResourceLimits,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::SetGroups => "setgroups",
+            Stage::SetGid => "setgid",
+            Stage::Chroot => "chroot",
+            Stage::SetUid => "setuid",
+            Stage::Chdir => "chdir",
+            Stage::Fchdir => "fchdir",
+            Stage::DeathSignal => "set death signal",
+            Stage::DetachTerminal => "detach terminal",
+            Stage::ProcessGroup => "setpgid",
+            Stage::Session => "setsid",
+            Stage::Nohup => "nohup",
+            Stage::NoCore => "disable core dumps",
+            Stage::ControllingTerminal => "set controlling terminal",
+            Stage::ResourceLimits => "set resource limits",
+            Stage::Descriptors => "map descriptors",
+        }
+    }
+}
+
+fn tag<T>(stage: Stage, res: Result<T, IoError>) -> Result<T, IoError> {
+    res.map_err(|err| {
+        // anything outside the low bits would change the stage
+        let errno = match err.raw_os_error() {
+            Some(errno) if errno > 0 && errno < 1 << STAGE_SHIFT => errno,
+            _ => libc::EINVAL,
+        };
+        IoError::from_raw_os_error((stage as i32) << STAGE_SHIFT | errno)
+    })
+}
+
+#[derive(Debug)]
+pub(crate) struct StageError {
+    stage: Stage,
+    pub errno: i32,
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed: {}",
+            self.stage.name(),
+            IoError::from_raw_os_error(self.errno)
+        )
+    }
+}
+
+impl std::error::Error for StageError {}
+
+fn untag(err: IoError) -> IoError {
+    let code = match err.raw_os_error() {
+        Some(code) => code,
+        None => return err,
+    };
+    let index = code >> STAGE_SHIFT;
+    let errno = code & ((1 << STAGE_SHIFT) - 1);
+    match Stage::ALL.iter().find(|s| **s as i32 == index) {
+        Some(stage) => IoError::new(
+            IoError::from_raw_os_error(errno).kind(),
+            StageError {
+                stage: *stage,
+                errno,
+            },
+        ),
+        None => err,
+    }
+}
+
+pub(crate) fn errno(err: &IoError) -> i32 {
+    err.raw_os_error()
+        .or_else(|| {
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<StageError>())
+                .map(|e| e.errno)
+        })
+        .unwrap_or(-1)
+}
+
+// Size exec will see: strings with terminating NUL and the pointer
+// arrays. Environment is inherited with request values on top.
+fn exec_size(req: &msg::ProcessRequest) -> usize {
+    let ptr = std::mem::size_of::<*const u8>();
+    let string = |len: usize| len + 1 + ptr;
+    let pair = |k: usize, v: usize| string(k + 1 + v);
+
+    let args: usize = std::iter::once(req.program)
+        .chain(req.argv.iter().cloned())
+        .map(|a| string(a.len()))
+        .sum();
+    let requested: usize =
+        req.env.iter().map(|(k, v)| pair(k.len(), v.len())).sum();
+    let inherited: usize = std::env::vars_os()
+        .filter(|(k, _)| !req.env.iter().any(|(rk, _)| k.as_bytes() == *rk))
+        .map(|(k, v)| pair(k.len(), v.len()))
+        .sum();
+
+    args + requested + inherited + 2 * ptr
+}
+
+fn check_exec_size(req: &msg::ProcessRequest) -> Result<(), IoError> {
+    use nix::unistd::{sysconf, SysconfVar};
+
+    let limit = match sysconf(SysconfVar::ARG_MAX) {
+        Ok(Some(limit)) if limit > 0 => limit as usize,
+        _ => return Ok(()),
+    };
+    let size = exec_size(req);
+    if size > limit {
+        return Err(IoError::new(
+            IoError::from_raw_os_error(libc::E2BIG).kind(),
+            format!("arguments too long: {} > {} bytes", size, limit),
+        ));
+    }
+    Ok(())
+}
+
+/// `None` when there is no such file, permissions of another user are
+/// guessed from the mode bits.
+fn executable(path: &Path, uid: i32) -> Option<bool> {
+    use nix::unistd::{access, getuid, AccessFlags};
+
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(ref err) if err.kind() == ErrorKind::PermissionDenied => {
+            return Some(false)
+        }
+        Err(_) => return None,
+    };
+    if !meta.is_file() {
+        return Some(false);
+    }
+    if uid >= 0 && uid as u32 != getuid().as_raw() {
+        return Some(meta.permissions().mode() & 0o111 != 0);
+    }
+    Some(access(path, AccessFlags::X_OK).is_ok())
+}
+
+// Best effort ahead of the fork, exec has the final say. Relative paths
+// are left alone when they depend on a directory not entered yet.
+fn check_executable(req: &msg::ProcessRequest) -> Result<(), IoError> {
+    let program = OsStr::from_bytes(req.program);
+    let candidates: Vec<PathBuf> = if req.program.contains(&b'/') {
+        let elsewhere = !req.cwd.is_empty() || req.io.contains(Files::CWD);
+        if !req.program.starts_with(b"/") && elsewhere {
+            return Ok(());
+        }
+        vec![PathBuf::from(program)]
+    } else {
+        // searched in PATH the program gets
+        let path = req
+            .env
+            .iter()
+            .rev()
+            .find(|(key, _)| *key == b"PATH")
+            .map(|(_, value)| OsStr::from_bytes(value).to_owned())
+            .or_else(|| std::env::var_os("PATH"))
+            .unwrap_or_else(OsString::new);
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .collect()
+    };
+
+    let mut found = false;
+    for candidate in &candidates {
+        match executable(candidate, req.uid) {
+            Some(true) => return Ok(()),
+            Some(false) => found = true,
+            None => {}
+        }
+    }
+    let (kind, problem) = if found {
+        (ErrorKind::PermissionDenied, "is not executable")
+    } else {
+        (ErrorKind::NotFound, "not found")
+    };
+    Err(IoError::new(
+        kind,
+        format!("program {:?} {}", program, problem),
+    ))
+}
+
+fn default_path(uid: i32) -> &'static str {
+    if uid == 0 {
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+    } else {
+        "/usr/local/bin:/usr/bin:/bin"
+    }
+}
+
+/// Passwd entry of the user for `StartMode::LOGIN`.
+fn login_account(
+    req: &msg::ProcessRequest,
+) -> Result<Option<system::Account>, IoError> {
+    if !req.startup.contains(StartMode::LOGIN) {
+        return Ok(None);
+    }
+    if req.uid < 0 {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "login requires a user id",
+        ));
+    }
+    system::account(req.uid as u32).map(Some)
+}
+
+// Program and its arguments become positional parameters of the login
+// shell, which needs no quoting and finds the program in the new PATH.
+fn login_shell(
+    req: &msg::ProcessRequest,
+    account: &system::Account,
+) -> Command {
+    let shell: &[u8] = match account.shell.as_slice() {
+        b"" => b"/bin/sh",
+        shell => shell,
+    };
+    let name = shell.rsplit(|c| *c == b'/').next().unwrap_or(shell);
+    let mut argv0 = b"-".to_vec();
+    argv0.extend_from_slice(name);
+
+    let mut cmd = Command::new(OsStr::from_bytes(shell));
+    cmd.arg0(OsStr::from_bytes(&argv0))
+        .arg("-c")
+        .arg("exec \"$0\" \"$@\"")
+        .arg(OsStr::from_bytes(req.program))
+        .args(req.argv.iter().map(|arg| OsStr::from_bytes(arg)));
+
+    let user = OsStr::from_bytes(&account.name);
+    cmd.env_clear()
+        .env("HOME", OsStr::from_bytes(&account.home))
+        .env("USER", user)
+        .env("LOGNAME", user)
+        .env("SHELL", OsStr::from_bytes(shell))
+        .env("PATH", default_path(req.uid));
+    cmd
+}
+
+fn prepare(
+    req: &msg::ProcessRequest,
+    parent: system::Pid,
+    workdir: Option<RawFd>,
+    ctty: Option<RawFd>,
+    extra: &[RawFd],
+    login: Option<&system::Account>,
+) -> Command {
+    let mut cmd = match login {
+        Some(account) => login_shell(req, account),
+        None => {
+            let mut cmd = Command::new(OsStr::from_bytes(req.program));
+            cmd.args(req.argv.iter().map(|arg| OsStr::from_bytes(arg)));
+            cmd
+        }
+    };
 
     let startup_mode: msg::StartMode = req.startup;
     let deathsig = system::Signal::from_c_int(req.deathsig).ok();
     let pgid = system::Pid::from_raw(req.pgid);
+    let uid = req.uid;
+    // login takes the primary group of the user unless one is given
+    let gid = match login {
+        Some(account) if req.gid < 0 => account.gid as i32,
+        _ => req.gid,
+    };
+    // like `su -` login gets the user's groups unless some are given,
+    // those of the server are never passed on to another user
+    let groups = match login {
+        Some(account) if req.groups.is_empty() => {
+            system::group_list(&account.name, gid as u32).unwrap_or_default()
+        }
+        _ => req.groups.to_vec(),
+    };
+    let umask = req.umask;
+    let set_groups =
+        !groups.is_empty() || (uid >= 0 && nix::unistd::getuid().is_root());
+    let cwd = match req.cwd {
+        b"" => None,
+        path => Some(CString::new(path)),
+    };
+    let root = match req.chroot {
+        b"" => None,
+        path => Some(CString::new(path)),
+    };
+    let slash = CString::new("/").unwrap();
+    // like `su -`, a missing home leaves the directory unchanged
+    let home = match login {
+        Some(account) if cwd.is_none() && workdir.is_none() => {
+            CString::new(account.home.as_slice()).ok()
+        }
+        _ => None,
+    };
+    let rlimits = req.rlimits.to_vec();
+    let fdmap: Vec<(RawFd, RawFd)> = extra
+        .iter()
+        .cloned()
+        .zip(req.extra_fds.iter().cloned())
+        .collect();
+    let mut scratch = vec![-1; fdmap.len()];
 
-    unsafe {
-        cmd.pre_exec(move || {
-            if cfg!(target_os = "linux") {
-                if let Some(ds) = deathsig {
-                    kill_self_if_parent_exits(parent, ds)?;
-                }
-            }
+    // limits and groups are set while still privileged, credentials are
+    // changed next, this resets death signal
+    let mut setup = move || -> Result<(), IoError> {
+        for limit in &rlimits {
+            tag(Stage::ResourceLimits, system::set_resource_limit(limit))?;
+        }
 
-            if startup_mode.contains(StartMode::DETACH_TERMINAL) {
-                tty::disconnect_controlling_terminal()?;
-            }
+        if let Some(mask) = umask {
+            system::set_umask(mask);
+        }
 
-            if startup_mode.contains(StartMode::PROCESS_GROUP) {
-                system::new_process_group(pgid)?;
+        if set_groups {
+            tag(Stage::SetGroups, system::set_groups(&groups))?;
+        }
+
+        if gid >= 0 {
+            tag(Stage::SetGid, system::set_group(gid as u32))?;
+        }
+
+        // needs privileges the user change drops, the directory
+        // left behind is outside of the new root
+        match root {
+            Some(Ok(ref path)) => {
+                tag(Stage::Chroot, system::change_root(path))?;
+                tag(Stage::Chroot, system::change_dir(&slash))?;
+            }
+            Some(Err(_)) => {
+                let err = IoError::from_raw_os_error(libc::EINVAL);
+                tag(Stage::Chroot, Err(err))?;
             }
+            None => {}
+        }
 
-            if startup_mode.contains(StartMode::SESSION) {
-                system::new_session()?
+        if uid >= 0 {
+            tag(Stage::SetUid, system::set_user(uid as u32))?;
+        }
+
+        match cwd {
+            Some(Ok(ref path)) => {
+                tag(Stage::Chdir, system::change_dir(path))?;
+            }
+            Some(Err(_)) => {
+                let err = IoError::from_raw_os_error(libc::EINVAL);
+                tag(Stage::Chdir, Err(err))?;
             }
+            None => {}
+        }
 
-            if startup_mode.contains(StartMode::NOHUP) {
-                system::nohup()?
+        if let Some(ref path) = home {
+            let _ = system::change_dir(path);
+        }
+
+        // descriptor refers to directory on client side of any
+        // root change, so it goes last
+        if let Some(fd) = workdir {
+            tag(Stage::Fchdir, system::change_dir_fd(fd))?;
+        }
+
+        // may overwrite workdir descriptor, so after fchdir
+        if !fdmap.is_empty() {
+            tag(Stage::Descriptors, system::map_fds(&fdmap, &mut scratch))?;
+        }
+
+        if cfg!(target_os = "linux") {
+            if let Some(ds) = deathsig {
+                tag(
+                    Stage::DeathSignal,
+                    kill_self_if_parent_exits(parent, ds),
+                )?;
             }
+        }
+
+        if startup_mode.contains(StartMode::DETACH_TERMINAL) {
+            tag(
+                Stage::DetachTerminal,
+                tty::disconnect_controlling_terminal(),
+            )?;
+        }
+
+        if startup_mode.contains(StartMode::PROCESS_GROUP) {
+            tag(Stage::ProcessGroup, system::new_process_group(pgid))?;
+        }
 
-            Ok(())
+        if startup_mode.contains(StartMode::SESSION) {
+            tag(Stage::Session, system::new_session())?
+        }
+
+        if let Some(fd) = ctty {
+            tag(
+                Stage::ControllingTerminal,
+                tty::set_controlling_terminal(fd),
+            )?
+        }
+
+        // only a session leader without terminal may acquire one
+        if startup_mode.contains(StartMode::CONTROLLING_TTY)
+            && nix::unistd::isatty(0).unwrap_or(false)
+        {
+            tag(Stage::ControllingTerminal, tty::set_controlling_terminal(0))?
+        }
+
+        if startup_mode.contains(StartMode::NOHUP) {
+            tag(Stage::Nohup, system::nohup())?
+        }
+
+        if startup_mode.contains(StartMode::NO_CORE) {
+            tag(Stage::NoCore, system::disable_core_dumps())?
+        }
+
+        Ok(())
+    };
+    // a panic must not unwind into the copy of the server it runs in
+    unsafe {
+        cmd.pre_exec(move || {
+            match std::panic::catch_unwind(AssertUnwindSafe(&mut setup)) {
+                Ok(res) => res,
+                Err(_) => std::process::abort(),
+            }
         });
     }
 
     if !req.env.is_empty() {
         for (k, v) in req.env {
-            cmd.env(k, v);
+            cmd.env(OsStr::from_bytes(k), OsStr::from_bytes(v));
         }
     }
 
-    if !req.cwd.is_empty() {
-        let pb: PathBuf = req.cwd.into();
-        cmd.current_dir(pb);
-    }
+    cmd
+}
 
-    if req.uid >= 0 {
-        cmd.uid(req.uid as u32);
-    }
+pub(crate) fn execute_into(
+    req: &msg::ProcessRequest,
+    extra: &[RawFd],
+) -> IoError {
+    let account = match check_exec_size(req).and_then(|()| login_account(req))
+    {
+        Ok(account) => account,
+        Err(err) => return err,
+    };
 
-    if req.gid >= 0 {
-        cmd.gid(req.gid as u32);
+    let mut cmd = prepare(
+        req,
+        system::Pid::parent(),
+        None,
+        None,
+        extra,
+        account.as_ref(),
+    );
+    if !req.io.contains(Files::IN) {
+        cmd.stdin(Stdio::null());
+    }
+    if !req.io.contains(Files::OUT) {
+        cmd.stdout(Stdio::null());
+    }
+    if !req.io.contains(Files::ERR) {
+        cmd.stderr(Stdio::null());
     }
+    untag(cmd.exec())
+}
 
-    cmd
+// fchdir to a directory outside of the new root would escape it
+fn check_chroot(req: &msg::ProcessRequest) -> Result<(), IoError> {
+    if !req.chroot.is_empty() && req.io.contains(Files::CWD) {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "working directory descriptor cannot be used with chroot",
+        ));
+    }
+    Ok(())
 }
 
-pub(crate) fn execute_into(req: &msg::ProcessRequest) -> IoError {
-    prepare(req, system::Pid::parent()).exec()
+// streams are indexed by their bits, a short list must not get there
+fn check_descriptor_count(
+    req: &msg::ProcessRequest,
+    numstreams: usize,
+    passed: usize,
+) -> Result<(), IoError> {
+    let expected = numstreams
+        + req.io.contains(Files::CWD) as usize
+        + req.io.contains(Files::TTY) as usize
+        + req.extra_fds.len();
+    if expected == passed {
+        return Ok(());
+    }
+    Err(IoError::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "request maps {} descriptors but {} were passed",
+            expected, passed
+        ),
+    ))
 }
 
 pub(crate) fn setup_command(
     req: &msg::ProcessRequest,
     fds: &[RawFd],
 ) -> Result<Child, IoError> {
-    let mut cmd = prepare(req, system::Pid::this());
+    let numstreams = [Files::IN, Files::OUT, Files::ERR]
+        .iter()
+        .filter(|f| req.io.contains(**f))
+        .count();
+
+    // the login shell looks for the program in a PATH of its own, in a
+    // new root it is not where the server would look
+    let checked = check_descriptor_count(req, numstreams, fds.len())
+        .and_then(|()| check_exec_size(req))
+        .and_then(|()| check_chroot(req))
+        .and_then(|()| login_account(req))
+        .and_then(|account| match account {
+            Some(_) => Ok(account),
+            None if !req.chroot.is_empty() => Ok(None),
+            None => check_executable(req).map(|()| None),
+        });
+    let account = match checked {
+        Ok(account) => account,
+        Err(err) => {
+            for _ in fds.iter().cloned().map(Fd::new) {
+                //
+            }
+            return Err(err);
+        }
+    };
+
+    // kept open until child is spawned
+    let workdir = if req.io.contains(Files::CWD) && numstreams < fds.len() {
+        Some(Fd::new(fds[numstreams]))
+    } else {
+        None
+    };
 
-    let numfds = if !req.io.is_empty() {
-        setup_command_streams(&mut cmd, req.io, &fds)
+    // kept open until child is spawned as well
+    let first_tty = numstreams + workdir.is_some() as usize;
+    let ctty = if req.io.contains(Files::TTY) && first_tty < fds.len() {
+        Some(Fd::new(fds[first_tty]))
+    } else {
+        None
+    };
+
+    let first_extra = first_tty + ctty.is_some() as usize;
+    let extra: Vec<Fd> = fds
+        .iter()
+        .skip(first_extra)
+        .take(req.extra_fds.len())
+        .cloned()
+        .map(Fd::new)
+        .collect();
+    let extra_raw: Vec<RawFd> = extra.iter().map(Fd::raw).collect();
+
+    let mut cmd = prepare(
+        req,
+        system::Pid::this(),
+        workdir.as_ref().map(Fd::raw),
+        ctty.as_ref().map(Fd::raw),
+        &extra_raw,
+        account.as_ref(),
+    );
+
+    let mut numfds = if !req.io.is_empty() {
+        setup_command_streams(&mut cmd, req.io, fds)
     } else {
         cmd.stdin(Stdio::null());
         cmd.stdout(Stdio::null());
@@ -136,9 +674,64 @@ pub(crate) fn setup_command(
         0
     };
 
+    if workdir.is_some() {
+        numfds += 1;
+    }
+    if ctty.is_some() {
+        numfds += 1;
+    }
+    numfds += extra.len();
+
     for _ in fds.iter().skip(numfds).cloned().map(Fd::new) {
         //
     }
 
-    spawn(cmd)
+    spawn(cmd).map_err(untag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(stage: Stage, err: IoError) -> (Stage, i32) {
+        let err = untag(tag::<()>(stage, Err(err)).unwrap_err());
+        let inner = err.get_ref().and_then(|e| e.downcast_ref());
+        let StageError { stage, errno } = inner.unwrap();
+        (*stage, *errno)
+    }
+
+    #[test]
+    fn errno_keeps_its_stage() {
+        for stage in Stage::ALL.iter() {
+            let err = IoError::from_raw_os_error(libc::EPERM);
+            assert_eq!(round_trip(*stage, err), (*stage, libc::EPERM));
+        }
+        let err = IoError::from_raw_os_error(libc::EHWPOISON);
+        assert_eq!(
+            round_trip(Stage::Chroot, err),
+            (Stage::Chroot, libc::EHWPOISON)
+        );
+    }
+
+    #[test]
+    fn odd_errno_does_not_change_stage() {
+        for errno in &[-1, 0, 1 << STAGE_SHIFT, i32::MAX, i32::MIN] {
+            let err = IoError::from_raw_os_error(*errno);
+            let (stage, errno) = round_trip(Stage::SetUid, err);
+            assert_eq!((stage, errno), (Stage::SetUid, libc::EINVAL));
+        }
+        let err = IoError::new(std::io::ErrorKind::InvalidInput, "no errno");
+        assert_eq!(
+            round_trip(Stage::Descriptors, err),
+            (Stage::Descriptors, libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn untag_leaves_plain_errno() {
+        let err = untag(IoError::from_raw_os_error(libc::ENOENT));
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let err = untag(IoError::from_raw_os_error(-1));
+        assert_eq!(err.raw_os_error(), Some(-1));
+    }
 }
@@ -1,14 +1,21 @@
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::future::Future;
 use std::io::{Result, Write};
-use std::mem;
 use std::os::unix::process::ExitStatusExt;
 use std::pin::Pin;
 use std::process::{Command, ExitStatus};
 use std::sync::Mutex;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+    pin_mut,
+};
 use lazy_static::lazy_static;
+use log::debug;
 use mio_uds::UnixStream;
 use tokio::io::AsyncRead;
 use tokio::io::PollEvented;
@@ -47,23 +54,25 @@ impl Watchers {
         receiver
     }
 
-    pub fn notify(&self, pid: i32, status: i32) {
+    pub fn notify(&self, pid: i32, exit: &Exit) {
         let mut dt = self.inner.lock().unwrap();
         if let Some(fd) = dt.storage.remove(&pid) {
-            send(fd, status);
+            send(fd, exit);
         }
     }
 }
 
 pub struct Child {
     pid: i32,
+    started: Instant,
     event: PollEvented<UnixStream>,
 }
 
 impl Child {
     pub fn from_id(pid: i32) -> Child {
         Child {
-            pid: pid,
+            pid,
+            started: Instant::now(),
             event: (PollEvented::new(watchers().register(pid)).unwrap()),
         }
     }
@@ -71,12 +80,63 @@ impl Child {
     pub fn id(&self) -> i32 {
         self.pid
     }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
 }
 
-const MSG_SIZE: usize = mem::size_of::<i32>();
+/// How a process ended, as reported by `wait4`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exit {
+    pub status: ExitStatus,
+    pub core_dumped: bool,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    pub max_rss: i64,
+}
+
+// status, core flag, user and system time in microseconds, max rss;
+// all little-endian so the layout does not depend on the struct
+const MSG_SIZE: usize = 4 + 1 + 8 + 8 + 8;
+
+fn micros(value: Duration) -> u64 {
+    value.as_secs() * 1_000_000 + u64::from(value.subsec_micros())
+}
+
+impl Exit {
+    fn encode(&self) -> [u8; MSG_SIZE] {
+        let mut data = [0; MSG_SIZE];
+        data[..4].copy_from_slice(&self.status.into_raw().to_le_bytes());
+        data[4] = self.core_dumped as u8;
+        data[5..13].copy_from_slice(&micros(self.user_time).to_le_bytes());
+        data[13..21].copy_from_slice(&micros(self.system_time).to_le_bytes());
+        data[21..].copy_from_slice(&self.max_rss.to_le_bytes());
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Exit> {
+        if data.len() != MSG_SIZE || data[4] > 1 {
+            return None;
+        }
+        let time = |at: usize| {
+            let raw = u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+            Duration::from_micros(raw)
+        };
+        Some(Exit {
+            status: ExitStatus::from_raw(i32::from_le_bytes(
+                data[..4].try_into().unwrap(),
+            )),
+            core_dumped: data[4] == 1,
+            user_time: time(5),
+            system_time: time(13),
+            max_rss: i64::from_le_bytes(data[21..].try_into().unwrap()),
+        })
+    }
+}
 
 impl Future for Child {
-    type Output = Result<ExitStatus>;
+    type Output = Result<Exit>;
 
     fn poll(
         mut self: Pin<&mut Self>,
@@ -86,27 +146,21 @@ impl Future for Child {
 
         match Pin::new(&mut self.event).poll_read(cx, &mut data) {
             Poll::Ready(Ok(0)) => panic!("EOF on self-pipe"),
-            Poll::Ready(Ok(MSG_SIZE)) => {
-                let status: i32 = unsafe {
-                    let mut d: [u8; MSG_SIZE] = [0, 0, 0, 0];
-                    d.copy_from_slice(&data[..MSG_SIZE]);
-                    mem::transmute(d)
-                };
-                Poll::Ready(Ok(ExitStatus::from_raw(status)))
-            }
-            Poll::Ready(Ok(sz)) => panic!(
-                "Unexpected self-pipe message received: {:?}",
-                &data[..sz]
-            ),
+            Poll::Ready(Ok(sz)) => match Exit::decode(&data[..sz]) {
+                Some(exit) => Poll::Ready(Ok(exit)),
+                None => panic!(
+                    "Unexpected self-pipe message received: {:?}",
+                    &data[..sz]
+                ),
+            },
             Poll::Ready(Err(e)) => panic!("Bad read on self-pipe: {}", e),
             Poll::Pending => Poll::Pending,
         }
     }
 }
 
-fn send(mut stream: UnixStream, status: i32) {
-    let data: [u8; MSG_SIZE] = unsafe { mem::transmute(status) };
-    drop(stream.write(&data));
+fn send(mut stream: UnixStream, exit: &Exit) {
+    drop(stream.write(&exit.encode()));
 }
 
 fn watchers() -> Pin<&'static Watchers> {
@@ -124,30 +178,112 @@ pub fn spawn(mut command: Command) -> Result<Child> {
     Ok(Child::from_id(child.id() as i32))
 }
 
-pub async fn listen(mut sig: Signal) {
+fn timeval(value: &libc::timeval) -> Duration {
+    Duration::from_secs(value.tv_sec as u64)
+        + Duration::from_micros(value.tv_usec as u64)
+}
+
+fn exit(status: libc::c_int, usage: &libc::rusage) -> Exit {
+    Exit {
+        status: ExitStatus::from_raw(status),
+        core_dumped: libc::WIFSIGNALED(status) && libc::WCOREDUMP(status),
+        user_time: timeval(&usage.ru_utime),
+        system_time: timeval(&usage.ru_stime),
+        max_rss: usage.ru_maxrss as _,
+    }
+}
+
+// Collects every child that has exited so far
+fn reap(w: Pin<&Watchers>) {
+    loop {
+        let mut status: libc::c_int = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+        let res = unsafe {
+            libc::wait4(
+                -1,
+                &mut status as *mut libc::c_int,
+                libc::WNOHANG,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+
+        match res {
+            -1 => match Errno::last() {
+                Errno::EINTR => continue,
+                Errno::ECHILD => break,
+                err => panic!("waitpid failed: {}", err),
+            },
+            0 => break,
+            pid => w.notify(pid, &exit(status, &usage)),
+        }
+    }
+}
+
+/// Reaps children on SIGCHLD until `stop` fires or its sender is gone,
+/// a last pass picks up exits the final signal has not announced yet.
+pub async fn listen(mut sig: Signal, mut stop: oneshot::Receiver<()>) {
     let w = watchers();
 
-    while let Some(()) = sig.recv().await {
-        loop {
-            let mut status: libc::c_int = 0;
-
-            let res = unsafe {
-                libc::waitpid(
-                    -1,
-                    &mut status as *mut libc::c_int,
-                    libc::WNOHANG,
-                )
-            };
-
-            match res {
-                -1 => match Errno::last() {
-                    Errno::EINTR => continue,
-                    Errno::ECHILD => break,
-                    err => panic!("waitpid failed: {}", err),
-                },
-                0 => break,
-                pid => w.as_ref().notify(pid, status),
+    loop {
+        let received = sig.recv();
+        pin_mut!(received);
+        match select(received, &mut stop).await {
+            Either::Left((Some(()), _)) => reap(w),
+            Either::Left((None, _)) => break,
+            Either::Right(_) => {
+                reap(w);
+                break;
             }
         }
     }
+    debug!("child watcher stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(exit: Exit) {
+        assert_eq!(Exit::decode(&exit.encode()), Some(exit));
+    }
+
+    #[test]
+    fn exit_round_trip() {
+        round_trip(Exit {
+            status: ExitStatus::from_raw(3 << 8),
+            core_dumped: false,
+            user_time: Duration::from_micros(1_234_567),
+            system_time: Duration::from_micros(89),
+            max_rss: 4404,
+        });
+    }
+
+    #[test]
+    fn signal_round_trip() {
+        // killed by SIGSEGV with a core, the largest values still fit
+        round_trip(Exit {
+            status: ExitStatus::from_raw(libc::SIGSEGV | 0x80),
+            core_dumped: true,
+            user_time: Duration::from_micros(u64::MAX),
+            system_time: Duration::from_secs(0),
+            max_rss: i64::MAX,
+        });
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        let data = Exit {
+            status: ExitStatus::from_raw(0),
+            core_dumped: false,
+            user_time: Duration::from_secs(0),
+            system_time: Duration::from_secs(0),
+            max_rss: -1,
+        }
+        .encode();
+        assert_eq!(Exit::decode(&data[1..]), None);
+        let mut flag = data;
+        flag[4] = 2;
+        assert_eq!(Exit::decode(&flag), None);
+    }
 }
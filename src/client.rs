@@ -1,34 +1,80 @@
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
+use std::cell::Cell;
 use std::io::{Error as IoError, ErrorKind, Result};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::time::{Duration, Instant};
 
+use nix::fcntl::{self, OFlag};
 use nix::sys::signal::{raise, Signal};
+use nix::sys::stat::Mode;
 
-use futures::future::{select, Either};
+use futures::{
+    channel::mpsc,
+    future::{self, select, Either, FutureExt},
+    stream::StreamExt,
+};
 
+use crate::channel;
+use crate::events;
+use crate::guards::TermiosGuard;
 use crate::messages as msg;
-use crate::raw::blocking::connect;
+use crate::pipe;
+use crate::raw;
+use crate::raw::blocking::{connect, from_connected};
+use crate::relay;
 use crate::runtime;
 use crate::signals;
-use crate::socket::Socket;
+use crate::socket::{BufferSizes, Socket};
 use crate::system;
+use crate::tty;
 
 pub(crate) struct Args<'a> {
     pub connect: &'a Path,
-    pub program: &'a str,
-    pub args: &'a [&'a str],
-    pub env: &'a [(&'a str, &'a str)],
+    pub connect_fd: Option<RawFd>,
+    pub program: &'a [u8],
+    pub args: &'a [&'a [u8]],
+    pub env: &'a [(&'a [u8], &'a [u8])],
     pub cwd: &'a str,
+    pub chroot: &'a str,
     pub uid: i32,
     pub gid: i32,
+    pub groups: &'a [u32],
+    pub umask: Option<u32>,
     pub deathsig: i32,
+    pub start_signal: i32,
+    pub detach: bool,
+    pub no_wait: bool,
     pub setpgid: Option<i32>,
     pub setsid: bool,
+    pub keep_tty: bool,
+    pub ctty: Option<&'a Path>,
     pub notty: bool,
+    pub no_core: bool,
+    pub login: bool,
+    pub assert_creds: bool,
+    pub cwd_fd: bool,
+    pub output_prefix: Option<&'a str>,
+    pub streams: msg::Files,
+    // own descriptor passed as each of the program's 0, 1 and 2
+    pub stdio: [RawFd; 3],
+    pub relay: bool,
+    pub pty_stdout: bool,
+    pub strict: bool,
+    pub json: bool,
+    // gets the outcome as an `exited` event once the program is done
+    pub status_fd: Option<RawFd>,
+    pub idle_timeout: Option<Duration>,
+    // program gets SIGTERM once it runs that long, exit code is 124
+    pub timeout: Option<Duration>,
+    pub group_signals: bool,
+    pub buffers: BufferSizes,
+    pub rlimits: &'a [msg::ResourceLimit],
+    pub fd_targets: &'a [i32],
+    pub fd_sources: &'a [RawFd],
 }
 
-fn handle_stop(mut sigval: i32) {
+fn handle_stop(mut sigval: i32) -> bool {
     use Signal::{SIGSTOP, SIGTSTP};
 
     sigval = sigval.abs();
@@ -37,11 +83,14 @@ fn handle_stop(mut sigval: i32) {
         debug!("received signal value={}", sig);
         if sig == SIGTSTP || sig == SIGSTOP {
             debug!("raising SIGSTOP");
-            if let Err(err) = raise(SIGSTOP) {
-                error!("signal raise error: {:?}", err);
+            match raise(SIGSTOP) {
+                Ok(()) => return true,
+                Err(err) => error!("signal raise error: {:?}", err),
             }
         }
     }
+
+    false
 }
 
 fn convert_to_group_signals(signal: Signal) -> i32 {
@@ -49,40 +98,279 @@ fn convert_to_group_signals(signal: Signal) -> i32 {
 
     match signal {
         SIGTSTP | SIGSTOP | SIGCONT | SIGTTIN | SIGTTOU => -(signal as i32),
-        _ => (signal as i32),
+        _ => signal as i32,
     }
 }
 
-async fn wait_child(
+fn forwarded(caught: signals::Caught) -> msg::Signal {
+    let signo = match caught.signal() {
+        Some(sig) => convert_to_group_signals(sig),
+        None => caught.signo,
+    };
+    msg::Signal {
+        signo,
+        value: caught.value,
+    }
+}
+
+fn is_cont(caught: &signals::Caught) -> bool {
+    caught.signal() == Some(Signal::SIGCONT)
+}
+
+fn exit_code(status: &msg::ProcessResult, cpu_limit: Option<u64>) -> i32 {
+    debug!("process finished {:?}", status);
+    if let Some(limit) = cpu_limit {
+        if status.exceeded_cpu_limit(limit) {
+            error!("program exceeded CPU time limit of {}s", limit);
+        }
+    }
+    match status.exit_code() {
+        Some(code) => code,
+        None => {
+            warn!("exit reason undefined");
+            127
+        }
+    }
+}
+
+/// How relayed output and the outcome reach the user.
+struct Report {
+    cpu_limit: Option<u64>,
+    // events on stdout instead of raw output
+    json: bool,
+    status_fd: Option<RawFd>,
+    // SIGQUIT was forwarded, the user asked for a core dump
+    quit: Cell<bool>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    timed_out: Cell<bool>,
+}
+
+impl Report {
+    fn output(&self, stream: msg::Files, data: &[u8]) -> Result<()> {
+        if self.json {
+            events::output(stream, data)
+        } else {
+            relay::write_output(stream, data)
+        }
+    }
+
+    fn forwarded(&self, caught: &signals::Caught) {
+        if caught.signal() == Some(Signal::SIGQUIT) {
+            self.quit.set(true);
+        }
+    }
+
+    fn finished(&self, status: &msg::ProcessResult) -> Result<i32> {
+        if self.json {
+            events::exited(status)?;
+        }
+        if let Some(fd) = self.status_fd {
+            if let Err(err) = events::exited_to(fd, status) {
+                warn!("failed to write status to fd {}: {}", fd, err);
+            }
+        }
+        if self.quit.get() && status.signaled == Some(Signal::SIGQUIT as i32) {
+            if status.core_dumped {
+                warn!("program quit (core dump requested), core dumped");
+            } else {
+                warn!("program quit (core dump requested), no core dumped");
+            }
+        }
+        if self.timed_out.get() {
+            return Ok(124);
+        }
+        Ok(exit_code(status, self.cpu_limit))
+    }
+}
+
+// Passing the deadline is handled as SIGTERM from the user, it fires
+// once and is dropped with the wait when the program exits first.
+async fn next_signal(
+    signals: &signals::SignalHandler,
+    report: &Report,
+) -> Result<signals::Caught> {
+    let remaining = match report.deadline {
+        Some(at) if !report.timed_out.get() => {
+            at.saturating_duration_since(Instant::now())
+        }
+        _ => return signals.wait().await,
+    };
+    match runtime::timeout(remaining, signals.wait()).await {
+        Ok(caught) => caught,
+        Err(_) => {
+            warn!(
+                "program timed out after {:?}, sending SIGTERM",
+                report.timeout.unwrap_or_default()
+            );
+            report.timed_out.set(true);
+            Ok(signals::Caught {
+                signo: Signal::SIGTERM as i32,
+                value: None,
+            })
+        }
+    }
+}
+
+async fn send_signal(
+    socket: &Socket,
+    sendbuf: &mut Vec<u8>,
+    signal: msg::Signal,
+) -> Result<()> {
+    sendbuf.clear();
+    msg::encode_request(&mut *sendbuf, &msg::Relay::Signal(signal))?;
+    socket.send(sendbuf).await.map(drop)
+}
+
+// Size of own stdout, the terminal the job's pseudo-terminal stands in for
+async fn send_window_size(
+    socket: &Socket,
+    sendbuf: &mut Vec<u8>,
+) -> Result<()> {
+    let size = match tty::window_size(1) {
+        Ok(size) => size,
+        Err(err) => {
+            debug!("no window size to pass: {}", err);
+            return Ok(());
+        }
+    };
+    sendbuf.clear();
+    msg::encode_request(&mut *sendbuf, &msg::Relay::WinSize(size))?;
+    socket.send(sendbuf).await.map(drop)
+}
+
+enum Event {
+    Signal(Result<signals::Caught>),
+    Input(Option<Vec<u8>>),
+}
+
+// Same as `wait_child`, but program stdio travels over the connection
+async fn wait_relayed(
     socket: &Socket,
     signals: &signals::SignalHandler,
-    mut buffer: &mut Vec<u8>,
+    buffer: &mut [u8],
+    mut input: Option<mpsc::Receiver<Vec<u8>>>,
+    pty: bool,
+    report: &Report,
 ) -> Result<i32> {
     let mut sendbuf = Vec::new();
-    let mut srv = socket.recv(&mut buffer);
-    let mut sig = signals.wait();
-
-    let child_finished = |result: Result<usize>, buffer: &[u8]| {
-        use msg::ProcessResult::*;
-        match result {
-            Ok(0) => {
-                warn!("server disconnected");
-                Ok(128)
-            }
-            Ok(bytes) => {
-                let status: msg::ProcessResult;
-                status = msg::decode_request(&buffer[..bytes])?;
-                match status {
-                    Undefined => {
-                        warn!("exit reason undefined");
-                        Ok(127)
+    let mut resumed = false;
+    // full-screen programs read it at start
+    if pty {
+        send_window_size(socket, &mut sendbuf).await?;
+    }
+    let mut srv = socket.recv(buffer);
+
+    loop {
+        let stdin = match input {
+            Some(ref mut chunks) => Either::Left(chunks.next()),
+            None => Either::Right(future::pending()),
+        };
+        let event = match select(
+            srv,
+            select(next_signal(signals, report).boxed_local(), stdin),
+        )
+        .await
+        {
+            Either::Left((received, _local)) => {
+                let bytes = received?;
+                if bytes == 0 {
+                    warn!("server disconnected");
+                    return Ok(128);
+                }
+                let message: msg::Relay =
+                    msg::decode_request_ref(&buffer[..bytes])?;
+                match message {
+                    msg::Relay::Data(stream, data) => {
+                        report.output(stream, data)?
+                    }
+                    msg::Relay::Finished(status) => {
+                        return report.finished(&status)
                     }
-                    Exit(code) => Ok(code),
-                    Signal(sig) => Ok(128 + sig),
+                    _ => {}
                 }
+                srv = socket.recv(buffer);
+                continue;
+            }
+            Either::Right((Either::Left((sigval, _stdin)), srv1)) => {
+                srv = srv1;
+                Event::Signal(sigval)
+            }
+            Either::Right((Either::Right((chunk, _sig)), srv1)) => {
+                srv = srv1;
+                Event::Input(chunk)
+            }
+        };
+
+        match event {
+            Event::Signal(Ok(ref caught))
+                if pty && caught.signal() == Some(Signal::SIGWINCH) =>
+            {
+                send_window_size(socket, &mut sendbuf).await?;
             }
-            Err(err) => Err(err),
+            // already forwarded after resume
+            Event::Signal(Ok(ref caught)) if resumed && is_cont(caught) => {
+                resumed = false;
+            }
+            Event::Signal(Ok(caught)) => {
+                let m = forwarded(caught);
+                send_signal(socket, &mut sendbuf, m).await?;
+                debug!("signal value sent");
+                report.forwarded(&caught);
+                if handle_stop(m.signo) {
+                    // continue child together with client
+                    let cont = msg::Signal {
+                        signo: convert_to_group_signals(Signal::SIGCONT),
+                        value: None,
+                    };
+                    send_signal(socket, &mut sendbuf, cont).await?;
+                    debug!("resumed, SIGCONT sent");
+                    resumed = true;
+                }
+            }
+            Event::Signal(Err(err)) => {
+                panic!("signal handler error {:?}", err);
+            }
+            Event::Input(Some(data)) => {
+                sendbuf.clear();
+                let message = msg::Relay::Data(msg::Files::IN, &data);
+                msg::encode_request(&mut sendbuf, &message)?;
+                socket.send(&sendbuf).await?;
+            }
+            Event::Input(None) => {
+                debug!("stdin closed");
+                input = None;
+                sendbuf.clear();
+                let message = msg::Relay::Eof(msg::Files::IN);
+                msg::encode_request(&mut sendbuf, &message)?;
+                socket.send(&sendbuf).await?;
+            }
+        }
+    }
+}
+
+async fn wait_child(
+    socket: &Socket,
+    signals: &signals::SignalHandler,
+    buffer: &mut [u8],
+    report: &Report,
+) -> Result<i32> {
+    let mut sendbuf = Vec::new();
+    let mut srv = socket.recv(buffer);
+    let mut sig = next_signal(signals, report).boxed_local();
+    let mut resumed = false;
+
+    let child_finished = |result: Result<usize>, buffer: &[u8]| match result {
+        Ok(0) => {
+            warn!("server disconnected");
+            Ok(128)
+        }
+        Ok(bytes) => {
+            let status: msg::ProcessResult =
+                msg::decode_request(&buffer[..bytes])?;
+            report.finished(&status)
         }
+        Err(err) => Err(err),
     };
 
     let exitstatus = loop {
@@ -92,10 +380,13 @@ async fn wait_child(
                 break child_finished(read, buffer)?;
             }
             Either::Right((sigval, srv1)) => match sigval {
-                Ok(val) => {
-                    let v = convert_to_group_signals(val);
-
-                    let m = msg::Signal(v);
+                // already forwarded after resume
+                Ok(ref caught) if resumed && is_cont(caught) => {
+                    resumed = false;
+                    (srv1, next_signal(signals, report).boxed_local())
+                }
+                Ok(caught) => {
+                    let m = forwarded(caught);
 
                     sendbuf.clear();
                     msg::encode_request(&mut sendbuf, &m)?;
@@ -108,8 +399,25 @@ async fn wait_child(
                         Either::Right((delivered, srv1)) => match delivered {
                             Ok(_) => {
                                 debug!("signal value sent");
-                                handle_stop(v);
-                                (srv1, signals.wait())
+                                report.forwarded(&caught);
+                                if handle_stop(m.signo) {
+                                    // continue child together with client
+                                    let cont = msg::Signal {
+                                        signo: convert_to_group_signals(
+                                            Signal::SIGCONT,
+                                        ),
+                                        value: None,
+                                    };
+                                    sendbuf.clear();
+                                    msg::encode_request(&mut sendbuf, &cont)?;
+                                    socket.send(&sendbuf).await?;
+                                    debug!("resumed, SIGCONT sent");
+                                    resumed = true;
+                                }
+                                (
+                                    srv1,
+                                    next_signal(signals, report).boxed_local(),
+                                )
                             }
                             Err(err) => {
                                 warn!("sender error");
@@ -119,7 +427,7 @@ async fn wait_child(
                     }
                 }
                 Err(err) => {
-                    panic!(format!("signal handler error {:?}", err));
+                    panic!("signal handler error {:?}", err);
                 }
             },
         };
@@ -145,31 +453,205 @@ fn prepare_request<'a>(args: &Args<'a>) -> msg::ExecRequestInput<'a> {
         startup |= msg::StartMode::SESSION;
     }
 
+    if args.keep_tty {
+        startup |= msg::StartMode::SESSION | msg::StartMode::CONTROLLING_TTY;
+    }
+
+    if args.ctty.is_some() {
+        startup |= msg::StartMode::SESSION;
+    }
+
     if args.notty {
         startup |= msg::StartMode::DETACH_TERMINAL;
     }
 
-    let files = msg::Files::IN | msg::Files::OUT | msg::Files::ERR;
+    if args.no_core {
+        startup |= msg::StartMode::NO_CORE;
+    }
+
+    if args.login {
+        startup |= msg::StartMode::LOGIN;
+    }
+
+    let (mut files, mut relay) = if args.relay {
+        (msg::Files::empty(), args.streams)
+    } else {
+        (args.streams, msg::Files::empty())
+    };
+    // only stdout comes back over the connection
+    let pty = if args.pty_stdout {
+        files.remove(msg::Files::OUT);
+        relay |= msg::Files::OUT;
+        msg::Files::OUT
+    } else {
+        msg::Files::empty()
+    };
+    let cwd = if args.cwd_fd {
+        files |= msg::Files::CWD;
+        &b""[..]
+    } else {
+        args.cwd.as_bytes()
+    };
+    if args.ctty.is_some() {
+        files |= msg::Files::TTY;
+    }
+
+    let connsig = if args.detach || args.no_wait {
+        0
+    } else {
+        Signal::SIGTERM as i32
+    };
 
     msg::ExecRequestInput {
         program: args.program,
         argv: args.args,
-        cwd: args.cwd,
+        cwd,
+        chroot: args.chroot.as_bytes(),
         env: args.env,
         startup,
         io: files,
+        relay,
+        pty,
         pgid,
         uid: args.uid,
         gid: args.gid,
+        groups: args.groups,
+        umask: args.umask,
         deathsig: args.deathsig,
-        connsig: system::SIGKILL as i32,
+        connsig,
+        start_signal: args.start_signal,
+        idle_timeout: args.idle_timeout,
+        timeout: args.timeout,
+        group_signals: args.group_signals,
+        credentials: args.assert_creds,
+        rlimits: args.rlimits,
+        extra_fds: args.fd_targets,
+    }
+}
+
+// Closed stdio is reopened as /dev/null by std before main, so only the
+// kind of what is passed can be off.
+fn check_stdio(args: &Args, request: &msg::ExecRequestInput) -> Vec<String> {
+    const STREAMS: [(msg::Files, &str); 3] = [
+        (msg::Files::IN, "stdin"),
+        (msg::Files::OUT, "stdout"),
+        (msg::Files::ERR, "stderr"),
+    ];
+
+    let mut problems = Vec::new();
+    for (i, (flag, name)) in STREAMS.iter().enumerate() {
+        // relayed streams are read and written by the client itself
+        let fd = if request.io.contains(*flag) {
+            args.stdio[i]
+        } else if request.relay.contains(*flag) {
+            i as RawFd
+        } else {
+            continue;
+        };
+        match system::fd_kind(fd) {
+            None => problems.push(format!("{} is not open", name)),
+            Some("directory") => {
+                problems.push(format!("{} is a directory", name))
+            }
+            Some(_) => {}
+        }
+    }
+
+    if request.pty.contains(msg::Files::OUT) {
+        match system::fd_kind(1) {
+            Some("terminal") | None => {}
+            Some(kind) => problems.push(format!(
+                "--pty-stdout with stdout redirected to a {}",
+                kind
+            )),
+        }
+    }
+
+    if args.keep_tty && request.io.contains(msg::Files::IN) {
+        match system::fd_kind(args.stdio[0]) {
+            Some("terminal") | None => {}
+            Some(kind) => problems.push(format!(
+                "--new-session-keep-tty has no effect, stdin is a {}",
+                kind
+            )),
+        }
+    }
+
+    problems
+}
+
+fn asserted_credentials(args: &Args) -> raw::Credentials {
+    use nix::unistd::{getgid, getpid, getuid};
+
+    raw::Credentials {
+        pid: getpid().as_raw(),
+        uid: if args.uid >= 0 {
+            args.uid as u32
+        } else {
+            getuid().as_raw()
+        },
+        gid: if args.gid >= 0 {
+            args.gid as u32
+        } else {
+            getgid().as_raw()
+        },
     }
 }
 
+#[cfg(target_os = "linux")]
+const DIR_FLAGS: OFlag = OFlag::from_bits_truncate(
+    OFlag::O_PATH.bits() | OFlag::O_DIRECTORY.bits() | OFlag::O_CLOEXEC.bits(),
+);
+
+#[cfg(not(target_os = "linux"))]
+const DIR_FLAGS: OFlag = OFlag::from_bits_truncate(
+    OFlag::O_RDONLY.bits()
+        | OFlag::O_DIRECTORY.bits()
+        | OFlag::O_CLOEXEC.bits(),
+);
+
+fn open_workdir(args: &Args) -> Result<Option<raw::Fd>> {
+    if !args.cwd_fd {
+        return Ok(None);
+    }
+
+    let path = if args.cwd.is_empty() { "." } else { args.cwd };
+    debug!("passing workdir {:?} as descriptor", path);
+    fcntl::open(path, DIR_FLAGS, Mode::empty())
+        .map(|fd| Some(raw::Fd::new(fd)))
+        .map_err(raw::nixerror)
+}
+
+fn open_ctty(args: &Args) -> Result<Option<raw::Fd>> {
+    let path = match args.ctty {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    debug!("passing terminal {:?} as descriptor", path);
+    let flags = OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC;
+    fcntl::open(path, flags, Mode::empty())
+        .map(|fd| Some(raw::Fd::new(fd)))
+        .map_err(raw::nixerror)
+}
+
+struct Passed<'a> {
+    stdio: [RawFd; 3],
+    workdir: Option<&'a raw::Fd>,
+    ctty: Option<&'a raw::Fd>,
+    extra: &'a [RawFd],
+}
+
 async fn execute(
     request: &msg::ExecRequestInput<'_>,
+    creds: Option<raw::Credentials>,
+    passed: Passed<'_>,
     socket: Socket,
+    no_wait: bool,
+    prefix: Option<&str>,
+    mut report: Report,
 ) -> Result<i32> {
+    let json = report.json;
     let mut buffer = Vec::new();
     msg::encode_request(&mut buffer, &request)?;
 
@@ -185,18 +667,51 @@ async fn execute(
         let _sent = socket.send(&header).await?;
     }
 
+    let output = match prefix {
+        Some(prefix) => Some((pipe::make_output_pipe()?, prefix)),
+        None => None,
+    };
+
     {
-        let streams = [
-            std::io::stdin().as_raw_fd(),
-            std::io::stdout().as_raw_fd(),
-            std::io::stderr().as_raw_fd(),
-        ];
-        let _sent = socket.sendfds(&buffer, &streams).await?;
+        // same order as the server consumes them
+        let mut streams = Vec::with_capacity(5 + passed.extra.len());
+        if request.io.contains(msg::Files::IN) {
+            streams.push(passed.stdio[0]);
+        }
+        if request.io.contains(msg::Files::OUT) {
+            streams.push(match output {
+                Some(((_, ref fd), _)) => fd.raw(),
+                None => passed.stdio[1],
+            });
+        }
+        if request.io.contains(msg::Files::ERR) {
+            streams.push(passed.stdio[2]);
+        }
+        if let Some(fd) = passed.workdir {
+            streams.push(fd.raw());
+        }
+        if let Some(fd) = passed.ctty {
+            streams.push(fd.raw());
+        }
+        streams.extend_from_slice(passed.extra);
+        let _sent = socket.sendfds(&buffer, &streams, creds).await?;
     }
 
+    // write end now belongs to the server, keep only the read end
+    let relay = output.map(|((input, _), prefix)| {
+        runtime::spawn(relay::prefix_output(input, prefix.to_owned()))
+    });
+
     buffer.clear();
     buffer.resize(4096, 0);
 
+    // program shares the terminal, undo its changes unless it exits
+    let mut terminal = TermiosGuard::new(&[
+        std::io::stdin().as_raw_fd(),
+        std::io::stdout().as_raw_fd(),
+        std::io::stderr().as_raw_fd(),
+    ]);
+
     let received = socket.recv(&mut buffer).await?;
     debug!("response received {:?} bytes", received);
 
@@ -204,11 +719,67 @@ async fn execute(
         let ret: msg::StartedProcess =
             { msg::decode_request_ref(&buffer[..received])? };
         debug!("received {:#?}", ret);
+        // pid is all a debugger needs, SIGCONT is forwarded as usual
+        if ret.errno == 0 && request.start_signal == system::SIGSTOP as i32 {
+            info!("process {} started stopped", ret.pid);
+        }
+        if json && ret.errno == 0 {
+            events::started(ret.pid, ret.job)?;
+        }
         if ret.errno != 0 {
-            Err(IoError::from_raw_os_error(ret.errno))
+            let err = IoError::from_raw_os_error(ret.errno);
+            let err = match ret.message {
+                "" => err,
+                message => IoError::new(err.kind(), message),
+            };
+            if json {
+                events::failed(&err.to_string(), ret.errno)?;
+            }
+            Err(err)
+        } else if no_wait {
+            info!("started job={} process={}", ret.job, ret.pid);
+            terminal.disarm();
+            Ok(0)
         } else {
             let sigsink = signals::SignalHandler::new()?;
-            wait_child(&socket, &sigsink, &mut buffer).await
+            // the last one given for the resource is in effect
+            let cpu_limit = request
+                .rlimits
+                .iter()
+                .rev()
+                .find(|limit| limit.resource == msg::Resource::Cpu)
+                .and_then(|limit| limit.soft);
+            report.cpu_limit = cpu_limit;
+            report.deadline = report.timeout.map(|t| Instant::now() + t);
+            let code = if request.relay.is_empty() {
+                wait_child(&socket, &sigsink, &mut buffer, &report).await?
+            } else {
+                let input = if request.relay.contains(msg::Files::IN) {
+                    Some(relay::read_stdin())
+                } else {
+                    None
+                };
+                let pty = request.pty.contains(msg::Files::OUT);
+                wait_relayed(
+                    &socket,
+                    &sigsink,
+                    &mut buffer,
+                    input,
+                    pty,
+                    &report,
+                )
+                .await?
+            };
+            // exited on its own, terminal state is up to the program
+            if code < 128 {
+                terminal.disarm();
+            }
+            if let Some(relay) = relay {
+                if let Ok(Err(err)) = relay.await {
+                    warn!("output relay error: {}", err);
+                }
+            }
+            Ok(code)
         }
     } else {
         warn!("server disconnected");
@@ -218,22 +789,76 @@ async fn execute(
 
 pub(crate) fn command(args: &Args) -> Result<i32> {
     let request = prepare_request(args);
-    debug!("connecting to {:?}", args.connect);
-    match connect(args.connect) {
+    let problems = check_stdio(args, &request);
+    for problem in &problems {
+        if args.strict {
+            error!("{}", problem);
+        } else {
+            warn!("{}", problem);
+        }
+    }
+    if args.strict && !problems.is_empty() {
+        return Ok(2);
+    }
+
+    let creds = if args.assert_creds {
+        Some(asserted_credentials(args))
+    } else {
+        None
+    };
+    let workdir = open_workdir(args)?;
+    let ctty = open_ctty(args)?;
+    let socket = match args.connect_fd {
+        Some(fd) => {
+            debug!("using connected socket fd={}", fd);
+            from_connected(fd)
+        }
+        None => {
+            debug!("connecting to {:?}", args.connect);
+            connect(args.connect)
+        }
+    };
+    match socket {
         Ok(fd) => runtime::new()?.block_on(async {
-            let ret = execute(&request, Socket::from_fd(fd)?).await?;
+            let socket = Socket::from_fd(fd)?;
+            socket.set_buffer_sizes(args.buffers)?;
+            let ret = execute(
+                &request,
+                creds,
+                Passed {
+                    stdio: args.stdio,
+                    workdir: workdir.as_ref(),
+                    ctty: ctty.as_ref(),
+                    extra: args.fd_sources,
+                },
+                socket,
+                args.no_wait,
+                args.output_prefix,
+                Report {
+                    cpu_limit: None,
+                    json: args.json,
+                    status_fd: args.status_fd,
+                    quit: Cell::new(false),
+                    timeout: args.timeout,
+                    deadline: None,
+                    timed_out: Cell::new(false),
+                },
+            )
+            .await?;
             debug!("finished with code {:?}", ret);
             Ok(ret)
         }),
-        Err(err) => {
-            error!(
-                "failed to connect\n    \
-                 socket: {}\n    \
-                 error:  {}",
-                args.connect.to_string_lossy(),
-                err,
-            );
-            Ok(128)
-        }
+        Err(err) => match args.connect_fd {
+            Some(fd) => {
+                error!(
+                    "failed to connect\n    \
+                     socket: fd {}\n    \
+                     error:  {}",
+                    fd, err,
+                );
+                Ok(128)
+            }
+            None => Ok(channel::connect_failed(args.connect, &err)),
+        },
     }
 }
@@ -25,7 +25,7 @@ impl<'a> std::fmt::Debug for BytesDebug<'a> {
             } else if c == b'\0' {
                 write!(fmt, "\\0")?;
             // ASCII printable
-            } else if c >= 0x20 && c < 0x7f {
+            } else if (0x20..0x7f).contains(&c) {
                 write!(fmt, "{}", c as char)?;
             } else {
                 write!(fmt, "\\x{:02x}", c)?;
@@ -36,6 +36,33 @@ impl<'a> std::fmt::Debug for BytesDebug<'a> {
     }
 }
 
+/// Text of `val` for people to read, escaped like `bytes` unless it is
+/// valid UTF-8.
+pub fn text(val: &[u8]) -> std::borrow::Cow<'_, str> {
+    match std::str::from_utf8(val) {
+        Ok(text) => text.into(),
+        Err(_) => format!("{:?}", BytesDebug(val)).into(),
+    }
+}
+
+/// Shows each item like `bytes`, e.g. the argv of a request.
+pub fn list<'a, T: AsRef<[u8]>>(items: &'a [T]) -> ListDebug<'a, T> {
+    ListDebug(items)
+}
+
+pub struct ListDebug<'a, T: AsRef<[u8]>>(pub &'a [T]);
+
+impl<'a, T: AsRef<[u8]>> std::fmt::Debug for ListDebug<'a, T> {
+    fn fmt(
+        &self,
+        fmt: &mut std::fmt::Formatter,
+    ) -> Result<(), std::fmt::Error> {
+        fmt.debug_list()
+            .entries(self.0.iter().map(|item| BytesDebug(item.as_ref())))
+            .finish()
+    }
+}
+
 pub struct OptionDebug<'a, T: Debug>(pub &'a Option<T>);
 
 #[allow(dead_code)]
@@ -54,3 +81,45 @@ impl<'a, T: Debug> std::fmt::Debug for OptionDebug<'a, T> {
         }
     }
 }
+
+pub struct EnvDebug<'a> {
+    env: &'a [(&'a [u8], &'a [u8])],
+    redact: &'a [String],
+}
+
+pub fn env<'a>(
+    env: &'a [(&'a [u8], &'a [u8])],
+    redact: &'a [String],
+) -> EnvDebug<'a> {
+    EnvDebug { env, redact }
+}
+
+impl<'a> EnvDebug<'a> {
+    fn is_redacted(&self, key: &str) -> bool {
+        let key = key.to_ascii_uppercase();
+        self.redact
+            .iter()
+            .any(|pattern| key.contains(pattern.as_str()))
+    }
+}
+
+impl<'a> std::fmt::Debug for EnvDebug<'a> {
+    fn fmt(
+        &self,
+        fmt: &mut std::fmt::Formatter,
+    ) -> Result<(), std::fmt::Error> {
+        write!(fmt, "[")?;
+        for (i, (key, value)) in self.env.iter().enumerate() {
+            if i > 0 {
+                write!(fmt, ", ")?;
+            }
+            let key = String::from_utf8_lossy(key);
+            if self.is_redacted(&key) {
+                write!(fmt, "{}=<redacted>", key)?;
+            } else {
+                write!(fmt, "{}={:?}", key, String::from_utf8_lossy(value))?;
+            }
+        }
+        write!(fmt, "]")
+    }
+}
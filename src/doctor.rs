@@ -0,0 +1,131 @@
+use std::io::{Error as IoError, Result, Write};
+use std::path::Path;
+
+use nix::sys::socket::{self, AddressFamily, SockFlag, SockType};
+
+use crate::raw::{self, Fd};
+use crate::system;
+use crate::tty;
+
+enum Probe {
+    Usable,
+    Unusable(String),
+    Unsupported,
+}
+
+fn probe<T>(result: std::result::Result<T, IoError>) -> Probe {
+    match result {
+        Ok(_) => Probe::Usable,
+        Err(err) => Probe::Unusable(err.to_string()),
+    }
+}
+
+fn socket_pair(kind: SockType) -> Probe {
+    let pair =
+        socket::socketpair(AddressFamily::Unix, kind, None, SockFlag::empty())
+            .map(|(a, b)| (Fd::new(a), Fd::new(b)))
+            .map_err(raw::nixerror);
+    probe(pair)
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_socket() -> Probe {
+    use nix::sys::socket::{SockAddr, UnixAddr};
+
+    let name = format!("sidecar-doctor-{}", system::Pid::this());
+    let bound = UnixAddr::new_abstract(name.as_bytes())
+        .and_then(|addr| {
+            let fd = socket::socket(
+                AddressFamily::Unix,
+                SockType::SeqPacket,
+                SockFlag::SOCK_CLOEXEC,
+                None,
+            )
+            .map(Fd::new)?;
+            socket::bind(fd.raw(), &SockAddr::Unix(addr)).map(|()| fd)
+        })
+        .map_err(raw::nixerror);
+    probe(bound)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_socket() -> Probe {
+    Probe::Unsupported
+}
+
+#[cfg(target_os = "linux")]
+fn prctl() -> Probe {
+    probe(system::death_signal())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn prctl() -> Probe {
+    Probe::Unsupported
+}
+
+fn procfs() -> Probe {
+    if Path::new("/proc/self/fd").is_dir() {
+        Probe::Usable
+    } else {
+        Probe::Unusable("/proc is not mounted".to_owned())
+    }
+}
+
+fn privileged() -> Probe {
+    if nix::unistd::geteuid().is_root() {
+        Probe::Usable
+    } else {
+        Probe::Unusable("--setuid/--setgid need root".to_owned())
+    }
+}
+
+fn linux_only() -> Probe {
+    if cfg!(target_os = "linux") {
+        Probe::Usable
+    } else {
+        Probe::Unsupported
+    }
+}
+
+fn probes() -> Vec<(&'static str, Probe)> {
+    vec![
+        ("seqpacket sockets", socket_pair(SockType::SeqPacket)),
+        ("stream sockets", socket_pair(SockType::Stream)),
+        ("abstract sockets", abstract_socket()),
+        ("/proc filesystem", procfs()),
+        ("root privileges", privileged()),
+        ("prctl", prctl()),
+        ("controlling terminal", probe(tty::ttyfd())),
+        ("accept4", linux_only()),
+        ("MSG_CMSG_CLOEXEC", linux_only()),
+        ("SCM_CREDENTIALS", linux_only()),
+    ]
+}
+
+fn print(dest: &mut impl Write, probes: &[(&str, Probe)]) -> Result<()> {
+    for (name, probe) in probes {
+        match probe {
+            Probe::Usable => writeln!(dest, "{:<22} yes", name)?,
+            Probe::Unusable(reason) => {
+                writeln!(dest, "{:<22} no: {}", name, reason)?
+            }
+            Probe::Unsupported => {
+                writeln!(dest, "{:<22} not on this platform", name)?
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn command() -> Result<i32> {
+    let probes = probes();
+    print(&mut std::io::stdout().lock(), &probes)?;
+
+    // server needs at least one usable transport
+    let transports = &probes[..2];
+    if transports.iter().any(|(_, p)| matches!(p, Probe::Usable)) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
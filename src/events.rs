@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::{Result, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use crate::info::json_string;
+use crate::messages::{self as msg, Files};
+
+fn line(
+    event: &str,
+    fields: impl FnOnce(&mut Vec<u8>) -> Result<()>,
+) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    write!(line, "{{\"event\":")?;
+    json_string(&mut line, event)?;
+    fields(&mut line)?;
+    writeln!(line, "}}")?;
+    Ok(line)
+}
+
+// One object per line on stdout, flushed so readers see it right away
+fn emit(
+    event: &str,
+    fields: impl FnOnce(&mut Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let line = line(event, fields)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(&line)?;
+    out.flush()
+}
+
+fn optional(dest: &mut Vec<u8>, value: Option<i32>) -> Result<()> {
+    match value {
+        Some(value) => write!(dest, "{}", value),
+        None => write!(dest, "null"),
+    }
+}
+
+pub(crate) fn started(pid: i32, job: u64) -> Result<()> {
+    emit("started", |dest| {
+        write!(dest, ",\"pid\":{},\"job\":{}", pid, job)
+    })
+}
+
+pub(crate) fn failed(message: &str, errno: i32) -> Result<()> {
+    emit("failed", |dest| {
+        write!(dest, ",\"message\":")?;
+        json_string(dest, message)?;
+        write!(dest, ",\"errno\":{}", errno)
+    })
+}
+
+/// Bytes that are not UTF-8 are replaced, JSON strings can not hold
+/// them.
+pub(crate) fn output(stream: Files, data: &[u8]) -> Result<()> {
+    let name = if stream == Files::ERR {
+        "stderr"
+    } else {
+        "stdout"
+    };
+    emit("output", |dest| {
+        write!(dest, ",\"stream\":")?;
+        json_string(dest, name)?;
+        write!(dest, ",\"data\":")?;
+        json_string(dest, &String::from_utf8_lossy(data))
+    })
+}
+
+fn exit_fields(dest: &mut Vec<u8>, status: &msg::ProcessResult) -> Result<()> {
+    write!(dest, ",\"code\":")?;
+    optional(dest, status.exited)?;
+    write!(dest, ",\"signal\":")?;
+    optional(dest, status.signaled)?;
+    write!(
+        dest,
+        ",\"core_dumped\":{},\"duration\":{}",
+        status.core_dumped,
+        status.duration.as_secs_f64()
+    )?;
+    if let Some(ref usage) = status.rusage {
+        write!(
+            dest,
+            ",\"user_time\":{},\"system_time\":{},\"max_rss\":{}",
+            usage.user_time.as_secs_f64(),
+            usage.system_time.as_secs_f64(),
+            usage.max_rss
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) fn exited(status: &msg::ProcessResult) -> Result<()> {
+    emit("exited", |dest| exit_fields(dest, status))
+}
+
+/// Writes the `exited` event to `fd` and closes it.
+pub(crate) fn exited_to(fd: RawFd, status: &msg::ProcessResult) -> Result<()> {
+    let line = line("exited", |dest| exit_fields(dest, status))?;
+    unsafe { File::from_raw_fd(fd) }.write_all(&line)
+}
@@ -1,5 +1,9 @@
-use libc;
 use std::mem::MaybeUninit;
+use std::os::unix::io::RawFd;
+
+use log::{debug, warn};
+use nix::sys::termios::{self, SetArg, Termios};
+use nix::unistd::isatty;
 
 const PTHREAD_CANCEL_DISABLE: i32 = 1;
 
@@ -100,3 +104,43 @@ impl ThreadGuard {
         self.sigmask.mask()
     }
 }
+
+/// Puts saved terminal attributes back unless disarmed, so a program
+/// that dies in raw mode does not leave the terminal broken.
+pub struct TermiosGuard {
+    fd: RawFd,
+    saved: Option<Termios>,
+}
+
+impl TermiosGuard {
+    pub fn new(fds: &[RawFd]) -> Self {
+        let fd = fds
+            .iter()
+            .cloned()
+            .find(|fd| isatty(*fd).unwrap_or(false))
+            .unwrap_or(-1);
+        let saved = if fd >= 0 {
+            termios::tcgetattr(fd).ok()
+        } else {
+            None
+        };
+        TermiosGuard { fd, saved }
+    }
+
+    pub fn disarm(&mut self) {
+        self.saved = None;
+    }
+}
+
+impl Drop for TermiosGuard {
+    fn drop(&mut self) {
+        if let Some(ref saved) = self.saved {
+            debug!("restoring terminal attributes fd={}", self.fd);
+            if let Err(err) =
+                termios::tcsetattr(self.fd, SetArg::TCSADRAIN, saved)
+            {
+                warn!("failed to restore terminal: {}", err);
+            }
+        }
+    }
+}
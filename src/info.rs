@@ -0,0 +1,140 @@
+use std::io::{Result, Write};
+use std::path::Path;
+
+use log::debug;
+
+use crate::channel::{connect_failed, MessageChannel};
+use crate::messages as msg;
+use crate::raw::blocking::connect;
+use crate::runtime;
+use crate::socket::Socket;
+
+pub(crate) struct Args<'a> {
+    pub connect: &'a Path,
+    pub json: bool,
+}
+
+enum Value<'a> {
+    Str(&'a str),
+    Num(u64),
+    Bool(bool),
+    List(&'a [String]),
+    Null,
+}
+
+async fn execute(socket: Socket) -> Result<msg::ServerInfo> {
+    MessageChannel::new(socket)
+        .request(&msg::RequestInput::Info)
+        .await
+}
+
+fn optional(val: Option<&str>) -> Value<'_> {
+    val.map_or(Value::Null, Value::Str)
+}
+
+fn entries(info: &msg::ServerInfo) -> Vec<(&'static str, Value<'_>)> {
+    vec![
+        ("version", Value::Str(&info.version)),
+        ("pid", Value::Num(info.pid as u64)),
+        ("uid", Value::Num(u64::from(info.uid))),
+        ("privileged", Value::Bool(info.privileged)),
+        ("socket", Value::Str(&info.socket)),
+        ("state_file", optional(info.state_file.as_deref())),
+        ("log_file", optional(info.log_file.as_deref())),
+        ("buffer_size", Value::Num(info.buffer_size as u64)),
+        ("socket_sndbuf", Value::Num(info.socket_sndbuf as u64)),
+        ("socket_rcvbuf", Value::Num(info.socket_rcvbuf as u64)),
+        ("redact_env", Value::List(&info.redact_env)),
+        (
+            "status_retention",
+            Value::Num(info.status_retention.as_secs()),
+        ),
+        ("transport", Value::Str(&info.transport)),
+        ("exec_rate", Value::Num(u64::from(info.exec_rate))),
+        ("exec_burst", Value::Num(u64::from(info.exec_burst))),
+        ("max_args", Value::Num(info.max_args as u64)),
+        ("max_env", Value::Num(info.max_env as u64)),
+        ("max_env_size", Value::Num(info.max_env_size as u64)),
+        ("forbid_root", Value::Bool(info.forbid_root)),
+        ("policy_file", optional(info.policy_file.as_deref())),
+        ("started_at", Value::Num(info.started_at.as_secs())),
+        ("uptime", Value::Num(info.uptime.as_secs())),
+    ]
+}
+
+pub(crate) fn json_string(dest: &mut impl Write, text: &str) -> Result<()> {
+    write!(dest, "\"")?;
+    for ch in text.chars() {
+        match ch {
+            '"' => write!(dest, "\\\"")?,
+            '\\' => write!(dest, "\\\\")?,
+            '\n' => write!(dest, "\\n")?,
+            '\r' => write!(dest, "\\r")?,
+            '\t' => write!(dest, "\\t")?,
+            c if (c as u32) < 0x20 => write!(dest, "\\u{:04x}", c as u32)?,
+            c => write!(dest, "{}", c)?,
+        }
+    }
+    write!(dest, "\"")
+}
+
+fn print_json(dest: &mut impl Write, info: &msg::ServerInfo) -> Result<()> {
+    write!(dest, "{{")?;
+    for (i, (key, value)) in entries(info).iter().enumerate() {
+        if i > 0 {
+            write!(dest, ",")?;
+        }
+        json_string(dest, key)?;
+        write!(dest, ":")?;
+        match value {
+            Value::Str(s) => json_string(dest, s)?,
+            Value::Num(n) => write!(dest, "{}", n)?,
+            Value::Bool(b) => write!(dest, "{}", b)?,
+            Value::Null => write!(dest, "null")?,
+            Value::List(items) => {
+                write!(dest, "[")?;
+                for (j, item) in items.iter().enumerate() {
+                    if j > 0 {
+                        write!(dest, ",")?;
+                    }
+                    json_string(dest, item)?;
+                }
+                write!(dest, "]")?;
+            }
+        }
+    }
+    writeln!(dest, "}}")
+}
+
+fn print_text(dest: &mut impl Write, info: &msg::ServerInfo) -> Result<()> {
+    for (key, value) in entries(info) {
+        match value {
+            Value::Str(s) => writeln!(dest, "{}: {}", key, s)?,
+            Value::Num(n) => writeln!(dest, "{}: {}", key, n)?,
+            Value::Bool(b) => writeln!(dest, "{}: {}", key, b)?,
+            Value::Null => writeln!(dest, "{}: -", key)?,
+            Value::List(items) => {
+                writeln!(dest, "{}: {}", key, items.join(","))?
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn command(args: &Args) -> Result<i32> {
+    debug!("connecting to {:?}", args.connect);
+    match connect(args.connect) {
+        Ok(fd) => runtime::new()?.block_on(async {
+            let info = execute(Socket::from_fd(fd)?).await?;
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            if args.json {
+                print_json(&mut out, &info)?;
+            } else {
+                print_text(&mut out, &info)?;
+            }
+            Ok(0)
+        }),
+        Err(err) => Ok(connect_failed(args.connect, &err)),
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use log::{debug, error, info};
+
+use crate::debug;
+use crate::info::json_string;
+use crate::messages::{self as msg, JobInfo, JobSelector, JobState};
+use crate::system::{self, Pid};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Leader {
+    pub group: bool,
+    pub session: bool,
+}
+
+struct Entry {
+    info: JobInfo,
+    persistent: bool,
+}
+
+const MAX_FINISHED: usize = 256;
+
+struct Finished {
+    id: u64,
+    pid: i32,
+    state: JobState,
+    at: Instant,
+}
+
+struct Registry {
+    next_id: u64,
+    entries: Vec<Entry>,
+    finished: VecDeque<Finished>,
+    retention: Duration,
+    state: Option<PathBuf>,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry {
+            next_id: 1,
+            entries: Vec::new(),
+            finished: VecDeque::new(),
+            retention: Duration::from_secs(60),
+            state: None,
+        }
+    }
+
+    fn expire(&mut self) {
+        let retention = self.retention;
+        while let Some(oldest) = self.finished.front() {
+            if oldest.at.elapsed() < retention
+                && self.finished.len() <= MAX_FINISHED
+            {
+                break;
+            }
+            self.finished.pop_front();
+        }
+    }
+
+    fn prune(&mut self) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| {
+            !e.info.adopted || system::is_alive(Pid::from_raw(e.info.pid))
+        });
+        before != self.entries.len()
+    }
+
+    fn save(&self) {
+        let path = match self.state {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let jobs: Vec<&JobInfo> = self
+            .entries
+            .iter()
+            .filter(|e| e.persistent)
+            .map(|e| &e.info)
+            .collect();
+
+        if let Err(err) = write_state(path, &jobs) {
+            error!("failed to write state file {:?}: {}", path, err);
+        }
+    }
+}
+
+fn write_state(path: &Path, jobs: &[&JobInfo]) -> Result<()> {
+    let mut data = Vec::new();
+    msg::encode_request(&mut data, &jobs)?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, &data)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn read_state(path: &Path) -> Result<Vec<JobInfo>> {
+    match std::fs::read(path) {
+        Ok(data) => msg::decode_request(&data),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    lazy_static! {
+        static ref GLOBALS: Mutex<Registry> = Mutex::new(Registry::new());
+    }
+
+    &GLOBALS
+}
+
+pub(crate) fn restore(path: &Path) -> Result<()> {
+    let previous = read_state(path)?;
+    let mut reg = registry().lock().unwrap();
+    reg.state = Some(path.to_owned());
+
+    for mut info in previous {
+        reg.next_id = reg.next_id.max(info.id + 1);
+        if !system::is_alive(Pid::from_raw(info.pid)) {
+            debug!("job={} process={} is gone", info.id, info.pid);
+            continue;
+        }
+        info!("job={} process={} adopted", info.id, info.pid);
+        info.adopted = true;
+        reg.entries.push(Entry {
+            info,
+            persistent: true,
+        });
+    }
+
+    reg.save();
+    Ok(())
+}
+
+pub(crate) fn register(
+    pid: i32,
+    program: &[u8],
+    argv: &[&[u8]],
+    leader: Leader,
+    persistent: bool,
+) -> u64 {
+    let mut reg = registry().lock().unwrap();
+    let id = reg.next_id;
+    reg.next_id += 1;
+    reg.entries.push(Entry {
+        info: JobInfo {
+            id,
+            pid,
+            program: program.to_vec(),
+            argv: argv.iter().map(|s| s.to_vec()).collect(),
+            group_leader: leader.group,
+            session_leader: leader.session,
+            adopted: false,
+        },
+        persistent,
+    });
+    if persistent {
+        reg.save();
+    }
+    id
+}
+
+pub(crate) fn set_retention(retention: Duration) {
+    registry().lock().unwrap().retention = retention;
+}
+
+pub(crate) fn unregister(id: u64, result: Option<&msg::ProcessResult>) {
+    let mut reg = registry().lock().unwrap();
+    if let Some(pos) = reg.entries.iter().position(|e| e.info.id == id) {
+        let entry = reg.entries.remove(pos);
+        if entry.persistent {
+            reg.save();
+        }
+        reg.finished.push_back(Finished {
+            id,
+            pid: entry.info.pid,
+            state: result.map_or(JobState::Unknown, |r| r.state()),
+            at: Instant::now(),
+        });
+        reg.expire();
+    }
+}
+
+pub(crate) fn list() -> Vec<JobInfo> {
+    let mut reg = registry().lock().unwrap();
+    if reg.prune() {
+        reg.save();
+    }
+    reg.entries.iter().map(|e| e.info.clone()).collect()
+}
+
+/// Jobs that end with the server, those started without death signal
+/// are left running.
+pub(crate) fn terminable() -> Vec<(Pid, Leader)> {
+    let reg = registry().lock().unwrap();
+    reg.entries
+        .iter()
+        .filter(|e| !e.persistent)
+        .map(|e| {
+            let leader = Leader {
+                group: e.info.group_leader,
+                session: e.info.session_leader,
+            };
+            (Pid::from_raw(e.info.pid), leader)
+        })
+        .collect()
+}
+
+fn dump_job(dest: &mut impl Write, job: &JobInfo) -> Result<()> {
+    write!(dest, "{{\"id\":{},\"pid\":{},\"program\":", job.id, job.pid)?;
+    json_string(dest, &debug::text(&job.program))?;
+    write!(dest, ",\"argv\":[")?;
+    for (i, arg) in job.argv.iter().enumerate() {
+        if i > 0 {
+            write!(dest, ",")?;
+        }
+        json_string(dest, &debug::text(arg))?;
+    }
+    write!(dest, "],\"adopted\":{},", job.adopted)?;
+    match status(JobSelector::Id(job.id)) {
+        JobState::Running => write!(dest, "\"status\":\"running\"")?,
+        JobState::Exited(code) => {
+            write!(dest, "\"status\":\"exited\",\"code\":{}", code)?
+        }
+        JobState::Signaled(sig) => {
+            write!(dest, "\"status\":\"signaled\",\"signal\":{}", sig)?
+        }
+        JobState::Unknown => write!(dest, "\"status\":\"unknown\"")?,
+    }
+    write!(dest, "}}")
+}
+
+/// Writes `jobs` with their last known status as a JSON array.
+pub(crate) fn dump(path: &Path, jobs: &[JobInfo]) -> Result<()> {
+    let mut data = Vec::new();
+    write!(data, "[")?;
+    for (i, job) in jobs.iter().enumerate() {
+        if i > 0 {
+            write!(data, ",")?;
+        }
+        dump_job(&mut data, job)?;
+    }
+    writeln!(data, "]")?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, &data)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn matches(selector: JobSelector, id: u64, pid: i32) -> bool {
+    match selector {
+        JobSelector::Id(value) => value == id,
+        JobSelector::Pid(value) => value == pid,
+    }
+}
+
+/// Id, process and leadership of running job picked by `selector`.
+pub(crate) fn find(selector: JobSelector) -> Option<(u64, Pid, Leader)> {
+    let mut reg = registry().lock().unwrap();
+    if reg.prune() {
+        reg.save();
+    }
+    reg.entries
+        .iter()
+        .find(|e| matches(selector, e.info.id, e.info.pid))
+        .map(|e| {
+            let leader = Leader {
+                group: e.info.group_leader,
+                session: e.info.session_leader,
+            };
+            (e.info.id, Pid::from_raw(e.info.pid), leader)
+        })
+}
+
+pub(crate) fn status(selector: JobSelector) -> JobState {
+    let matches = |id, pid| matches(selector, id, pid);
+
+    let mut reg = registry().lock().unwrap();
+    if reg.prune() {
+        reg.save();
+    }
+    reg.expire();
+
+    if reg.entries.iter().any(|e| matches(e.info.id, e.info.pid)) {
+        return JobState::Running;
+    }
+
+    reg.finished
+        .iter()
+        .rev()
+        .find(|f| matches(f.id, f.pid))
+        .map_or(JobState::Unknown, |f| f.state)
+}
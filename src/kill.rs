@@ -0,0 +1,54 @@
+use std::io::{Error as IoError, Result};
+use std::path::Path;
+
+use log::{debug, error};
+
+use crate::channel::{connect_failed, MessageChannel};
+use crate::messages as msg;
+use crate::raw::blocking::connect;
+use crate::runtime;
+use crate::socket::Socket;
+
+pub(crate) struct Args<'a> {
+    pub connect: &'a Path,
+    pub request: msg::KillRequest,
+}
+
+async fn execute(
+    socket: Socket,
+    request: msg::KillRequest,
+) -> Result<msg::KillResult> {
+    MessageChannel::new(socket)
+        .request(&msg::RequestInput::Kill(request))
+        .await
+}
+
+pub(crate) fn command(args: &Args) -> Result<i32> {
+    debug!("connecting to {:?}", args.connect);
+    match connect(args.connect) {
+        Ok(fd) => runtime::new()?.block_on(async {
+            let result = execute(Socket::from_fd(fd)?, args.request).await?;
+            if !result.found {
+                match args.request.selector {
+                    msg::JobSelector::Id(id) => {
+                        error!("no job with id {}", id)
+                    }
+                    msg::JobSelector::Pid(pid) => {
+                        error!("no job with pid {}", pid)
+                    }
+                }
+                Ok(1)
+            } else if result.errno != 0 {
+                error!(
+                    "failed to send signal\n    \
+                     error:  {}",
+                    IoError::from_raw_os_error(result.errno)
+                );
+                Ok(1)
+            } else {
+                Ok(0)
+            }
+        }),
+        Err(err) => Ok(connect_failed(args.connect, &err)),
+    }
+}
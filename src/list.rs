@@ -0,0 +1,60 @@
+use std::io::{Result, Write};
+use std::path::Path;
+
+use log::debug;
+
+use crate::channel::{connect_failed, MessageChannel};
+use crate::debug;
+use crate::messages as msg;
+use crate::raw::blocking::connect;
+use crate::runtime;
+use crate::socket::Socket;
+
+pub(crate) struct Args<'a> {
+    pub connect: &'a Path,
+}
+
+async fn execute(socket: Socket) -> Result<msg::JobList> {
+    MessageChannel::new(socket)
+        .request(&msg::RequestInput::List)
+        .await
+}
+
+fn print(dest: &mut impl Write, list: &msg::JobList) -> Result<()> {
+    writeln!(dest, "{:<6} {:<8} {:<6} COMMAND", "ID", "PID", "LEADER")?;
+    for job in &list.jobs {
+        let leader = match (job.session_leader, job.group_leader) {
+            (true, _) => "sess",
+            (false, true) => "pgrp",
+            (false, false) => "-",
+        };
+        write!(
+            dest,
+            "{:<6} {:<8} {:<6} {}",
+            job.id,
+            job.pid,
+            leader,
+            debug::text(&job.program)
+        )?;
+        for arg in &job.argv {
+            write!(dest, " {}", debug::text(arg))?;
+        }
+        if job.adopted {
+            write!(dest, " (adopted)")?;
+        }
+        writeln!(dest)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn command(args: &Args) -> Result<i32> {
+    debug!("connecting to {:?}", args.connect);
+    match connect(args.connect) {
+        Ok(fd) => runtime::new()?.block_on(async {
+            let list = execute(Socket::from_fd(fd)?).await?;
+            print(&mut std::io::stdout().lock(), &list)?;
+            Ok(0)
+        }),
+        Err(err) => Ok(connect_failed(args.connect, &err)),
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::{self, Level, Log};
+
+struct Sink {
+    path: Option<PathBuf>,
+    file: Option<File>,
+}
+
+fn sink() -> &'static Mutex<Sink> {
+    lazy_static! {
+        static ref GLOBALS: Mutex<Sink> = Mutex::new(Sink {
+            path: None,
+            file: None,
+        });
+    }
+
+    &GLOBALS
+}
+
+// kept outside the installed logger, `loglevel` changes it at runtime
+static VERBOSITY: AtomicU32 = AtomicU32::new(0);
+
+fn filter(verbosity: u32) -> (Level, Level) {
+    match verbosity {
+        0 => (Level::Warn, Level::Warn),
+        1 => (Level::Info, Level::Warn),
+        2 => (Level::Debug, Level::Info),
+        3 => (Level::Debug, Level::Debug),
+        _ => (Level::Trace, Level::Trace),
+    }
+}
+
+// own messages are filtered apart from those of dependencies
+fn is_enabled(verbosity: u32, metadata: &log::Metadata) -> bool {
+    let (own, others) = filter(verbosity);
+    if metadata.target().starts_with("sidecar") {
+        metadata.level() <= own
+    } else {
+        metadata.level() <= others
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        is_enabled(VERBOSITY.load(Ordering::Relaxed), metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let lch = match record.level() {
+            Level::Error => "[E ",
+            Level::Warn => "[W ",
+            Level::Info => "[I ",
+            Level::Debug => "[D ",
+            Level::Trace => "[T ",
+        };
+
+        let mut sink = sink().lock().unwrap();
+        if let Some(ref mut file) = sink.file {
+            let _ = writeln!(
+                file,
+                "{}{}] {}",
+                lch,
+                record.target(),
+                record.args()
+            );
+            return;
+        }
+
+        let lout = std::io::stderr();
+        let mut out = lout.lock();
+        writeln!(&mut out, "{}{}] {}", lch, record.target(), record.args())
+            .unwrap();
+    }
+
+    fn flush(&self) {
+        //
+    }
+}
+
+pub(crate) fn configure(verbosity: u32) {
+    log::set_boxed_logger(Box::new(Logger)).unwrap();
+    set_verbosity(verbosity);
+}
+
+pub(crate) fn verbosity() -> u32 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_verbosity(verbosity: u32) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+    log::set_max_level(filter(verbosity).0.to_level_filter())
+}
+
+/// Name of the level `verbosity` enables for own messages.
+pub(crate) fn level_name(verbosity: u32) -> &'static str {
+    match filter(verbosity).0 {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+fn open_file(path: &Path) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+pub(crate) fn open(path: &Path) -> Result<()> {
+    let file = open_file(path)?;
+    let mut sink = sink().lock().unwrap();
+    sink.path = Some(path.to_owned());
+    sink.file = Some(file);
+    Ok(())
+}
+
+pub(crate) fn path() -> Option<PathBuf> {
+    sink().lock().unwrap().path.clone()
+}
+
+pub(crate) fn is_file() -> bool {
+    sink().lock().unwrap().file.is_some()
+}
+
+pub(crate) fn reopen() -> Result<()> {
+    let mut sink = sink().lock().unwrap();
+    let file = match sink.path {
+        Some(ref path) => open_file(path)?,
+        None => return Ok(()),
+    };
+    sink.file = Some(file);
+    Ok(())
+}
+
+/// Accepts a level name or a verbosity count as given with `-v`.
+pub(crate) fn level_from_str(text: &str) -> std::result::Result<u32, String> {
+    match text {
+        "warn" => Ok(0),
+        "info" => Ok(1),
+        "debug" => Ok(2),
+        "trace" => Ok(4),
+        _ => text.parse().map_err(|_| {
+            format!(
+                "unknown level {:?}, expected warn, info, debug or trace",
+                text
+            )
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(verbosity: u32, target: &str, level: Level) -> bool {
+        let metadata =
+            log::Metadata::builder().target(target).level(level).build();
+        is_enabled(verbosity, &metadata)
+    }
+
+    #[test]
+    fn level_names_and_counts() {
+        assert_eq!(level_from_str("warn"), Ok(0));
+        assert_eq!(level_from_str("info"), Ok(1));
+        assert_eq!(level_from_str("debug"), Ok(2));
+        assert_eq!(level_from_str("trace"), Ok(4));
+        assert_eq!(level_from_str("3"), Ok(3));
+        assert!(level_from_str("loud").is_err());
+        assert!(level_from_str("-1").is_err());
+
+        for name in &["warn", "info", "debug", "trace"] {
+            assert_eq!(level_name(level_from_str(name).unwrap()), *name);
+        }
+    }
+
+    #[test]
+    fn verbosity_picks_levels() {
+        assert!(enabled(0, "sidecar::server", Level::Warn));
+        assert!(!enabled(0, "sidecar::server", Level::Info));
+        assert!(enabled(1, "sidecar::server", Level::Info));
+        assert!(!enabled(1, "tokio", Level::Info));
+        assert!(enabled(2, "sidecar", Level::Debug));
+        assert!(enabled(2, "mio", Level::Info));
+        assert!(!enabled(2, "mio", Level::Debug));
+        assert!(!enabled(3, "sidecar", Level::Trace));
+        assert!(enabled(3, "mio", Level::Debug));
+        assert!(enabled(4, "mio", Level::Trace));
+        assert!(enabled(u32::MAX, "sidecar", Level::Trace));
+    }
+}
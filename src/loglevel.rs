@@ -0,0 +1,41 @@
+use std::io::{Result, Write};
+use std::path::Path;
+
+use log::debug;
+
+use crate::channel::{connect_failed, MessageChannel};
+use crate::logger;
+use crate::messages as msg;
+use crate::raw::blocking::connect;
+use crate::runtime;
+use crate::socket::Socket;
+
+pub(crate) struct Args<'a> {
+    pub connect: &'a Path,
+    pub verbosity: Option<u32>,
+}
+
+async fn execute(
+    socket: Socket,
+    verbosity: Option<u32>,
+) -> Result<msg::LogLevelResult> {
+    MessageChannel::new(socket)
+        .request(&msg::RequestInput::LogLevel(verbosity))
+        .await
+}
+
+pub(crate) fn command(args: &Args) -> Result<i32> {
+    debug!("connecting to {:?}", args.connect);
+    match connect(args.connect) {
+        Ok(fd) => runtime::new()?.block_on(async {
+            let result = execute(Socket::from_fd(fd)?, args.verbosity).await?;
+            writeln!(
+                std::io::stdout(),
+                "{}",
+                logger::level_name(result.verbosity)
+            )?;
+            Ok(0)
+        }),
+        Err(err) => Ok(connect_failed(args.connect, &err)),
+    }
+}
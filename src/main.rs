@@ -1,9 +1,11 @@
 //! Main
 
+mod channel;
 mod child;
 mod child_watcher;
 mod debug;
 mod guards;
+mod logger;
 mod messages;
 mod pipe;
 mod raw;
@@ -13,21 +15,41 @@ mod socket;
 mod system;
 mod tty;
 
+mod attach;
+mod capture;
 mod client;
+mod doctor;
+mod events;
+mod info;
+mod jobs;
+mod kill;
+mod list;
+mod loglevel;
+mod policy;
+mod ratelimit;
+mod relay;
+mod reload;
 mod server;
+mod status;
 mod stop;
 
+use std::ffi::OsString;
 use std::io::{Result, Write};
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::system::{signal_from_str, Signal};
+use crate::socket::BufferSizes;
+use crate::system::{signal_from_str, signal_number_from_str, Signal};
 use gumdrop::{Options, ParsingStyle};
-use log::{self, error, Level, Log};
+use log::error;
+use scopeguard::defer;
 
 const NAME: &str = env!("CARGO_PKG_NAME");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const SOCKET_ENV: &str = "SIDECAR_SOCKET";
 
 #[derive(Debug, Options)]
 struct Cli {
@@ -52,8 +74,35 @@ enum Command {
     /// Stop running server
     Stop(StopCommand),
 
+    /// Stop accepting connections, exit once running jobs finish
+    Drain(DrainCommand),
+
+    /// Apply changed policy file of running server
+    Reload(ReloadCommand),
+
+    /// Show or change log level of running server
+    Loglevel(LoglevelCommand),
+
     /// Execute command on server
     Exec(ExecCommand),
+
+    /// List jobs running on server
+    List(ListCommand),
+
+    /// Show status of a single job
+    Status(StatusCommand),
+
+    /// Send signal to a job on server
+    Kill(KillCommand),
+
+    /// Follow relayed output of a job without controlling it
+    Attach(AttachCommand),
+
+    /// Show configuration of running server
+    Info(InfoCommand),
+
+    /// Check which features are usable on this system
+    Doctor(DoctorCommand),
 }
 
 /// Start server and wait for commands
@@ -68,6 +117,30 @@ struct StartCommand {
     #[options(help = "make parent directories as needed")]
     parents: bool,
 
+    #[options(
+        help = "set permissions of socket directory created by --parents",
+        meta = "MODE",
+        no_short,
+        parse(try_from_str = "mode_from_str")
+    )]
+    socket_dir_mode: Option<u32>,
+
+    #[options(
+        help = "set permissions of the socket, e.g. 0600 for owner only",
+        meta = "MODE",
+        no_short,
+        parse(try_from_str = "mode_from_str")
+    )]
+    socket_mode: Option<u32>,
+
+    #[options(
+        help = "set file mode creation mask of server",
+        meta = "MASK",
+        no_short,
+        parse(try_from_str = "mode_from_str")
+    )]
+    umask: Option<u32>,
+
     #[options(help = "set the effective user ID", meta = "UID", no_short)]
     setuid: Option<u32>,
 
@@ -87,8 +160,188 @@ struct StartCommand {
     #[options(help = "detach process from /dev/tty", no_short)]
     notty: bool,
 
+    #[options(
+        help = "receive signal NAME when launching process exits",
+        meta = "NAME",
+        no_short,
+        parse(try_from_str = "signal_from_str")
+    )]
+    parent_death_signal: Option<Signal>,
+
+    #[options(
+        help = "keep surviving jobs in FILE to adopt them after restart",
+        meta = "FILE",
+        no_short
+    )]
+    state_file: Option<PathBuf>,
+
+    #[options(
+        help = "write jobs left at shutdown to FILE as JSON",
+        meta = "FILE",
+        no_short
+    )]
+    dump_jobs: Option<PathBuf>,
+
+    #[options(
+        help = "hide values of environment variables containing PATTERN",
+        meta = "PATTERN",
+        no_short
+    )]
+    redact_env: Vec<String>,
+
+    #[options(
+        help = "write log to FILE, reopened on SIGHUP",
+        meta = "FILE",
+        no_short
+    )]
+    log_file: Option<PathBuf>,
+
+    #[options(
+        help = "keep status of finished jobs for SECS seconds",
+        default = "60",
+        meta = "SECS",
+        no_short
+    )]
+    status_retention: u64,
+
+    #[options(
+        help = "listen on SOCK_STREAM with length-prefixed messages",
+        no_short
+    )]
+    stream: bool,
+
+    #[options(
+        help = "set SO_SNDBUF of connections to BYTES",
+        meta = "BYTES",
+        no_short
+    )]
+    socket_sndbuf: Option<usize>,
+
+    #[options(
+        help = "set SO_RCVBUF of connections to BYTES",
+        meta = "BYTES",
+        no_short
+    )]
+    socket_rcvbuf: Option<usize>,
+
+    #[options(
+        help = "allow N exec requests per second (0 for no limit)",
+        default = "0",
+        meta = "N",
+        no_short
+    )]
+    exec_rate: u32,
+
+    #[options(
+        help = "allow bursts of N exec requests (defaults to rate)",
+        meta = "N",
+        no_short
+    )]
+    exec_burst: Option<u32>,
+
+    #[options(help = "limit exec rate separately per peer uid", no_short)]
+    exec_rate_per_uid: bool,
+
+    #[options(help = "refuse to run programs as root", no_short)]
+    forbid_root: bool,
+
+    #[options(
+        help = "refuse programs with more than N arguments",
+        default = "4096",
+        meta = "N",
+        no_short
+    )]
+    max_args: usize,
+
+    #[options(
+        help = "refuse programs with more than N environment variables",
+        default = "1024",
+        meta = "N",
+        no_short
+    )]
+    max_env: usize,
+
+    #[options(
+        help = "refuse environment larger than BYTES in total",
+        default = "1048576",
+        meta = "BYTES",
+        no_short
+    )]
+    max_env_size: usize,
+
+    #[options(
+        help = "read limits from FILE, again on `reload`",
+        meta = "FILE",
+        no_short
+    )]
+    policy: Option<PathBuf>,
+
+    #[options(help = "print socket path once listening", no_short)]
+    print_socket: bool,
+
+    #[options(help = "log every exec request in full as received", no_short)]
+    log_requests: bool,
+
+    #[options(
+        help = "bind in a new private directory, implies --print-socket",
+        no_short
+    )]
+    temp: bool,
+
+    #[options(
+        help = "drain and exit SECS seconds after start",
+        meta = "SECS",
+        no_short
+    )]
+    max_runtime: Option<u64>,
+
+    #[options(
+        help = "log running jobs and uptime every SECS seconds",
+        meta = "SECS",
+        no_short
+    )]
+    heartbeat_log: Option<u64>,
+
+    #[options(
+        help = "give jobs SECS seconds after SIGTERM before SIGKILL",
+        default = "5",
+        meta = "SECS",
+        no_short
+    )]
+    kill_grace: u64,
+
+    #[options(
+        help = "exit with the status of the supervised program",
+        no_short
+    )]
+    propagate_exit: bool,
+
+    #[options(
+        help = "restart the supervised program on signal NAME",
+        meta = "NAME",
+        no_short,
+        parse(try_from_str = "signal_from_str")
+    )]
+    restart_on_signal: Option<Signal>,
+
+    #[options(
+        help = "stop the supervised program with NAME to restart it",
+        meta = "NAME",
+        default_expr = "Signal::SIGTERM",
+        no_short,
+        parse(try_from_str = "signal_from_str")
+    )]
+    restart_stop_signal: Signal,
+
     #[options(help = "server socket location", free)]
     path: PathBuf,
+
+    #[options(
+        help = "program to supervise, server exits after it",
+        free,
+        parse(from_str = "OsString::from")
+    )]
+    program: Vec<OsString>,
 }
 
 /// Stop running server
@@ -100,125 +353,1085 @@ struct StopCommand {
     #[options(count, help = "enable debug messages (up to 3)")]
     verbose: u32,
 
+    #[options(
+        help = "give up after SECS seconds (0 to wait forever)",
+        default = "5",
+        meta = "SECS"
+    )]
+    timeout: u64,
+
+    #[options(
+        help = "give jobs SECS seconds after SIGTERM before SIGKILL",
+        meta = "SECS",
+        no_short
+    )]
+    kill_grace: Option<u64>,
+
     #[options(help = "server socket location", free)]
     path: PathBuf,
 }
 
-/// Execute command on server
+/// Stop accepting connections, exit once running jobs finish
 #[derive(Debug, Options)]
-struct ExecCommand {
+struct DrainCommand {
     #[options(help = "print help message and exit")]
     help: bool,
 
     #[options(count, help = "enable debug messages (up to 3)")]
     verbose: u32,
 
-    #[options(help = "server socket location")]
-    connect: PathBuf,
-
     #[options(
-        help = "set each NAME to VALUE in the environment",
-        meta = "NAME=VALUE"
+        help = "give up after SECS seconds (0 to wait forever)",
+        default = "5",
+        meta = "SECS"
     )]
-    env: Vec<String>,
+    timeout: u64,
 
-    #[options(help = "change working directory to DIR", meta = "DIR")]
-    workdir: String,
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
+}
+
+/// Apply changed policy file of running server
+#[derive(Debug, Options)]
+struct ReloadCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
+
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
+
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
+}
+
+/// Show or change log level of running server
+#[derive(Debug, Options)]
+struct LoglevelCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
+
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
+
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
 
     #[options(
-        help = "set user id",
-        default_expr = "-1",
-        meta = "UID",
-        no_short
+        help = "warn, info, debug, trace or verbosity count",
+        free,
+        parse(try_from_str = "logger::level_from_str")
     )]
-    setuid: i32,
+    level: Option<u32>,
+}
+
+/// List jobs running on server
+#[derive(Debug, Options)]
+struct ListCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
+
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
+
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
+}
+
+/// Show status of a single job
+#[derive(Debug, Options)]
+struct StatusCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
+
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
+
+    #[options(help = "job id as shown by list", meta = "ID")]
+    job: Option<u64>,
+
+    #[options(help = "job process id", meta = "PID")]
+    pid: Option<i32>,
+
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
+}
+
+/// Send signal to a job on server
+#[derive(Debug, Options)]
+struct KillCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
+
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
+
+    #[options(help = "job id as shown by list", meta = "ID")]
+    job: Option<u64>,
+
+    #[options(help = "job process id", meta = "PID")]
+    pid: Option<i32>,
 
     #[options(
-        help = "set group id",
-        default_expr = "-1",
-        meta = "GID",
-        no_short
+        help = "signal to send, SIGRTMIN+N for realtime (default: SIGTERM)",
+        meta = "NAME",
+        default_expr = "Signal::SIGTERM as i32",
+        parse(try_from_str = "signal_number_from_str")
     )]
-    setgid: i32,
+    signal: i32,
 
     #[options(
-        help = "set process group (0 to become leader)",
-        meta = "PGID",
+        help = "queue integer N along with the signal",
+        meta = "N",
         no_short
     )]
-    setpgid: Option<i32>,
+    value: Option<i32>,
 
-    #[options(help = "run program in a new session", no_short)]
-    setsid: bool,
+    #[options(help = "signal the job's process group", no_short)]
+    group: bool,
 
-    #[options(help = "detach from /dev/tty", no_short)]
-    notty: bool,
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
+}
 
-    #[options(
-        help = "deliver the signal when parent process exits",
-        default_expr = "Signal::SIGKILL",
-        no_short,
-        parse(try_from_str = "signal_from_str")
-    )]
-    deathsig: Signal,
+/// Follow relayed output of a job without controlling it
+#[derive(Debug, Options)]
+struct AttachCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
 
-    #[options(help = "program arguments to execute", free)]
-    program: Vec<String>,
-}
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
 
-fn env_to_kv(arg: &str) -> (&str, &str) {
-    for (i, val) in arg.bytes().enumerate() {
-        if val == b'=' {
-            return (
-                std::str::from_utf8(&arg.as_bytes()[..i]).unwrap(),
-                std::str::from_utf8(&arg.as_bytes()[i + 1..]).unwrap(),
-            );
-        }
-    }
-    (arg, &arg[arg.len()..arg.len()])
+    #[options(help = "job id as shown by list", meta = "ID")]
+    job: Option<u64>,
+
+    #[options(help = "job process id", meta = "PID")]
+    pid: Option<i32>,
+
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
 }
 
-fn command_exec(arg: &ExecCommand) -> Result<i32> {
-    if arg.program.is_empty() {
-        return Ok(0);
-    }
+/// Show configuration of running server
+#[derive(Debug, Options)]
+struct InfoCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
 
-    if arg.connect.as_os_str().is_empty() {
-        return command_exec_local(&arg);
-    }
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
 
-    system::disable_inherit_stdio()?;
+    #[options(help = "print as JSON object", no_short)]
+    json: bool,
 
-    // let program: &str = &arg.program[0];
-    let args: Vec<&str> =
-        arg.program[1..].iter().map(|s| s.as_ref()).collect();
-    let envs: Vec<_> = arg.env.iter().map(|s| env_to_kv(&s)).collect();
+    #[options(help = "server socket location", free)]
+    path: PathBuf,
+}
+
+/// Check which features are usable on this system
+#[derive(Debug, Options)]
+struct DoctorCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
+
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
+}
+
+/// Execute command on server
+#[derive(Debug, Options)]
+struct ExecCommand {
+    #[options(help = "print help message and exit")]
+    help: bool,
+
+    #[options(count, help = "enable debug messages (up to 3)")]
+    verbose: u32,
+
+    #[options(help = "server socket location (default: $SIDECAR_SOCKET)")]
+    connect: PathBuf,
+
+    #[options(
+        help = "use already connected server socket FD",
+        meta = "FD",
+        no_short
+    )]
+    connect_fd: Option<i32>,
+
+    #[options(help = "execute program locally, without server", no_short)]
+    local: bool,
+
+    #[options(
+        help = "set each NAME to VALUE in the environment",
+        meta = "NAME=VALUE"
+    )]
+    env: Vec<String>,
+
+    #[options(help = "pass own environment to program", no_short)]
+    preserve_env: bool,
+
+    #[options(help = "pass own LANG, LC_* and TZ to program", no_short)]
+    forward_locale: bool,
+
+    #[options(
+        help = "do not pass own NAME with --preserve-env",
+        meta = "NAME",
+        no_short
+    )]
+    env_except: Vec<String>,
+
+    #[options(help = "change working directory to DIR", meta = "DIR")]
+    workdir: String,
+
+    #[options(
+        help = "pass working directory to server as descriptor",
+        no_short
+    )]
+    cwd_fd_inherit: bool,
+
+    #[options(
+        help = "change root directory to DIR, --workdir is inside of it",
+        meta = "DIR",
+        no_short
+    )]
+    chroot: String,
+
+    #[options(
+        help = "set user id",
+        default_expr = "-1",
+        meta = "UID",
+        no_short
+    )]
+    setuid: i32,
+
+    #[options(
+        help = "set group id",
+        default_expr = "-1",
+        meta = "GID",
+        no_short
+    )]
+    setgid: i32,
+
+    #[options(
+        help = "set supplementary groups to GID, may be repeated",
+        meta = "GID",
+        no_short
+    )]
+    group: Vec<u32>,
+
+    #[options(
+        help = "set file mode creation mask of program",
+        meta = "MASK",
+        no_short,
+        parse(try_from_str = "mode_from_str")
+    )]
+    umask: Option<u32>,
+
+    #[options(
+        help = "set process group (0 to become leader)",
+        meta = "PGID",
+        no_short
+    )]
+    setpgid: Option<i32>,
+
+    #[options(help = "run program in a new session", no_short)]
+    setsid: bool,
+
+    #[options(
+        help = "run program in a new session with stdin tty as terminal",
+        no_short
+    )]
+    new_session_keep_tty: bool,
+
+    #[options(
+        help = "run program in a new session with PATH as terminal",
+        meta = "PATH",
+        no_short
+    )]
+    ctty: Option<PathBuf>,
+
+    #[options(help = "detach from /dev/tty", no_short)]
+    notty: bool,
+
+    #[options(help = "disable core dumps for program", no_short)]
+    no_core: bool,
+
+    #[options(
+        help = "run program from login shell of --setuid user like su -",
+        no_short
+    )]
+    login: bool,
+
+    #[options(help = "apply own resource limits to program", no_short)]
+    inherit_rlimits: bool,
+
+    #[options(
+        help = "limit program to SECS seconds of CPU time",
+        meta = "SECS",
+        no_short
+    )]
+    cpu_limit: Option<u64>,
+
+    #[options(
+        help = "set resource limit of program, e.g. nofile=1024 or cpu=60:70",
+        meta = "NAME=SOFT[:HARD]",
+        no_short,
+        parse(try_from_str = "limit_from_str")
+    )]
+    limit: Vec<messages::ResourceLimit>,
+
+    #[options(help = "give program /dev/null as stdin", no_short)]
+    no_stdin: bool,
+
+    #[options(help = "discard program stdout", no_short)]
+    no_stdout: bool,
+
+    #[options(help = "discard program stderr", no_short)]
+    no_stderr: bool,
+
+    #[options(
+        help = "deliver the signal when parent process exits",
+        default_expr = "Signal::SIGKILL",
+        no_short,
+        parse(try_from_str = "signal_from_str")
+    )]
+    deathsig: Signal,
+
+    #[options(help = "do not set death signal for program", no_short)]
+    no_deathsig: bool,
+
+    #[options(
+        help = "send signal NAME to program right after start",
+        meta = "NAME",
+        no_short,
+        parse(try_from_str = "signal_from_str")
+    )]
+    start_signal: Option<Signal>,
+
+    #[options(
+        help = "stop program right after start, e.g. to attach debugger",
+        no_short
+    )]
+    start_stopped: bool,
+
+    #[options(help = "keep program running if client disconnects", no_short)]
+    detach: bool,
+
+    #[options(
+        help = "return once program is started (implies --detach)",
+        no_short
+    )]
+    no_wait: bool,
+
+    #[options(
+        help = "assert own credentials to server (Linux only)",
+        no_short
+    )]
+    assert_creds: bool,
+
+    #[options(
+        help = "prefix each line of program output with STR",
+        meta = "STR",
+        no_short
+    )]
+    output_prefix: Option<String>,
+
+    #[options(
+        help = "relay program stdio over the connection, pass no descriptors",
+        no_short
+    )]
+    relay: bool,
+
+    #[options(
+        help = "give program a pseudo-terminal as stdout, relayed back",
+        no_short
+    )]
+    pty_stdout: bool,
+
+    #[options(help = "fail instead of warning about own stdio", no_short)]
+    strict: bool,
+
+    #[options(
+        help = "print start, relayed output and exit as JSON lines",
+        no_short
+    )]
+    json: bool,
+
+    #[options(
+        help = "write exit status as a JSON line to own FD when done",
+        meta = "FD",
+        no_short
+    )]
+    status_fd: Option<i32>,
+
+    #[options(
+        help = "kill program after SECS seconds without output (--relay)",
+        meta = "SECS",
+        no_short
+    )]
+    idle_timeout: Option<u64>,
+
+    #[options(
+        help = "send SIGTERM after N[smhd] and exit with 124, 0 for none",
+        meta = "DURATION",
+        no_short,
+        parse(try_from_str = "duration_from_str")
+    )]
+    timeout: Option<Duration>,
+
+    #[options(
+        help = "forward every signal to the program's process group",
+        no_short
+    )]
+    group_signals: bool,
+
+    #[options(
+        help = "set SO_SNDBUF of server connection to BYTES",
+        meta = "BYTES",
+        no_short
+    )]
+    socket_sndbuf: Option<usize>,
+
+    #[options(
+        help = "set SO_RCVBUF of server connection to BYTES",
+        meta = "BYTES",
+        no_short
+    )]
+    socket_rcvbuf: Option<usize>,
+
+    #[options(
+        help = "give program fds in LIST the descriptor of the first (0,1,2)",
+        meta = "LIST",
+        no_short
+    )]
+    same_stdio: Option<String>,
+
+    #[options(
+        help = "pass own descriptor SOURCEFD to program as CHILDFD",
+        meta = "CHILDFD=SOURCEFD",
+        no_short
+    )]
+    fd: Vec<String>,
+
+    #[options(
+        help = "read program and arguments from PATH (- for stdin)",
+        meta = "PATH",
+        no_short
+    )]
+    command_file: Option<PathBuf>,
+
+    #[options(
+        help = "program arguments to execute",
+        free,
+        parse(from_str = "OsString::from")
+    )]
+    program: Vec<OsString>,
+}
+
+/// Octal permission bits, nothing above `7777`.
+fn mode_from_str(text: &str) -> std::result::Result<u32, String> {
+    match u32::from_str_radix(text, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        Ok(_) => Err(format!("mode {:?} is above 7777", text)),
+        Err(err) => Err(format!("invalid mode {:?}: {}", text, err)),
+    }
+}
+
+/// Number with an optional `s`, `m`, `h` or `d` suffix like coreutils
+/// `timeout`, seconds without one.
+fn duration_from_str(text: &str) -> std::result::Result<Duration, String> {
+    let (number, unit) = match text.char_indices().last() {
+        Some((pos, 's')) => (&text[..pos], 1),
+        Some((pos, 'm')) => (&text[..pos], 60),
+        Some((pos, 'h')) => (&text[..pos], 60 * 60),
+        Some((pos, 'd')) => (&text[..pos], 24 * 60 * 60),
+        _ => (text, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration {:?}", text))
+}
+
+/// Parses `NAME=SOFT[:HARD]`, the hard limit is the soft one unless
+/// given, `unlimited` stands for no limit.
+fn limit_from_str(
+    text: &str,
+) -> std::result::Result<messages::ResourceLimit, String> {
+    let (name, value) = match text.find('=') {
+        Some(pos) => (&text[..pos], &text[pos + 1..]),
+        None => {
+            return Err(format!("expected NAME=SOFT[:HARD], got {:?}", text))
+        }
+    };
+    let resource = system::resource_from_name(name).ok_or_else(|| {
+        format!(
+            "unknown resource {:?}, expected one of core, cpu, data, \
+             fsize, nofile, stack, as, nproc or memlock",
+            name
+        )
+    })?;
+    let number = |value: &str| match value {
+        "unlimited" | "infinity" => Ok(None),
+        _ => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid limit {:?} for {}", value, name)),
+    };
+    let (soft, hard) = match value.find(':') {
+        Some(pos) => (number(&value[..pos])?, number(&value[pos + 1..])?),
+        None => {
+            let soft = number(value)?;
+            (soft, soft)
+        }
+    };
+    // no limit is above any number
+    if hard.is_some() && (soft.is_none() || soft > hard) {
+        return Err(format!("soft limit of {} is above the hard one", name));
+    }
+    Ok(messages::ResourceLimit {
+        resource,
+        soft,
+        hard,
+    })
+}
+
+/// Splits `NAME=VALUE`, the value may be empty but the `=` is required.
+fn env_to_kv(arg: &str) -> std::result::Result<(&[u8], &[u8]), String> {
+    let bytes = arg.as_bytes();
+    let (name, value) = match bytes.iter().position(|b| *b == b'=') {
+        Some(pos) => (&bytes[..pos], &bytes[pos + 1..]),
+        None => return Err(format!("expected NAME=VALUE: {:?}", arg)),
+    };
+    if name.is_empty() {
+        return Err(format!("expected NAME=VALUE: {:?}", arg));
+    }
+    if bytes.contains(&0) {
+        return Err(format!("{:?} contains a NUL byte", arg));
+    }
+    Ok((name, value))
+}
+
+// NUL separated if there is any NUL, one argument per line otherwise
+fn split_command(data: Vec<u8>) -> Vec<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let delim = if data.contains(&0) { 0 } else { b'\n' };
+    let data = match data.split_last() {
+        Some((last, rest)) if *last == delim => rest,
+        _ => &data[..],
+    };
+    if data.is_empty() {
+        return Vec::new();
+    }
+    data.split(|b| *b == delim)
+        .map(|arg| OsString::from_vec(arg.to_vec()))
+        .collect()
+}
+
+fn read_command_file(path: &Path) -> Result<Vec<OsString>> {
+    use std::io::Read;
+
+    let mut data = Vec::new();
+    if path.as_os_str() == "-" {
+        std::io::stdin().lock().read_to_end(&mut data)?;
+    } else {
+        std::fs::File::open(path)?.read_to_end(&mut data)?;
+    }
+    Ok(split_command(data))
+}
+
+fn start_signal(arg: &ExecCommand) -> i32 {
+    match (arg.start_stopped, arg.start_signal) {
+        (true, _) => Signal::SIGSTOP as i32,
+        (false, Some(sig)) => sig as i32,
+        (false, None) => 0,
+    }
+}
+
+fn deathsig(arg: &ExecCommand) -> i32 {
+    if arg.no_deathsig {
+        0
+    } else {
+        arg.deathsig as i32
+    }
+}
+
+// zero disables it, same as not giving the option
+fn exec_timeout(arg: &ExecCommand) -> Option<Duration> {
+    arg.timeout
+        .filter(|timeout| *timeout > Duration::from_secs(0))
+}
+
+fn is_locale(name: &OsString) -> bool {
+    let name = name.as_bytes();
+    name == b"LANG" || name == b"TZ" || name.starts_with(b"LC_")
+}
+
+// passed as bytes, the program gets non-UTF8 variables unchanged
+fn preserved_env(
+    arg: &ExecCommand,
+    vars: impl Iterator<Item = (OsString, OsString)>,
+) -> Vec<(OsString, OsString)> {
+    if !arg.preserve_env && !arg.forward_locale {
+        return Vec::new();
+    }
+
+    vars.filter(|(k, _)| arg.preserve_env || is_locale(k))
+        .filter(|(k, _)| !arg.env_except.iter().any(|e| k == e.as_str()))
+        .collect()
+}
+
+/// `--env` goes after the preserved variables, so it wins over both the
+/// client's value and `--env-except`.
+fn exec_env<'a>(
+    preserved: &'a [(OsString, OsString)],
+    requested: Vec<(&'a [u8], &'a [u8])>,
+) -> Vec<(&'a [u8], &'a [u8])> {
+    preserved
+        .iter()
+        .map(|(k, v)| (k.as_bytes(), v.as_bytes()))
+        .chain(requested)
+        .collect()
+}
+
+fn streams(arg: &ExecCommand) -> messages::Files {
+    use crate::messages::Files;
+
+    let mut files = Files::IN | Files::OUT | Files::ERR;
+    files.set(Files::IN, !arg.no_stdin);
+    files.set(Files::OUT, !arg.no_stdout);
+    files.set(Files::ERR, !arg.no_stderr);
+    files
+}
+
+/// Own descriptor for each of the program's stdio, `--same-stdio 0,1,2`
+/// hands the stdin terminal to all three like an interactive shell has.
+fn shared_stdio(arg: &ExecCommand) -> std::result::Result<[i32; 3], String> {
+    use crate::messages::Files;
+
+    let mut stdio = [0, 1, 2];
+    let list = match arg.same_stdio {
+        Some(ref list) => list,
+        None => return Ok(stdio),
+    };
+    let mut shared: Vec<usize> = Vec::with_capacity(3);
+    for item in list.split(',') {
+        let fd = match item.trim().parse() {
+            Ok(fd) if fd < 3 => fd,
+            _ => return Err(format!("{:?} is not 0, 1 or 2", item)),
+        };
+        if shared.contains(&fd) {
+            return Err(format!("{} is listed twice", fd));
+        }
+        let stream = [Files::IN, Files::OUT, Files::ERR][fd];
+        if !streams(arg).contains(stream) {
+            return Err(format!("{} is not passed to program", fd));
+        }
+        shared.push(fd);
+    }
+    if shared.len() < 2 {
+        return Err("expected at least two descriptors".to_owned());
+    }
+    for fd in &shared[1..] {
+        stdio[*fd] = shared[0] as i32;
+    }
+    Ok(stdio)
+}
+
+type FdMap = (Vec<i32>, Vec<i32>);
+
+fn extra_fds(arg: &ExecCommand) -> std::result::Result<FdMap, String> {
+    let mut targets = Vec::with_capacity(arg.fd.len());
+    let mut sources = Vec::with_capacity(arg.fd.len());
+    for spec in &arg.fd {
+        let (target, source) = match spec.find('=') {
+            Some(pos) => (spec[..pos].parse(), spec[pos + 1..].parse()),
+            None => {
+                return Err(format!("expected CHILDFD=SOURCEFD: {}", spec))
+            }
+        };
+        let (target, source) = match (target, source) {
+            (Ok(target), Ok(source)) => (target, source),
+            _ => return Err(format!("invalid descriptor numbers: {}", spec)),
+        };
+        if target < 3 {
+            return Err(format!("{} is stdio, use redirection", target));
+        }
+        if targets.contains(&target) {
+            return Err(format!("{} is mapped twice", target));
+        }
+        if !system::is_valid_fd(source) {
+            return Err(format!("{} is not an open descriptor", source));
+        }
+        targets.push(target);
+        sources.push(source);
+    }
+    Ok((targets, sources))
+}
+
+// SIGXCPU is sent at the soft limit every second, the hard one a second
+// later gives programs handling it time to clean up before SIGKILL
+fn cpu_limit(arg: &ExecCommand) -> Option<messages::ResourceLimit> {
+    arg.cpu_limit.map(|secs| messages::ResourceLimit {
+        resource: messages::Resource::Cpu,
+        soft: Some(secs),
+        hard: Some(secs + 1),
+    })
+}
+
+fn connect_path(arg: &ExecCommand) -> Option<PathBuf> {
+    if !arg.connect.as_os_str().is_empty() {
+        return Some(arg.connect.clone());
+    }
+
+    std::env::var_os(SOCKET_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+fn command_exec(arg: &ExecCommand) -> Result<i32> {
+    if arg.program.is_empty() {
+        return Ok(0);
+    }
+
+    if arg.cpu_limit == Some(0) {
+        error!("--cpu-limit must be at least 1 second");
+        return Ok(2);
+    }
+
+    if !arg.chroot.is_empty() && arg.cwd_fd_inherit {
+        error!("--chroot cannot be used with --cwd-fd-inherit");
+        return Ok(2);
+    }
+
+    if arg.login && arg.setuid < 0 {
+        error!("--login requires --setuid");
+        return Ok(2);
+    }
+
+    if arg.local {
+        return command_exec_local(arg);
+    }
+
+    if arg.output_prefix.is_some() && arg.no_wait {
+        error!("--output-prefix cannot be used with --no-wait");
+        return Ok(2);
+    }
+
+    if arg.output_prefix.is_some() && arg.no_stdout {
+        error!("--output-prefix cannot be used with --no-stdout");
+        return Ok(2);
+    }
+
+    if arg.relay && arg.no_wait {
+        error!("--relay cannot be used with --no-wait");
+        return Ok(2);
+    }
+
+    if arg.relay && arg.output_prefix.is_some() {
+        error!("--relay cannot be used with --output-prefix");
+        return Ok(2);
+    }
+
+    if arg.pty_stdout && arg.no_stdout {
+        error!("--pty-stdout cannot be used with --no-stdout");
+        return Ok(2);
+    }
+
+    if arg.pty_stdout && arg.no_wait {
+        error!("--pty-stdout cannot be used with --no-wait");
+        return Ok(2);
+    }
+
+    if arg.pty_stdout && arg.output_prefix.is_some() {
+        error!("--pty-stdout cannot be used with --output-prefix");
+        return Ok(2);
+    }
+
+    if arg.pty_stdout && arg.same_stdio.is_some() {
+        error!("--pty-stdout cannot be used with --same-stdio");
+        return Ok(2);
+    }
+
+    if arg.ctty.is_some() && arg.new_session_keep_tty {
+        error!("--ctty cannot be used with --new-session-keep-tty");
+        return Ok(2);
+    }
+
+    // events share stdout with nothing but relayed output
+    if arg.json && !arg.relay && !arg.pty_stdout && !arg.no_stdout {
+        error!("--json requires --relay, --pty-stdout or --no-stdout");
+        return Ok(2);
+    }
+
+    if arg.json && arg.same_stdio.is_some() {
+        error!("--json cannot be used with --same-stdio");
+        return Ok(2);
+    }
+
+    if arg.json && arg.output_prefix.is_some() {
+        error!("--json cannot be used with --output-prefix");
+        return Ok(2);
+    }
+
+    if let Some(fd) = arg.status_fd {
+        if arg.no_wait {
+            error!("--status-fd cannot be used with --no-wait");
+            return Ok(2);
+        }
+        // closed once written, own stdio still carries the program's
+        if (0..=2).contains(&fd) {
+            error!("--status-fd must not be stdin, stdout or stderr");
+            return Ok(2);
+        }
+        if !system::is_valid_fd(fd) {
+            error!("--status-fd {} is not open", fd);
+            return Ok(2);
+        }
+    }
+
+    if arg.idle_timeout.is_some() && !arg.relay && !arg.pty_stdout {
+        error!("--idle-timeout requires --relay");
+        return Ok(2);
+    }
+
+    // same leadership the server checks before signalling a group
+    let leads_group = arg.setpgid == Some(0)
+        || arg.setsid
+        || arg.new_session_keep_tty
+        || arg.ctty.is_some();
+    if arg.group_signals && !leads_group {
+        error!("--group-signals requires --setpgid 0 or a new session");
+        return Ok(2);
+    }
+
+    if arg.start_stopped && arg.start_signal.is_some() {
+        error!("--start-stopped cannot be used with --start-signal");
+        return Ok(2);
+    }
+
+    if arg.same_stdio.is_some() && arg.relay {
+        error!("--same-stdio cannot be used with --relay");
+        return Ok(2);
+    }
+
+    if arg.same_stdio.is_some() && arg.output_prefix.is_some() {
+        error!("--same-stdio cannot be used with --output-prefix");
+        return Ok(2);
+    }
+
+    let stdio = match shared_stdio(arg) {
+        Ok(stdio) => stdio,
+        Err(err) => {
+            error!("--same-stdio {}", err);
+            return Ok(2);
+        }
+    };
+
+    let (fd_targets, fd_sources) = match extra_fds(arg) {
+        Ok(map) => map,
+        Err(err) => {
+            error!("--fd {}", err);
+            return Ok(2);
+        }
+    };
+
+    let requested: Vec<_> =
+        match arg.env.iter().map(|s| env_to_kv(s)).collect() {
+            Ok(envs) => envs,
+            Err(err) => {
+                error!("--env {}", err);
+                return Ok(2);
+            }
+        };
+
+    let connect = match (arg.connect_fd, connect_path(arg)) {
+        (Some(_), _) => PathBuf::new(),
+        (None, Some(path)) => path,
+        (None, None) => return command_exec_local(arg),
+    };
+
+    if fd_targets.len() > messages::MAX_EXTRA_FDS {
+        error!(
+            "--fd may be given at most {} times",
+            messages::MAX_EXTRA_FDS
+        );
+        return Ok(2);
+    }
+
+    system::disable_inherit_stdio()?;
+
+    // let program: &str = &arg.program[0];
+    let args: Vec<&[u8]> =
+        arg.program[1..].iter().map(|s| s.as_bytes()).collect();
+    let preserved = preserved_env(arg, std::env::vars_os());
+    let envs = exec_env(&preserved, requested);
+
+    let mut rlimits = if arg.inherit_rlimits {
+        system::INHERITED_LIMITS
+            .iter()
+            .map(|r| system::get_resource_limit(*r))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        Vec::new()
+    };
+    rlimits.extend_from_slice(&arg.limit);
+    rlimits.extend(cpu_limit(arg));
+
+    client::command(&client::Args {
+        program: arg.program[0].as_bytes(),
+        args: args.as_slice(),
+        env: envs.as_slice(),
+        cwd: &arg.workdir,
+        chroot: &arg.chroot,
+        connect: connect.as_path(),
+        connect_fd: arg.connect_fd,
+        uid: arg.setuid,
+        gid: arg.setgid,
+        groups: &arg.group,
+        umask: arg.umask,
+        deathsig: deathsig(arg),
+        start_signal: start_signal(arg),
+        detach: arg.detach,
+        no_wait: arg.no_wait,
+        setpgid: arg.setpgid,
+        setsid: arg.setsid,
+        keep_tty: arg.new_session_keep_tty,
+        ctty: arg.ctty.as_deref(),
+        notty: arg.notty,
+        no_core: arg.no_core,
+        login: arg.login,
+        assert_creds: arg.assert_creds,
+        cwd_fd: arg.cwd_fd_inherit,
+        output_prefix: arg.output_prefix.as_deref(),
+        streams: streams(arg),
+        stdio,
+        relay: arg.relay,
+        pty_stdout: arg.pty_stdout,
+        strict: arg.strict,
+        json: arg.json,
+        status_fd: arg.status_fd,
+        idle_timeout: arg.idle_timeout.map(Duration::from_secs),
+        timeout: exec_timeout(arg),
+        group_signals: arg.group_signals,
+        buffers: BufferSizes {
+            send: arg.socket_sndbuf,
+            recv: arg.socket_rcvbuf,
+        },
+        rlimits: &rlimits,
+        fd_targets: &fd_targets,
+        fd_sources: &fd_sources,
+    })
+}
+
+fn command_exec_local(arg: &ExecCommand) -> Result<i32> {
+    use crate::messages::{ProcessRequest, StartMode};
+
+    if arg.program.is_empty() {
+        return Ok(0);
+    }
+
+    if arg.output_prefix.is_some() {
+        error!("--output-prefix requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.relay {
+        error!("--relay requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.pty_stdout {
+        error!("--pty-stdout requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.json {
+        error!("--json requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.status_fd.is_some() {
+        error!("--status-fd requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.ctty.is_some() {
+        error!("--ctty requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.start_stopped || arg.start_signal.is_some() {
+        error!("--start-signal requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.group_signals {
+        error!("--group-signals requires a server connection");
+        return Ok(2);
+    }
+
+    if exec_timeout(arg).is_some() {
+        error!("--timeout requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.cwd_fd_inherit {
+        error!("--cwd-fd-inherit requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.assert_creds {
+        error!("--assert-creds requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.no_wait {
+        error!("--no-wait requires a server connection");
+        return Ok(2);
+    }
+
+    if arg.idle_timeout.is_some() {
+        error!("--idle-timeout requires a server connection");
+        return Ok(2);
+    }
+
+    let stdio = match shared_stdio(arg) {
+        Ok(stdio) => stdio,
+        Err(err) => {
+            error!("--same-stdio {}", err);
+            return Ok(2);
+        }
+    };
 
-    client::command(&client::Args {
-        program: &arg.program[0],
-        args: args.as_slice(),
-        env: envs.as_slice(),
-        cwd: &arg.workdir,
-        connect: arg.connect.as_path(),
-        uid: arg.setuid,
-        gid: arg.setgid,
-        deathsig: arg.deathsig as i32,
-        setpgid: arg.setpgid,
-        setsid: arg.setsid,
-        notty: arg.notty,
-    })
-}
+    let (mut fd_targets, mut fd_sources) = match extra_fds(arg) {
+        Ok(map) => map,
+        Err(err) => {
+            error!("--fd {}", err);
+            return Ok(2);
+        }
+    };
 
-fn command_exec_local(arg: &ExecCommand) -> Result<i32> {
-    use crate::messages::{Files, ProcessRequest, StartMode};
+    let envs: Vec<_> = match arg.env.iter().map(|s| env_to_kv(s)).collect() {
+        Ok(envs) => envs,
+        Err(err) => {
+            error!("--env {}", err);
+            return Ok(2);
+        }
+    };
 
-    if arg.program.is_empty() {
-        return Ok(0);
+    // stdio is inherited here, shared streams are mapped like --fd
+    for (target, source) in stdio.iter().enumerate() {
+        if *source != target as i32 {
+            fd_targets.push(target as i32);
+            fd_sources.push(*source);
+        }
     }
 
-    let args: Vec<&str> =
-        arg.program[1..].iter().map(|s| s.as_ref()).collect();
-    let envs: Vec<_> = arg.env.iter().map(|s| env_to_kv(&s)).collect();
+    let args: Vec<&[u8]> =
+        arg.program[1..].iter().map(|s| s.as_bytes()).collect();
 
     let mut startup = StartMode::empty();
     let pgid = match arg.setpgid {
@@ -233,27 +1446,77 @@ fn command_exec_local(arg: &ExecCommand) -> Result<i32> {
         startup |= StartMode::SESSION;
     }
 
+    if arg.new_session_keep_tty {
+        startup |= StartMode::SESSION | StartMode::CONTROLLING_TTY;
+    }
+
     if arg.notty {
         startup |= StartMode::DETACH_TERMINAL;
     }
 
+    if arg.no_core {
+        startup |= StartMode::NO_CORE;
+    }
+
+    if arg.login {
+        startup |= StartMode::LOGIN;
+    }
+
+    let rlimits: Vec<_> =
+        arg.limit.iter().cloned().chain(cpu_limit(arg)).collect();
     let req = ProcessRequest {
-        program: &arg.program[0],
+        program: arg.program[0].as_bytes(),
         argv: &args,
-        cwd: &arg.workdir,
+        cwd: arg.workdir.as_bytes(),
+        chroot: arg.chroot.as_bytes(),
         env: &envs,
-        startup: startup,
-        io: Files::all(),
-        pgid: pgid,
+        startup,
+        io: streams(arg),
+        pgid,
         uid: arg.setuid,
         gid: arg.setgid,
-        deathsig: arg.deathsig as i32,
+        groups: &arg.group,
+        umask: arg.umask,
+        deathsig: deathsig(arg),
+        rlimits: &rlimits,
+        extra_fds: &fd_targets,
     };
 
-    Err(child::execute_into(&req))
+    Err(child::execute_into(&req, &fd_sources))
 }
 
 fn command_start(arg: &StartCommand) -> i32 {
+    let launcher = system::Pid::parent();
+
+    if arg.temp && !arg.path.as_os_str().is_empty() {
+        error!("--temp takes no socket path");
+        return 2;
+    }
+
+    if arg.propagate_exit && arg.program.is_empty() {
+        error!("--propagate-exit requires a program to supervise");
+        return 2;
+    }
+
+    if arg.restart_on_signal.is_some() && arg.program.is_empty() {
+        error!("--restart-on-signal requires a program to supervise");
+        return 2;
+    }
+
+    if arg.heartbeat_log == Some(0) {
+        error!("--heartbeat-log must be at least 1 second");
+        return 2;
+    }
+
+    // these stop the server or are taken already
+    if let Some(sig) = arg.restart_on_signal {
+        use Signal::*;
+        if matches!(sig, SIGINT | SIGTERM | SIGCHLD | SIGKILL | SIGSTOP) {
+            error!("--restart-on-signal cannot be {}", sig);
+            return 2;
+        }
+    }
+
     if let Err(e) = system::disable_inherit_stdio() {
         error!("stdio CLOEXEC: {}", e);
         return 1;
@@ -281,16 +1544,31 @@ fn command_start(arg: &StartCommand) -> i32 {
         }
     }
 
-    if arg.path.as_os_str().is_empty() {
+    if arg.path.as_os_str().is_empty() && !arg.temp {
         return 0;
     }
 
-    if arg.parents {
+    // before anything is created, the inherited one may be anything
+    if let Some(mask) = arg.umask {
+        use nix::sys::stat::{umask, Mode};
+        umask(Mode::from_bits_truncate(mask));
+    }
+
+    if arg.parents && !raw::blocking::is_abstract(&arg.path) {
         if let Some(parent) = arg.path.parent() {
+            let existed = parent.exists();
             if let Err(e) = std::fs::create_dir_all(parent) {
                 error!("mkdir({:?}) {}", parent, e);
                 return 1;
             }
+            if let (false, Some(mode)) = (existed, arg.socket_dir_mode) {
+                use std::os::unix::fs::PermissionsExt;
+                let perm = std::fs::Permissions::from_mode(mode);
+                if let Err(e) = std::fs::set_permissions(parent, perm) {
+                    error!("chmod({:?}, {:o}) {}", parent, mode, e);
+                    return 1;
+                }
+            }
         }
     }
 
@@ -308,8 +1586,97 @@ fn command_start(arg: &StartCommand) -> i32 {
         }
     }
 
+    // armed last, changing credentials resets it
+    if let Some(sig) = arg.parent_death_signal {
+        if let Err(e) = system::bind_to_parent(launcher, sig) {
+            error!("parent death signal({}) {}", sig, e);
+            return 1;
+        }
+    }
+
+    let mut redact_env: Vec<String> = server::DEFAULT_REDACT_ENV
+        .iter()
+        .map(|s| (*s).to_owned())
+        .collect();
+    redact_env.extend(arg.redact_env.iter().cloned());
+
+    let defaults = policy::Settings {
+        exec_rate: arg.exec_rate,
+        exec_burst: arg.exec_burst.unwrap_or(arg.exec_rate),
+        exec_rate_per_uid: arg.exec_rate_per_uid,
+        forbid_root: arg.forbid_root,
+        limits: policy::Limits {
+            args: arg.max_args,
+            env: arg.max_env,
+            env_size: arg.max_env_size,
+        },
+    };
+    let settings = match arg.policy {
+        Some(ref path) => match policy::load(path, defaults) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!("policy({:?}) {}", path, e);
+                return 1;
+            }
+        },
+        None => defaults,
+    };
+
+    // created last, so nothing fails before the guard is set up
+    let temp_dir = if arg.temp {
+        match system::make_temp_dir(&std::env::temp_dir().join("sidecar.")) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                error!("mkdtemp() {}", e);
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
+    defer!({
+        if let Some(ref dir) = temp_dir {
+            if let Err(e) = std::fs::remove_dir(dir) {
+                error!("failed to remove directory {:?}: {}", dir, e);
+            }
+        }
+    });
+    let path = match temp_dir {
+        Some(ref dir) => dir.join("sidecar.sock"),
+        None => arg.path.clone(),
+    };
+
+    let argv: Vec<&[u8]> =
+        arg.program.iter().skip(1).map(|s| s.as_bytes()).collect();
+    let supervise = arg.program.first().map(|program| server::Supervise {
+        program: program.as_bytes(),
+        argv: &argv,
+        propagate_exit: arg.propagate_exit,
+        restart_signal: arg.restart_on_signal,
+        restart_stop_signal: arg.restart_stop_signal,
+    });
+
     match server::command(&server::Args {
-        server: arg.path.as_path(),
+        server: path.as_path(),
+        state: arg.state_file.as_deref(),
+        dump_jobs: arg.dump_jobs.as_deref(),
+        redact_env: &redact_env,
+        retention: Duration::from_secs(arg.status_retention),
+        stream: arg.stream,
+        socket_mode: arg.socket_mode,
+        buffers: BufferSizes {
+            send: arg.socket_sndbuf,
+            recv: arg.socket_rcvbuf,
+        },
+        defaults,
+        settings,
+        policy_file: arg.policy.as_deref(),
+        print_socket: arg.print_socket || arg.temp,
+        log_requests: arg.log_requests,
+        max_runtime: arg.max_runtime.map(Duration::from_secs),
+        heartbeat: arg.heartbeat_log.map(Duration::from_secs),
+        kill_grace: Duration::from_secs(arg.kill_grace),
+        supervise,
     }) {
         Ok(code) => code,
         Err(e) => {
@@ -319,74 +1686,155 @@ fn command_start(arg: &StartCommand) -> i32 {
     }
 }
 
+fn stop_timeout(secs: u64) -> Option<Duration> {
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
 fn command_stop(arg: &StopCommand) -> Result<i32> {
     if arg.path.as_os_str().is_empty() {
         return Ok(0);
     }
     stop::command(&stop::Args {
         connect: arg.path.as_path(),
+        timeout: stop_timeout(arg.timeout),
+        kill_grace: arg.kill_grace.map(Duration::from_secs),
+        drain: false,
     })
 }
 
-struct Logger {
-    own: Level,
-    others: Level,
+fn command_drain(arg: &DrainCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
+    }
+    stop::command(&stop::Args {
+        connect: arg.path.as_path(),
+        timeout: stop_timeout(arg.timeout),
+        kill_grace: None,
+        drain: true,
+    })
 }
 
-impl Log for Logger {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        if metadata.target().starts_with("sidecar") {
-            metadata.level() <= self.own
-        } else {
-            metadata.level() <= self.others
-        }
+fn command_reload(arg: &ReloadCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
     }
+    reload::command(&reload::Args {
+        connect: arg.path.as_path(),
+    })
+}
 
-    fn log(&self, record: &log::Record) {
-        if !self.enabled(record.metadata()) {
-            return;
-        }
-
-        let lch = match record.level() {
-            Level::Error => "[E ",
-            Level::Warn => "[W ",
-            Level::Info => "[I ",
-            Level::Debug => "[D ",
-            Level::Trace => "[T ",
-        };
+fn command_loglevel(arg: &LoglevelCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
+    }
+    loglevel::command(&loglevel::Args {
+        connect: arg.path.as_path(),
+        verbosity: arg.level,
+    })
+}
 
-        let lout = std::io::stderr();
-        let mut out = lout.lock();
-        writeln!(&mut out, "{}{}] {}", lch, record.target(), record.args())
-            .unwrap();
+/// Job picked by `--job` or `--pid`, logs why when not exactly one.
+fn job_selector(
+    job: Option<u64>,
+    pid: Option<i32>,
+) -> Option<messages::JobSelector> {
+    match (job, pid) {
+        (Some(id), None) => Some(messages::JobSelector::Id(id)),
+        (None, Some(pid)) => Some(messages::JobSelector::Pid(pid)),
+        _ => {
+            error!("exactly one of --job or --pid is required");
+            None
+        }
     }
+}
 
-    fn flush(&self) {
-        //
+fn command_status(arg: &StatusCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
     }
+    let selector = match job_selector(arg.job, arg.pid) {
+        Some(selector) => selector,
+        None => return Ok(2),
+    };
+    status::command(&status::Args {
+        connect: arg.path.as_path(),
+        selector,
+    })
 }
 
-fn configure_log(verbosity: u32) {
-    let filter: (Level, Level) = match verbosity {
-        0 => (Level::Warn, Level::Warn),
-        1 => (Level::Info, Level::Warn),
-        2 => (Level::Debug, Level::Info),
-        3 => (Level::Debug, Level::Debug),
-        _ => (Level::Trace, Level::Trace),
+fn command_kill(arg: &KillCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
+    }
+    let selector = match job_selector(arg.job, arg.pid) {
+        Some(selector) => selector,
+        None => return Ok(2),
     };
+    // a queued value reaches a single process only
+    if arg.value.is_some() && arg.group {
+        error!("--value cannot be used with --group");
+        return Ok(2);
+    }
+    kill::command(&kill::Args {
+        connect: arg.path.as_path(),
+        request: messages::KillRequest {
+            selector,
+            signal: arg.signal,
+            value: arg.value,
+            group: arg.group,
+        },
+    })
+}
 
-    let logger = Logger {
-        own: filter.0,
-        others: filter.1,
+fn command_attach(arg: &AttachCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
+    }
+    let selector = match job_selector(arg.job, arg.pid) {
+        Some(selector) => selector,
+        None => return Ok(2),
     };
-    log::set_boxed_logger(Box::new(logger)).unwrap();
-    log::set_max_level(filter.0.to_level_filter())
+    attach::command(&attach::Args {
+        connect: arg.path.as_path(),
+        selector,
+    })
+}
+
+fn command_info(arg: &InfoCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
+    }
+    info::command(&info::Args {
+        connect: arg.path.as_path(),
+        json: arg.json,
+    })
+}
+
+fn command_list(arg: &ListCommand) -> Result<i32> {
+    if arg.path.as_os_str().is_empty() {
+        return Ok(0);
+    }
+    list::command(&list::Args {
+        connect: arg.path.as_path(),
+    })
 }
 
 fn usage_line(dest: &mut impl Write, name: &str, command: &str) -> Result<()> {
     let line = match command {
-        "start" => "[OPTIONS] PATH",
+        "start" => "[OPTIONS] PATH [-- PROGRAM [ARG]...]",
         "stop" => "PATH",
+        "drain" => "PATH",
+        "reload" => "PATH",
+        "loglevel" => "PATH [LEVEL]",
+        "list" => "PATH",
+        "status" => "(--job ID | --pid PID) PATH",
+        "kill" => "[OPTIONS] (--job ID | --pid PID) PATH",
+        "attach" => "(--job ID | --pid PID) PATH",
+        "info" => "[--json] PATH",
+        "doctor" => "[OPTIONS]",
         "exec" => "[OPTIONS] [PROGRAM [ARG]...]",
         _ => "[OPTIONS] COMMAND",
     };
@@ -421,11 +1869,45 @@ fn help(dest: &mut impl Write, name: &str, cli: &Cli) -> Result<()> {
     }
 }
 
+type SplitArgs = (Vec<String>, Option<Vec<OsString>>);
+
+/// Options are parsed as strings, but a program after `--` may be
+/// any bytes, then it is returned apart from the rest.
+fn split_args(
+    args: impl Iterator<Item = OsString>,
+) -> std::result::Result<SplitArgs, OsString> {
+    let args: Vec<OsString> = args.collect();
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .map_or(args.len(), |pos| pos + 1);
+    let strings = |args: &[OsString]| {
+        args.iter()
+            .map(|arg| arg.clone().into_string())
+            .collect::<std::result::Result<Vec<_>, _>>()
+    };
+
+    let mut options = strings(&args[..end])?;
+    match strings(&args[end..]) {
+        Ok(program) => {
+            options.extend(program);
+            Ok((options, None))
+        }
+        Err(_) => Ok((options, Some(args[end..].to_vec()))),
+    }
+}
+
 fn run() -> i32 {
-    let args = std::env::args().collect::<Vec<_>>();
+    let (args, raw_program) = match split_args(std::env::args_os()) {
+        Ok(split) => split,
+        Err(arg) => {
+            eprintln!("{}: argument {:?} is not valid UTF-8", NAME, arg);
+            return 2;
+        }
+    };
     let arg0 = &args[0];
 
-    let cli = {
+    let mut cli = {
         match Cli::parse_args(&args[1..], ParsingStyle::default()) {
             Ok(val) => val,
             Err(err) => {
@@ -435,13 +1917,24 @@ fn run() -> i32 {
         }
     };
 
+    if let Some(program) = raw_program {
+        match cli.command {
+            Some(Command::Exec(ref mut arg)) => arg.program = program,
+            Some(Command::Start(ref mut arg)) => arg.program = program,
+            _ => {
+                eprintln!("{}: arguments after -- must be UTF-8", arg0);
+                return 2;
+            }
+        }
+    }
+
     if cli.version {
         println!("{}", VERSION);
         return 0;
     }
 
     if cli.help_requested() {
-        let _ = help(&mut std::io::stdout().lock(), &arg0, &cli);
+        let _ = help(&mut std::io::stdout().lock(), arg0, &cli);
         return 0;
     }
 
@@ -450,12 +1943,21 @@ fn run() -> i32 {
         Some(cmd) => match cmd {
             Command::Start(ref arg) => {
                 verbose += arg.verbose;
-                configure_log(verbose);
+                logger::configure(verbose);
+                if let Some(ref path) = arg.log_file {
+                    if let Err(err) = logger::open(path) {
+                        error!(
+                            "{}: failed to open log {:?}\n{}",
+                            arg0, path, err
+                        );
+                        return 1;
+                    }
+                }
                 command_start(arg)
             }
             Command::Stop(ref arg) => {
                 verbose += arg.verbose;
-                configure_log(verbose);
+                logger::configure(verbose);
                 match command_stop(arg) {
                     Ok(code) => code,
                     Err(err) => {
@@ -464,19 +1966,134 @@ fn run() -> i32 {
                     }
                 }
             }
-            Command::Exec(ref arg) => {
+            Command::Drain(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_drain(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to drain server\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Reload(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_reload(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to reload server\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Loglevel(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_loglevel(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to query log level\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::List(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_list(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to list jobs\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Status(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_status(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to query job\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Kill(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_kill(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to signal job\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Attach(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_attach(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to attach to job\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Info(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match command_info(arg) {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to query server\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Doctor(ref arg) => {
+                verbose += arg.verbose;
+                logger::configure(verbose);
+                match doctor::command() {
+                    Ok(code) => code,
+                    Err(err) => {
+                        error!("{}: failed to run checks\n{}", arg0, err);
+                        1
+                    }
+                }
+            }
+            Command::Exec(mut arg) => {
                 verbose += arg.verbose;
-                configure_log(verbose);
-                match command_exec(arg) {
+                logger::configure(verbose);
+                if let Some(ref path) = arg.command_file {
+                    if !arg.program.is_empty() {
+                        error!("--command-file cannot be used with program");
+                        return 2;
+                    }
+                    match read_command_file(path) {
+                        Ok(program) => arg.program = program,
+                        Err(err) => {
+                            error!(
+                                "{}: failed to read command file {:?}\n{}",
+                                arg0, path, err
+                            );
+                            return 1;
+                        }
+                    }
+                }
+                match command_exec(&arg) {
                     Ok(ret) => ret,
                     Err(err) => {
                         error!(
                             "{}: failed to execute command: \"{}\"\n{}",
                             arg0,
                             arg.program
-                                .get(0)
-                                .map(|s| s.as_str())
-                                .unwrap_or(""),
+                                .first()
+                                .map(|s| s.to_string_lossy())
+                                .unwrap_or_default(),
                             err
                         );
                         128
@@ -496,3 +2113,234 @@ fn run() -> i32 {
 fn main() {
     std::process::exit(run());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn os_args(args: &[&[u8]]) -> impl Iterator<Item = OsString> {
+        let args: Vec<OsString> = args
+            .iter()
+            .map(|arg| OsString::from_vec(arg.to_vec()))
+            .collect();
+        args.into_iter()
+    }
+
+    #[test]
+    fn env_to_kv_splits_at_first_equals() {
+        assert_eq!(env_to_kv("A=b=c"), Ok((&b"A"[..], &b"b=c"[..])));
+        assert_eq!(env_to_kv("A="), Ok((&b"A"[..], &b""[..])));
+    }
+
+    #[test]
+    fn env_to_kv_rejects_malformed() {
+        assert!(env_to_kv("A").is_err());
+        assert!(env_to_kv("=b").is_err());
+        assert!(env_to_kv("A=b\0c").is_err());
+    }
+
+    #[test]
+    fn env_overrides_env_except() {
+        let arg = ExecCommand::parse_args_default(&[
+            "--preserve-env",
+            "--env-except",
+            "FOO",
+            "--env",
+            "FOO=x",
+            "true",
+        ])
+        .unwrap();
+        let vars = vec![
+            (OsString::from("FOO"), OsString::from("client")),
+            (OsString::from("BAR"), OsString::from("b")),
+        ];
+        let preserved = preserved_env(&arg, vars.into_iter());
+        assert_eq!(preserved, [("BAR".into(), "b".into())]);
+
+        let requested = arg.env.iter().map(|s| env_to_kv(s));
+        let requested = requested.collect::<std::result::Result<_, _>>();
+        let envs = exec_env(&preserved, requested.unwrap());
+        assert_eq!(envs, [(&b"BAR"[..], &b"b"[..]), (b"FOO", b"x")]);
+    }
+
+    fn usage(command: &str) -> String {
+        let mut dest = Vec::new();
+        usage_line(&mut dest, "sidecar", command).unwrap();
+        String::from_utf8(dest).unwrap()
+    }
+
+    #[test]
+    fn usage_line_of_kill() {
+        assert_eq!(
+            usage("kill"),
+            "Usage: sidecar [OPTIONS] (--job ID | --pid PID) PATH\n"
+        );
+    }
+
+    #[test]
+    fn usage_line_of_attach() {
+        assert_eq!(
+            usage("attach"),
+            "Usage: sidecar (--job ID | --pid PID) PATH\n"
+        );
+    }
+
+    #[test]
+    fn duration_from_str_units() {
+        let secs = |text| duration_from_str(text).map(|d| d.as_secs());
+        assert_eq!(secs("30"), Ok(30));
+        assert_eq!(secs("30s"), Ok(30));
+        assert_eq!(secs("5m"), Ok(300));
+        assert_eq!(secs("2h"), Ok(7200));
+        assert_eq!(secs("1d"), Ok(86400));
+        assert_eq!(secs("0"), Ok(0));
+        assert!(duration_from_str("").is_err());
+        assert!(duration_from_str("s").is_err());
+        assert!(duration_from_str("1.5s").is_err());
+        assert!(duration_from_str("-1s").is_err());
+        assert!(duration_from_str("10w").is_err());
+        assert!(duration_from_str("18446744073709551615d").is_err());
+    }
+
+    #[test]
+    fn forward_locale_keeps_only_locale() {
+        assert!(is_locale(&"LANG".into()));
+        assert!(is_locale(&"LC_ALL".into()));
+        assert!(is_locale(&"TZ".into()));
+        assert!(!is_locale(&"LANGUAGE".into()));
+        assert!(!is_locale(&"HOME".into()));
+
+        let arg = ExecCommand::parse_args_default(&[
+            "--forward-locale",
+            "--env-except",
+            "LC_ALL",
+            "true",
+        ])
+        .unwrap();
+        let vars = vec![
+            (OsString::from("LANG"), OsString::from("C.UTF-8")),
+            (OsString::from("LC_ALL"), OsString::from("C")),
+            (OsString::from("HOME"), OsString::from("/root")),
+            (OsString::from("TZ"), OsString::from("UTC")),
+        ];
+        let preserved = preserved_env(&arg, vars.clone().into_iter());
+        assert_eq!(
+            preserved,
+            [
+                ("LANG".into(), "C.UTF-8".into()),
+                ("TZ".into(), "UTC".into())
+            ]
+        );
+
+        let arg = ExecCommand::parse_args_default(&["true"]).unwrap();
+        assert!(preserved_env(&arg, vars.into_iter()).is_empty());
+    }
+
+    fn stdio(args: &[&str]) -> std::result::Result<[i32; 3], String> {
+        let mut args = args.to_vec();
+        args.push("true");
+        shared_stdio(&ExecCommand::parse_args_default(&args).unwrap())
+    }
+
+    #[test]
+    fn shared_stdio_points_at_first() {
+        assert_eq!(stdio(&[]), Ok([0, 1, 2]));
+        assert_eq!(stdio(&["--same-stdio", "0,1,2"]), Ok([0, 0, 0]));
+        assert_eq!(stdio(&["--same-stdio", "2, 1"]), Ok([0, 2, 2]));
+    }
+
+    #[test]
+    fn shared_stdio_rejects_bad_lists() {
+        assert!(stdio(&["--same-stdio", "0"]).is_err());
+        assert!(stdio(&["--same-stdio", "0,3"]).is_err());
+        assert!(stdio(&["--same-stdio", "1,1"]).is_err());
+        assert!(stdio(&["--same-stdio", "0,x"]).is_err());
+        assert!(stdio(&["--no-stdin", "--same-stdio", "0,1"]).is_err());
+    }
+
+    #[test]
+    fn mode_from_str_bounds() {
+        assert_eq!(mode_from_str("7777"), Ok(0o7777));
+        assert_eq!(mode_from_str("022"), Ok(0o22));
+        assert!(mode_from_str("10000").is_err());
+        assert!(mode_from_str("8").is_err());
+    }
+
+    fn limit(text: &str) -> (messages::Resource, Option<u64>, Option<u64>) {
+        let limit = limit_from_str(text).unwrap();
+        (limit.resource, limit.soft, limit.hard)
+    }
+
+    #[test]
+    fn limit_from_str_soft_and_hard() {
+        use messages::Resource::*;
+
+        assert_eq!(limit("nofile=1024"), (NoFile, Some(1024), Some(1024)));
+        assert_eq!(limit("nofile=512:1024"), (NoFile, Some(512), Some(1024)));
+        assert_eq!(limit("core=0:unlimited"), (Core, Some(0), None));
+        assert_eq!(limit("stack=infinity"), (Stack, None, None));
+        assert_eq!(limit("cpu=5:5"), (Cpu, Some(5), Some(5)));
+    }
+
+    #[test]
+    fn limit_from_str_rejects() {
+        assert!(limit_from_str("nofile=2048:1024").is_err());
+        assert!(limit_from_str("nofile=unlimited:1024").is_err());
+        assert!(limit_from_str("nofile").is_err());
+        assert!(limit_from_str("nofile=").is_err());
+        assert!(limit_from_str("nofile=lots").is_err());
+        assert!(limit_from_str("bogus=1").is_err());
+    }
+
+    fn command(data: &[u8]) -> Vec<Vec<u8>> {
+        let args = split_command(data.to_vec());
+        args.into_iter().map(OsString::into_vec).collect()
+    }
+
+    #[test]
+    fn split_command_by_lines() {
+        assert_eq!(
+            command(b"echo\nhello world\n"),
+            [&b"echo"[..], b"hello world"]
+        );
+        assert_eq!(command(b"echo\n\nlast"), [&b"echo"[..], b"", b"last"]);
+    }
+
+    #[test]
+    fn split_command_by_nul_keeps_newlines() {
+        assert_eq!(command(b"printf\0a\nb\0"), [&b"printf"[..], b"a\nb"]);
+    }
+
+    #[test]
+    fn split_command_has_no_quoting() {
+        // quotes and escapes are argument bytes, unbalanced ones too
+        assert_eq!(
+            command(b"sh\n'a b'\n\"c\\\"d\n'open"),
+            [&b"sh"[..], b"'a b'", b"\"c\\\"d", b"'open"]
+        );
+    }
+
+    #[test]
+    fn split_command_empty() {
+        assert!(command(b"").is_empty());
+        assert!(command(b"\n").is_empty());
+        assert!(command(b"\0").is_empty());
+    }
+
+    #[test]
+    fn split_args_keeps_raw_program() {
+        let args = os_args(&[b"sidecar", b"exec", b"--", b"cat", b"\xff"]);
+        let (options, program) = split_args(args).unwrap();
+        assert_eq!(options, ["sidecar", "exec", "--"]);
+        let program = program.unwrap();
+        assert_eq!(program[0], "cat");
+        assert_eq!(program[1].as_bytes(), b"\xff");
+    }
+
+    #[test]
+    fn split_args_rejects_non_utf8_option() {
+        let args = os_args(&[b"sidecar", b"exec", b"--env", b"A=\xff"]);
+        assert_eq!(split_args(args).unwrap_err().as_bytes(), b"A=\xff");
+    }
+}
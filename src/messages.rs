@@ -1,10 +1,11 @@
 use crate::debug::bytes;
-use bincode;
+use bincode::Options;
 use bitflags::bitflags;
 use log::trace;
 use serde::de::DeserializeOwned;
 use serde::{self, Deserialize, Serialize};
 use std::io::{Error as IoError, ErrorKind, Write};
+use std::time::Duration;
 
 bitflags! {
     #[derive(Serialize, Deserialize)]
@@ -13,6 +14,10 @@ bitflags! {
         const SESSION = 2;
         const DETACH_TERMINAL = 4;
         const NOHUP = 8;
+        const NO_CORE = 16;
+        const CONTROLLING_TTY = 32;
+        // through the login shell of `uid` with its environment
+        const LOGIN = 64;
     }
 }
 
@@ -22,21 +27,54 @@ bitflags! {
         const IN = 1;
         const OUT = 2;
         const ERR = 4;
+        const CWD = 8;
+        // terminal to become controlling one, passed after CWD
+        const TTY = 16;
     }
 }
 
+// Resource numbers and RLIM_INFINITY differ between platforms, limits
+// travel by name with `None` for unlimited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Resource {
+    Core,
+    Cpu,
+    Data,
+    FileSize,
+    NoFile,
+    Stack,
+    AddressSpace,
+    NProc,
+    MemLock,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ResourceLimit {
+    pub resource: Resource,
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ProcessRequest<'a> {
-    pub program: &'a str,
-    pub argv: &'a [&'a str],
-    pub cwd: &'a str,
-    pub env: &'a [(&'a str, &'a str)],
+    pub program: &'a [u8],
+    pub argv: &'a [&'a [u8]],
+    pub cwd: &'a [u8],
+    // new root directory, empty for none, cwd is inside of it
+    pub chroot: &'a [u8],
+    pub env: &'a [(&'a [u8], &'a [u8])],
     pub startup: StartMode,
     pub io: Files,
     pub pgid: i32,
     pub uid: i32,
     pub gid: i32,
+    // supplementary groups, empty drops those of the server
+    pub groups: &'a [u32],
+    // file mode creation mask, the server's one is kept without it
+    pub umask: Option<u32>,
     pub deathsig: i32,
+    pub rlimits: &'a [ResourceLimit],
+    pub extra_fds: &'a [i32],
 }
 
 impl<'a> From<&ExecRequestInput<'a>> for ProcessRequest<'a> {
@@ -45,13 +83,18 @@ impl<'a> From<&ExecRequestInput<'a>> for ProcessRequest<'a> {
             program: o.program,
             argv: o.argv,
             cwd: o.cwd,
+            chroot: o.chroot,
             env: o.env,
             startup: o.startup,
             io: o.io,
             pgid: o.pgid,
             uid: o.uid,
             gid: o.gid,
+            groups: o.groups,
+            umask: o.umask,
             deathsig: o.deathsig,
+            rlimits: o.rlimits,
+            extra_fds: o.extra_fds,
         }
     }
 }
@@ -62,62 +105,140 @@ impl<'a> From<&'a ExecRequestOutput<'a>> for ProcessRequest<'a> {
             program: o.program,
             argv: o.argv.as_slice(),
             cwd: o.cwd,
+            chroot: o.chroot,
             env: o.env.as_slice(),
             startup: o.startup,
             io: o.io,
             pgid: o.pgid,
             uid: o.uid,
             gid: o.gid,
+            groups: o.groups.as_slice(),
+            umask: o.umask,
             deathsig: o.deathsig,
+            rlimits: o.rlimits.as_slice(),
+            extra_fds: o.extra_fds.as_slice(),
         }
     }
 }
 
+/// Descriptors `--fd` may pass besides stdio, workdir and terminal.
+pub const MAX_EXTRA_FDS: usize = 16;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExecHeader {
     pub body_size: usize,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum JobSelector {
+    Id(u64),
+    Pid(i32),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct KillRequest {
+    pub selector: JobSelector,
+    pub signal: i32,
+    pub value: Option<i32>,
+    // process group of the job, which must lead one
+    pub group: bool,
+}
+
 #[derive(Serialize)]
 pub enum RequestInput {
-    Stop,
+    // grace period before SIGKILL, server default if none
+    Stop(Option<Duration>),
     Exec(ExecHeader),
+    List,
+    JobStatus(JobSelector),
+    Info,
+    Drain,
+    Reload,
+    LogLevel(Option<u32>),
+    // answered with `Pong`, the connection takes another request after
+    Ping,
+    Kill(KillRequest),
+    // read-only subscription to relayed output of a job
+    Attach(JobSelector),
 }
 
 #[derive(Deserialize)]
 pub enum RequestOutput {
-    Stop,
+    // grace period before SIGKILL, server default if none
+    Stop(Option<Duration>),
     Exec(ExecHeader),
+    List,
+    JobStatus(JobSelector),
+    Info,
+    Drain,
+    Reload,
+    LogLevel(Option<u32>),
+    // answered with `Pong`, the connection takes another request after
+    Ping,
+    Kill(KillRequest),
+    // read-only subscription to relayed output of a job
+    Attach(JobSelector),
 }
 
 #[derive(Serialize, Clone)]
 pub struct ExecRequestInput<'a> {
-    pub program: &'a str,
-    pub argv: &'a [&'a str],
-    pub cwd: &'a str,
-    pub env: &'a [(&'a str, &'a str)],
+    pub program: &'a [u8],
+    pub argv: &'a [&'a [u8]],
+    pub cwd: &'a [u8],
+    pub chroot: &'a [u8],
+    pub env: &'a [(&'a [u8], &'a [u8])],
     pub startup: StartMode,
     pub io: Files,
+    pub relay: Files,
+    // relayed streams given a pseudo-terminal instead of a pipe
+    pub pty: Files,
     pub pgid: i32,
     pub uid: i32,
     pub gid: i32,
+    pub groups: &'a [u32],
+    pub umask: Option<u32>,
     pub deathsig: i32,
     pub connsig: i32,
+    // delivered right after spawn, 0 for none
+    pub start_signal: i32,
+    pub idle_timeout: Option<Duration>,
+    // enforced by the server only once the client is gone
+    pub timeout: Option<Duration>,
+    // every forwarded signal goes to the group the job leads
+    pub group_signals: bool,
+    pub credentials: bool,
+    pub rlimits: &'a [ResourceLimit],
+    // target numbers of descriptors passed after stdio and workdir
+    pub extra_fds: &'a [i32],
 }
 
 #[derive(Deserialize, Clone)]
 pub struct ExecRequestOutput<'a> {
-    pub program: &'a str,
-    pub argv: Vec<&'a str>,
-    pub cwd: &'a str,
-    pub env: Vec<(&'a str, &'a str)>,
+    pub program: &'a [u8],
+    pub argv: Vec<&'a [u8]>,
+    pub cwd: &'a [u8],
+    pub chroot: &'a [u8],
+    pub env: Vec<(&'a [u8], &'a [u8])>,
     pub startup: StartMode,
     pub io: Files,
+    pub relay: Files,
+    // relayed streams given a pseudo-terminal instead of a pipe
+    pub pty: Files,
     pub pgid: i32,
     pub uid: i32,
     pub gid: i32,
+    pub groups: Vec<u32>,
+    pub umask: Option<u32>,
     pub deathsig: i32,
     pub connsig: i32,
+    // delivered right after spawn, 0 for none
+    pub start_signal: i32,
+    pub idle_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub group_signals: bool,
+    pub credentials: bool,
+    pub rlimits: Vec<ResourceLimit>,
+    pub extra_fds: Vec<i32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -126,18 +247,177 @@ pub struct StartedProcess<'a> {
     pub message: &'a str,
     pub errno: i32,
     pub pid: i32,
+    pub job: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Signal {
+    // negative for the process group the job leads
+    pub signo: i32,
+    // queued along with the signal, realtime ones mostly
+    pub value: Option<i32>,
+}
+
+// Client terminal size for a job whose stdout is a pseudo-terminal
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct WinSize {
+    pub rows: u16,
+    pub cols: u16,
+    // pixels, mostly 0
+    pub x: u16,
+    pub y: u16,
+}
+
+// Connection traffic of a job with relayed stdio, in place of `Signal`
+// and the bare `ProcessResult`. Streams are named by their `Files` bit.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Relay<'a> {
+    Signal(Signal),
+    Data(Files, &'a [u8]),
+    Eof(Files),
+    Finished(ProcessResult),
+    WinSize(WinSize),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct AttachResult {
+    pub found: bool,
+    // whether its output passes through the server
+    pub captured: bool,
+}
+
+// Traffic of an attached connection after `AttachResult`, kept output
+// comes first. `Ended` tells how the job finished, `Running` when the
+// client fell behind and was dropped.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Attached<'a> {
+    Data(Files, &'a [u8]),
+    Ended(JobState),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobInfo {
+    pub id: u64,
+    pub pid: i32,
+    pub program: Vec<u8>,
+    pub argv: Vec<Vec<u8>>,
+    pub group_leader: bool,
+    pub session_leader: bool,
+    pub adopted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobList {
+    pub jobs: Vec<JobInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub version: String,
+    pub pid: i32,
+    pub uid: u32,
+    pub privileged: bool,
+    pub socket: String,
+    pub state_file: Option<String>,
+    pub log_file: Option<String>,
+    pub buffer_size: usize,
+    /// Effective SO_SNDBUF and SO_RCVBUF of a client connection.
+    pub socket_sndbuf: usize,
+    pub socket_rcvbuf: usize,
+    pub redact_env: Vec<String>,
+    pub status_retention: Duration,
+    pub transport: String,
+    pub exec_rate: u32,
+    pub exec_burst: u32,
+    pub max_args: usize,
+    pub max_env: usize,
+    pub max_env_size: usize,
+    pub forbid_root: bool,
+    pub policy_file: Option<String>,
+    /// Since the unix epoch.
+    pub started_at: Duration,
+    pub uptime: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReloadResult {
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pong {
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct KillResult {
+    pub found: bool,
+    // zero once the signal is delivered
+    pub errno: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LogLevelResult {
+    pub verbosity: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Exited(i32),
+    Signaled(i32),
+    Unknown,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Signal(pub i32);
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    pub max_rss: i64,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ProcessResult {
-    Undefined,
-    Exit(i32),
-    Signal(i32),
+pub struct ProcessResult {
+    pub exited: Option<i32>,
+    pub signaled: Option<i32>,
+    pub core_dumped: bool,
+    pub duration: Duration,
+    pub rusage: Option<ResourceUsage>,
+}
+
+impl ProcessResult {
+    pub fn exit_code(&self) -> Option<i32> {
+        match (self.exited, self.signaled) {
+            (Some(code), _) => Some(code),
+            (None, Some(sig)) => Some(128 + sig),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether the kernel ended the program for using more than `limit`
+    /// seconds of CPU, SIGXCPU comes at the soft limit, SIGKILL later.
+    pub fn exceeded_cpu_limit(&self, limit: u64) -> bool {
+        let used = self
+            .rusage
+            .as_ref()
+            .map_or(Duration::default(), |u| u.user_time + u.system_time);
+        match self.signaled {
+            Some(libc::SIGXCPU) => true,
+            Some(libc::SIGKILL) => used >= Duration::from_secs(limit),
+            _ => false,
+        }
+    }
+
+    pub fn state(&self) -> JobState {
+        match (self.exited, self.signaled) {
+            (Some(code), _) => JobState::Exited(code),
+            (None, Some(sig)) => JobState::Signaled(sig),
+            (None, None) => JobState::Unknown,
+        }
+    }
 }
 
+#[allow(clippy::boxed_local)]
 fn encoding_error(base: bincode::Error) -> IoError {
     use bincode::ErrorKind::*;
     match *base {
@@ -156,13 +436,25 @@ where
     result.map_err(encoding_error)
 }
 
+/// Same encoding as `bincode::deserialize`, but no length prefix may
+/// claim more bytes than the message holds.
+fn decoder(limit: usize) -> impl Options {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64)
+}
+
 pub fn decode_request<B, T>(data: B) -> Result<T, IoError>
 where
     B: AsRef<[u8]>,
     T: DeserializeOwned,
 {
     trace!("message decoding {:?}", bytes(&data));
-    bincode::deserialize_from(data.as_ref()).map_err(encoding_error)
+    let data = data.as_ref();
+    decoder(data.len())
+        .deserialize_from(data)
+        .map_err(encoding_error)
 }
 
 pub fn decode_request_ref<'de, T>(data: &'de [u8]) -> Result<T, IoError>
@@ -170,5 +462,62 @@ where
     T: Deserialize<'de>,
 {
     trace!("message decoding {:?}", bytes(&data));
-    bincode::deserialize(data).map_err(encoding_error)
+    decoder(data.len())
+        .deserialize(data)
+        .map_err(encoding_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(signaled: Option<i32>, cpu_secs: u64) -> ProcessResult {
+        ProcessResult {
+            exited: None,
+            signaled,
+            core_dumped: false,
+            duration: Duration::from_secs(cpu_secs),
+            rusage: Some(ResourceUsage {
+                user_time: Duration::from_secs(cpu_secs),
+                system_time: Duration::from_millis(500),
+                max_rss: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn exceeded_cpu_limit_by_signal() {
+        assert!(result(Some(libc::SIGXCPU), 0).exceeded_cpu_limit(10));
+        assert!(result(Some(libc::SIGKILL), 10).exceeded_cpu_limit(10));
+        assert!(!result(Some(libc::SIGKILL), 9).exceeded_cpu_limit(10));
+        assert!(!result(Some(libc::SIGTERM), 10).exceeded_cpu_limit(10));
+        assert!(!result(None, 10).exceeded_cpu_limit(10));
+    }
+
+    #[test]
+    fn exceeded_cpu_limit_without_rusage() {
+        let mut killed = result(Some(libc::SIGKILL), 10);
+        killed.rusage = None;
+        assert!(!killed.exceeded_cpu_limit(10));
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let mut data = Vec::new();
+        encode_request(&mut data, &vec![1u32, 2, 3]).unwrap();
+        let decoded: Vec<u32> = decode_request(&data).unwrap();
+        assert_eq!(decoded, [1, 2, 3]);
+    }
+
+    #[test]
+    fn decode_rejects_length_beyond_input() {
+        // claims 2^60 elements, followed by just one
+        let mut data = (1u64 << 60).to_le_bytes().to_vec();
+        data.extend_from_slice(&7u32.to_le_bytes());
+
+        assert!(decode_request::<_, Vec<u32>>(&data).is_err());
+        assert!(decode_request::<_, Vec<String>>(&data).is_err());
+        assert!(decode_request_ref::<Vec<&[u8]>>(&data).is_err());
+        assert!(decode_request_ref::<&[u8]>(&data).is_err());
+    }
 }
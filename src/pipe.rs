@@ -5,13 +5,13 @@ use std::task::{Context, Poll};
 use nix::unistd;
 
 use crate::raw::{self, Events, Fd, RawFd};
+use crate::tty;
 
 pub struct PipeRead {
     inner: Events,
 }
 
 impl PipeRead {
-    #[allow(dead_code)]
     pub fn read<'a, 'b>(&'a self, buf: &'b mut [u8]) -> raw::Read<'a, 'b> {
         raw::read(&self.inner, buf)
     }
@@ -30,31 +30,41 @@ pub struct PipeWrite {
 }
 
 impl PipeWrite {
-    #[allow(dead_code)]
     pub fn write<'a, 'b>(&'a self, buf: &'b [u8]) -> raw::Write<'a, 'b> {
         raw::write(&self.inner, buf)
     }
 }
 
+// Both ends are close-on-exec from the start, the server spawns
+// children while other descriptors are being set up.
 #[cfg(target_os = "linux")]
-fn make_pipe_fds() -> Result<(Fd, Fd)> {
+fn cloexec_pipe() -> Result<(Fd, Fd)> {
     use nix::fcntl::OFlag;
 
-    unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)
+    unistd::pipe2(OFlag::O_CLOEXEC)
         .map(|(r, w)| (Fd::new(r), Fd::new(w)))
         .map_err(raw::nixerror)
 }
 
+// Without pipe2 the flag is set right away, children are only forked
+// from the runtime thread which is busy here.
 #[cfg(not(target_os = "linux"))]
-fn make_pipe_fds() -> Result<(Fd, Fd)> {
+fn cloexec_pipe() -> Result<(Fd, Fd)> {
     let (r, w) = unistd::pipe().map_err(raw::nixerror)?;
     let rd = Fd::new(r);
     let wd = Fd::new(w);
-    raw::flags::set_cloexec_nonblocking(r)?;
-    raw::flags::set_cloexec_nonblocking(w)?;
+    raw::flags::set_cloexec(r)?;
+    raw::flags::set_cloexec(w)?;
     Ok((rd, wd))
 }
 
+fn make_pipe_fds() -> Result<(Fd, Fd)> {
+    let (r, w) = cloexec_pipe()?;
+    raw::flags::set_nonblock(r.raw())?;
+    raw::flags::set_nonblock(w.raw())?;
+    Ok((r, w))
+}
+
 pub fn make_pipe() -> Result<(PipeRead, PipeWrite)> {
     let (r, w) = make_pipe_fds()?;
 
@@ -80,3 +90,46 @@ impl AsRawFd for PipeWrite {
         self.inner.as_raw_fd()
     }
 }
+
+/// Pipe whose write end stays blocking, to be handed to a child as-is.
+pub fn make_output_pipe() -> Result<(PipeRead, Fd)> {
+    let (rd, wd) = cloexec_pipe()?;
+    raw::flags::set_nonblock(rd.raw())?;
+
+    let pread = PipeRead {
+        inner: Events::from_fd(rd)?,
+    };
+
+    Ok((pread, wd))
+}
+
+/// Pseudo-terminal standing in for an output pipe, the child finds a
+/// tty on the slave end and line-buffers its output. The last one is
+/// a duplicate of the master to resize it by, reading may be over.
+pub fn make_pty_output() -> Result<(PipeRead, Fd, Fd)> {
+    use nix::fcntl::{fcntl, FcntlArg};
+
+    let (master, slave) = tty::open_pty()?;
+    raw::flags::set_nonblock(master.raw())?;
+    let control = fcntl(master.raw(), FcntlArg::F_DUPFD_CLOEXEC(0))
+        .map(Fd::new)
+        .map_err(raw::nixerror)?;
+
+    let pread = PipeRead {
+        inner: Events::from_fd(master)?,
+    };
+
+    Ok((pread, slave, control))
+}
+
+/// Pipe whose read end stays blocking, to be handed to a child as-is.
+pub fn make_input_pipe() -> Result<(Fd, PipeWrite)> {
+    let (rd, wd) = cloexec_pipe()?;
+    raw::flags::set_nonblock(wd.raw())?;
+
+    let pwrite = PipeWrite {
+        inner: Events::from_fd(wd)?,
+    };
+
+    Ok((rd, pwrite))
+}
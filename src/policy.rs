@@ -0,0 +1,191 @@
+use std::io::{Error as IoError, ErrorKind, Result};
+use std::path::Path;
+
+use crate::ratelimit::RateLimiter;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    pub args: usize,
+    pub env: usize,
+    pub env_size: usize,
+}
+
+/// Server settings that `reload` may change.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Settings {
+    pub exec_rate: u32,
+    pub exec_burst: u32,
+    pub exec_rate_per_uid: bool,
+    pub forbid_root: bool,
+    pub limits: Limits,
+}
+
+pub(crate) struct Policy {
+    pub settings: Settings,
+    limiter: RateLimiter,
+}
+
+impl Policy {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            limiter: RateLimiter::new(settings.exec_rate, settings.exec_burst),
+            settings,
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.limiter.rate()
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.limiter.burst()
+    }
+
+    pub fn admit(&self, peer_uid: impl FnOnce() -> Result<u32>) -> Result<()> {
+        if !self.limiter.is_enabled() {
+            return Ok(());
+        }
+        let key = if self.settings.exec_rate_per_uid {
+            Some(peer_uid()?)
+        } else {
+            None
+        };
+        self.limiter.admit(key)
+    }
+}
+
+fn flag(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Reads `NAME = VALUE` lines named after `start` options on top of
+/// `defaults`, blank lines and `#` comments are skipped.
+pub(crate) fn load(path: &Path, defaults: Settings) -> Result<Settings> {
+    let text = std::fs::read_to_string(path)?;
+    parse(&text, path, defaults)
+}
+
+fn parse(text: &str, path: &Path, defaults: Settings) -> Result<Settings> {
+    let mut settings = defaults;
+    let mut rate = None;
+    let mut burst = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |message: String| {
+            IoError::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), number + 1, message),
+            )
+        };
+
+        let (name, value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+            None => return Err(invalid("expected NAME = VALUE".to_owned())),
+        };
+
+        let parsed = match name {
+            "exec-rate" => value.parse().ok().map(|v| rate = Some(v)),
+            "exec-burst" => value.parse().ok().map(|v| burst = Some(v)),
+            "exec-rate-per-uid" => {
+                flag(value).map(|v| settings.exec_rate_per_uid = v)
+            }
+            "forbid-root" => flag(value).map(|v| settings.forbid_root = v),
+            "max-args" => value.parse().ok().map(|v| settings.limits.args = v),
+            "max-env" => value.parse().ok().map(|v| settings.limits.env = v),
+            "max-env-size" => {
+                value.parse().ok().map(|v| settings.limits.env_size = v)
+            }
+            _ => return Err(invalid(format!("unknown setting {:?}", name))),
+        };
+        if parsed.is_none() {
+            return Err(invalid(format!(
+                "invalid value {:?} for {}",
+                value, name
+            )));
+        }
+    }
+
+    // burst follows the rate unless given, same as on the command line
+    if let Some(rate) = rate {
+        settings.exec_rate = rate;
+        settings.exec_burst = rate;
+    }
+    if let Some(burst) = burst {
+        settings.exec_burst = burst;
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: Settings = Settings {
+        exec_rate: 0,
+        exec_burst: 0,
+        exec_rate_per_uid: false,
+        forbid_root: false,
+        limits: Limits {
+            args: 4096,
+            env: 1024,
+            env_size: 1 << 20,
+        },
+    };
+
+    fn parse_text(text: &str) -> Result<Settings> {
+        parse(text, Path::new("policy"), DEFAULTS)
+    }
+
+    #[test]
+    fn empty_keeps_defaults() {
+        let settings = parse_text("\n  # nothing here\n\n").unwrap();
+        assert_eq!(settings.exec_rate, DEFAULTS.exec_rate);
+        assert_eq!(settings.limits.args, DEFAULTS.limits.args);
+        assert_eq!(settings.limits.env_size, DEFAULTS.limits.env_size);
+        assert!(!settings.forbid_root);
+    }
+
+    #[test]
+    fn settings_override_defaults() {
+        let text = "\
+            # comments and blanks between settings\n\
+            max-args = 16\n\
+            \n\
+            forbid-root=yes\n\
+            \texec-rate-per-uid = 1  \n";
+        let settings = parse_text(text).unwrap();
+        assert_eq!(settings.limits.args, 16);
+        assert_eq!(settings.limits.env, DEFAULTS.limits.env);
+        assert!(settings.forbid_root);
+        assert!(settings.exec_rate_per_uid);
+    }
+
+    #[test]
+    fn burst_follows_rate() {
+        let settings = parse_text("exec-rate = 5").unwrap();
+        assert_eq!((settings.exec_rate, settings.exec_burst), (5, 5));
+        let settings = parse_text("exec-burst = 20\nexec-rate = 5").unwrap();
+        assert_eq!((settings.exec_rate, settings.exec_burst), (5, 20));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let err = parse_text("max-env = 1\nmax-procs = 2").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("policy:2: unknown setting"));
+
+        let err = parse_text("forbid-root = maybe").unwrap_err();
+        assert!(err.to_string().starts_with("policy:1: invalid value"));
+        assert!(parse_text("max-args = -1").is_err());
+        assert!(parse_text("max-args").is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind, Result};
+use std::sync::Mutex;
+use std::time::Instant;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket refilled at `rate` tokens per second up to `burst`,
+/// one bucket per key.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<Option<u32>, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate as u32
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst as u32
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    pub fn admit(&self, key: Option<u32>) -> Result<()> {
+        self.admit_at(key, Instant::now())
+    }
+
+    fn admit_at(&self, key: Option<u32>, now: Instant) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        // a full bucket is the same as none, every peer uid seen once
+        // would stay in memory otherwise
+        let (rate, burst) = (self.rate, self.burst);
+        buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate < burst
+        });
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(IoError::new(ErrorKind::WouldBlock, "rate limited"));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn disabled_admits_everything() {
+        let limiter = RateLimiter::new(0, 0);
+        let now = Instant::now();
+        assert!((0..100).all(|_| limiter.admit_at(None, now).is_ok()));
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = RateLimiter::new(2, 3);
+        let now = Instant::now();
+        assert!((0..3).all(|_| limiter.admit_at(None, now).is_ok()));
+        let err = limiter.admit_at(None, now).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        // two tokens a second, one is back after half of it
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.admit_at(None, later).is_ok());
+        assert!(limiter.admit_at(None, later).is_err());
+    }
+
+    #[test]
+    fn keys_have_own_buckets() {
+        let limiter = RateLimiter::new(1, 1);
+        let now = Instant::now();
+        assert!(limiter.admit_at(Some(1000), now).is_ok());
+        assert!(limiter.admit_at(Some(1000), now).is_err());
+        assert!(limiter.admit_at(Some(1001), now).is_ok());
+    }
+
+    #[test]
+    fn full_buckets_are_evicted() {
+        let limiter = RateLimiter::new(10, 10);
+        let now = Instant::now();
+        for uid in 0..100 {
+            limiter.admit_at(Some(uid), now).unwrap();
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 100);
+
+        // a tenth of a second refills one token, all are full again
+        let later = now + Duration::from_millis(100);
+        limiter.admit_at(Some(0), later).unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.keys().collect::<Vec<_>>(), [&Some(0)]);
+    }
+}
@@ -24,6 +24,8 @@ fn _accept(fd: RawFd) -> Result<Option<Fd>, nix::Error> {
     )
 }
 
+// Without accept4 the flags are set right after, children are only
+// forked from the runtime thread which is busy accepting.
 #[cfg(not(target_os = "linux"))]
 fn _accept(fd: RawFd) -> Result<Option<Fd>, nix::Error> {
     use nix::sys::socket::accept;
@@ -40,7 +42,9 @@ impl<'a> Accept<'a> {
         use super::flags::{set_cloexec, set_nonblock};
         match self.events.poll_read_maybe(ctx, |fd| _accept(fd)) {
             Poll::Ready(val) => Poll::Ready(Some(val.and_then(|fd| {
-                set_cloexec(fd.raw()).and_then(|| set_nonblock(fd.raw()))
+                set_cloexec(fd.raw())?;
+                set_nonblock(fd.raw())?;
+                Ok(fd)
             }))),
             Poll::Pending => Poll::Pending,
         }
@@ -66,6 +70,6 @@ impl<'a> Stream for Accept<'a> {
     }
 }
 
-pub fn accept(events: &Events) -> Accept {
+pub fn accept(events: &Events) -> Accept<'_> {
     Accept::new(events)
 }
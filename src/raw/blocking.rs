@@ -1,50 +1,172 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
 use super::flags;
-use super::{nixerror, Fd};
+use super::{nixerror, Fd, RawFd};
 
 use nix::sys::socket::{self, AddressFamily, SockAddr, SockFlag, SockType};
 
+// Setup calls interrupted by a signal are safe to repeat
+fn retry<T>(mut call: impl FnMut() -> nix::Result<T>) -> Result<T> {
+    loop {
+        match call().map_err(nixerror) {
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+// Sockets must not leak into spawned programs. Without SOCK_CLOEXEC
+// the flag is set before returning, children are only forked from the
+// thread creating sockets.
 #[cfg(not(target_os = "linux"))]
-fn new() -> Result<Fd> {
-    let fd = socket::socket(
-        AddressFamily::Unix,
-        SockType::SeqPacket,
-        SockFlag::empty(),
-        None,
-    )
-    .map_err(nixerror)
+fn new(kind: SockType) -> Result<Fd> {
+    let fd = retry(|| {
+        socket::socket(AddressFamily::Unix, kind, SockFlag::empty(), None)
+    })
     .map(Fd::new)?;
     flags::set_cloexec(fd.raw())?;
     Ok(fd)
 }
 
 #[cfg(target_os = "linux")]
-fn new() -> Result<Fd> {
-    socket::socket(
-        AddressFamily::Unix,
-        SockType::SeqPacket,
-        SockFlag::SOCK_CLOEXEC,
-        None,
-    )
-    .map_err(nixerror)
+fn new(kind: SockType) -> Result<Fd> {
+    retry(|| {
+        socket::socket(AddressFamily::Unix, kind, SockFlag::SOCK_CLOEXEC, None)
+    })
     .map(Fd::new)
 }
 
-pub fn bind(path: &Path) -> Result<Fd> {
-    let addr = SockAddr::new_unix(path).map_err(nixerror)?;
-    let fd = new()?;
-    socket::bind(fd.raw(), &addr).map_err(nixerror)?;
-    socket::listen(fd.raw(), 0).map_err(nixerror)?;
+fn max_path_len() -> usize {
+    let addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_path.len()
+}
+
+/// `@name` is bound in the abstract namespace, no file stands for it.
+pub fn is_abstract(path: &Path) -> bool {
+    cfg!(target_os = "linux") && path.as_os_str().as_bytes().starts_with(b"@")
+}
+
+fn unix_addr(path: &Path) -> Result<SockAddr> {
+    let len = path.as_os_str().as_bytes().len();
+    let max = max_path_len();
+    if len > max {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "socket path too long: {} > {} bytes; use a shorter path",
+                len, max
+            ),
+        ));
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if is_abstract(path) {
+            // nix pads the name with NULs to the whole sun_path, other
+            // programs would not find it under the name they are given
+            let name = &path.as_os_str().as_bytes()[1..];
+            let addr =
+                socket::UnixAddr::new_abstract(name).map_err(nixerror)?;
+            return Ok(SockAddr::Unix(socket::UnixAddr(
+                addr.0,
+                name.len() + 1,
+            )));
+        }
+    }
+    SockAddr::new_unix(path).map_err(nixerror)
+}
+
+/// Binds and listens at `path`, the file gets `mode` before clients may
+/// connect, abstract names have no file and ignore it.
+pub fn bind(path: &Path, stream: bool, mode: Option<u32>) -> Result<Fd> {
+    let addr = unix_addr(path)?;
+    let fd = new(if stream {
+        SockType::Stream
+    } else {
+        SockType::SeqPacket
+    })?;
+    retry(|| socket::bind(fd.raw(), &addr)).map_err(|err| {
+        match err.kind() {
+            ErrorKind::AddrInUse => Error::new(
+                ErrorKind::AddrInUse,
+                format!("socket already in use: {}", path.display()),
+            ),
+            _ => err,
+        }
+    })?;
+    if let (Some(mode), false) = (mode, is_abstract(path)) {
+        use std::os::unix::fs::PermissionsExt;
+        let perm = std::fs::Permissions::from_mode(mode);
+        if let Err(err) = std::fs::set_permissions(path, perm) {
+            let _ = std::fs::remove_file(path);
+            return Err(err);
+        }
+    }
+    retry(|| socket::listen(fd.raw(), 0))?;
     flags::set_nonblock(fd.raw())?;
     Ok(fd)
 }
 
-pub fn connect(path: &Path) -> Result<Fd> {
-    let addr = SockAddr::new_unix(path).map_err(nixerror)?;
-    let fd = new()?;
-    socket::connect(fd.raw(), &addr).map_err(nixerror)?;
+fn connect_as(addr: &SockAddr, kind: SockType) -> Result<Fd> {
+    let fd = new(kind)?;
+    socket::connect(fd.raw(), addr).map_err(nixerror)?;
     flags::set_nonblock(fd.raw())?;
     Ok(fd)
 }
+
+/// Connects with SOCK_SEQPACKET and falls back to SOCK_STREAM when
+/// either the listener or the platform does not support it.
+pub fn connect(path: &Path) -> Result<Fd> {
+    let addr = unix_addr(path)?;
+    match connect_as(&addr, SockType::SeqPacket) {
+        Err(err)
+            if [
+                libc::EPROTOTYPE,
+                libc::EPROTONOSUPPORT,
+                libc::ESOCKTNOSUPPORT,
+            ]
+            .contains(&err.raw_os_error().unwrap_or(0)) =>
+        {
+            connect_as(&addr, SockType::Stream)
+        }
+        result => result,
+    }
+}
+
+pub fn socket_type(fd: RawFd) -> Result<libc::c_int> {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(kind),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+pub fn from_connected(fd: RawFd) -> Result<Fd> {
+    let kind = socket_type(fd)?;
+    let local = socket::getsockname(fd).map_err(nixerror)?;
+    match (kind, local) {
+        (libc::SOCK_SEQPACKET, SockAddr::Unix(_)) => {}
+        (libc::SOCK_STREAM, SockAddr::Unix(_)) => {}
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "not a unix seqpacket or stream socket",
+            ))
+        }
+    }
+    socket::getpeername(fd).map_err(nixerror)?;
+    flags::set_cloexec(fd)?;
+    flags::set_nonblock(fd)?;
+    Ok(Fd::new(fd))
+}
@@ -15,15 +15,16 @@ impl Fd {
     }
 
     pub fn into_raw(self) -> RawFd {
-        self.0
+        let fd = self.0;
+        std::mem::forget(self);
+        fd
     }
 
     #[allow(dead_code)]
     pub fn forget(self) {
-        //
+        std::mem::forget(self);
     }
 
-    #[allow(dead_code)]
     pub fn close(self) -> Result<()> {
         match unsafe { libc::close(self.into_raw()) } {
             0 => Ok(()),
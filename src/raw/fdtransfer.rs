@@ -5,63 +5,192 @@ use std::mem;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+use futures::ready;
 use libc::CMSG_SPACE;
 use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags};
 use nix::sys::uio::IoVec;
 
-use super::{Events, RawFd};
+use super::frame::Frame;
+use super::{Events, Fd, RawFd};
 
+// Received descriptors are kept close-on-exec, otherwise they would
+// leak into every process spawned by the server. Child streams do not
+// need the flag cleared: they are installed with dup2, which creates
+// descriptors without FD_CLOEXEC.
 #[cfg(target_os = "linux")]
 const RECV_FLAGS: MsgFlags = MsgFlags::MSG_CMSG_CLOEXEC;
 
 #[cfg(not(target_os = "linux"))]
 const RECV_FLAGS: MsgFlags = MsgFlags::empty();
 
+#[cfg(target_os = "linux")]
+fn set_received_flags(_fd: RawFd) {}
+
+#[cfg(not(target_os = "linux"))]
+fn set_received_flags(fd: RawFd) {
+    let _ = super::flags::set_cloexec(fd);
+}
+
+// SCM_CREDENTIALS is Linux-specific: the kernel checks that asserted
+// ids belong to the sender, the receiver must enable SO_PASSCRED.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Credentials {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
 fn cmsg_space(num_fds: usize) -> usize {
     let sz = (mem::size_of::<RawFd>() * num_fds).try_into().unwrap();
     let res = unsafe { CMSG_SPACE(sz) };
     res as usize
 }
 
-fn extract_fds(received: &socket::RecvMsg, dest: &mut [RawFd]) -> usize {
+#[cfg(target_os = "linux")]
+fn creds_space() -> usize {
+    let sz = mem::size_of::<libc::ucred>().try_into().unwrap();
+    let res = unsafe { CMSG_SPACE(sz) };
+    res as usize
+}
+
+#[cfg(not(target_os = "linux"))]
+fn creds_space() -> usize {
+    0
+}
+
+#[cfg(target_os = "linux")]
+fn sendmsg(
+    fd: RawFd,
+    iovec: &[IoVec<&[u8]>],
+    fds: &[RawFd],
+    creds: Option<&Credentials>,
+) -> nix::Result<usize> {
+    match creds {
+        Some(creds) => {
+            let ucred = libc::ucred {
+                pid: creds.pid,
+                uid: creds.uid,
+                gid: creds.gid,
+            };
+            let cmsg = [
+                ControlMessage::ScmRights(fds),
+                ControlMessage::ScmCredentials(&ucred),
+            ];
+            socket::sendmsg(fd, iovec, &cmsg, MsgFlags::empty(), None)
+        }
+        None => {
+            let cmsg = [ControlMessage::ScmRights(fds)];
+            socket::sendmsg(fd, iovec, &cmsg, MsgFlags::empty(), None)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendmsg(
+    fd: RawFd,
+    iovec: &[IoVec<&[u8]>],
+    fds: &[RawFd],
+    creds: Option<&Credentials>,
+) -> nix::Result<usize> {
+    if creds.is_some() {
+        return Err(nix::Error::Sys(nix::errno::Errno::EOPNOTSUPP));
+    }
+    let cmsg = [ControlMessage::ScmRights(fds)];
+    socket::sendmsg(fd, iovec, &cmsg, MsgFlags::empty(), None)
+}
+
+fn extract(
+    received: &socket::RecvMsg,
+    dest: &mut [RawFd],
+) -> (usize, Option<Credentials>) {
     let mut numfds = 0;
+    let mut creds = None;
     let max = dest.len();
 
-    'done: for r in received.cmsgs() {
-        if let ControlMessageOwned::ScmRights(ref fds) = r {
-            for fd in fds {
-                dest[numfds] = *fd;
-                numfds += 1;
-                if numfds == max {
-                    break 'done;
+    for r in received.cmsgs() {
+        match r {
+            ControlMessageOwned::ScmRights(ref fds) => {
+                for fd in fds {
+                    // the spare room for credentials may carry more
+                    // descriptors than requested, nobody would close them
+                    if numfds == max {
+                        Fd::new(*fd);
+                        continue;
+                    }
+                    set_received_flags(*fd);
+                    dest[numfds] = *fd;
+                    numfds += 1;
                 }
             }
+            #[cfg(target_os = "linux")]
+            ControlMessageOwned::ScmCredentials(ref ucred) => {
+                creds = Some(Credentials {
+                    pid: ucred.pid,
+                    uid: ucred.uid,
+                    gid: ucred.gid,
+                });
+            }
+            _ => {}
         }
     }
 
-    numfds
+    (numfds, creds)
 }
 
 pub struct SendFds<'a, 'b> {
     events: &'a Events,
     buf: &'b [u8],
-    cmsg: [ControlMessage<'b>; 1],
+    fds: &'b [RawFd],
+    creds: Option<Credentials>,
+    frame: Option<Frame>,
 }
 
 impl<'a, 'b> SendFds<'a, 'b> {
-    pub fn new(events: &'a Events, buf: &'b [u8], fds: &'b [RawFd]) -> Self {
+    pub fn new(
+        events: &'a Events,
+        buf: &'b [u8],
+        fds: &'b [RawFd],
+        creds: Option<Credentials>,
+        framed: bool,
+    ) -> Self {
         Self {
             events,
             buf,
-            cmsg: [ControlMessage::ScmRights(fds); 1],
+            fds,
+            creds,
+            frame: if framed {
+                Some(Frame::outgoing(buf.len()))
+            } else {
+                None
+            },
         }
     }
 
     pub fn do_poll(&mut self, ctx: &mut Context<'_>) -> Poll<Result<usize>> {
-        let iovec = [IoVec::from_slice(self.buf); 1];
-        self.events.poll_write(ctx, |fd| {
-            socket::sendmsg(fd, &iovec, &self.cmsg, MsgFlags::empty(), None)
-        })
+        let events = self.events;
+        let fds = self.fds;
+        let creds = self.creds.as_ref();
+        match self.frame {
+            // descriptors travel with the first byte of the frame
+            Some(ref mut frame) => {
+                frame.poll_send(ctx, self.buf, |ctx, iovec, first| {
+                    events.poll_write(ctx, |fd| match first {
+                        true => sendmsg(fd, iovec, fds, creds),
+                        false => socket::sendmsg(
+                            fd,
+                            iovec,
+                            &[],
+                            MsgFlags::empty(),
+                            None,
+                        ),
+                    })
+                })
+            }
+            None => {
+                let iovec = [IoVec::from_slice(self.buf); 1];
+                events.poll_write(ctx, |fd| sendmsg(fd, &iovec, fds, creds))
+            }
+        }
     }
 }
 
@@ -69,11 +198,14 @@ pub struct CmsgBuf<'a> {
     data: &'a mut [u8],
     fds: &'a mut [RawFd],
     inner: Vec<u8>,
+    creds: Option<Credentials>,
 }
 
-pub struct RecvFds<'a, 'b> {
+pub struct RecvFds<'a, 'b, 'c> {
     events: &'a Events,
-    buf: &'b mut CmsgBuf<'b>,
+    buf: &'b mut CmsgBuf<'c>,
+    frame: Option<Frame>,
+    numfds: usize,
 }
 
 impl<'a> CmsgBuf<'a> {
@@ -81,34 +213,88 @@ impl<'a> CmsgBuf<'a> {
         let len = fds.len();
         CmsgBuf {
             data: buf,
-            fds: fds,
-            inner: vec![0u8; cmsg_space(len)],
+            fds,
+            inner: vec![0u8; cmsg_space(len) + creds_space()],
+            creds: None,
         }
     }
+
+    pub fn credentials(&self) -> Option<Credentials> {
+        self.creds
+    }
 }
 
-impl<'a, 'b> RecvFds<'a, 'b> {
-    pub fn new(events: &'a Events, buf: &'b mut CmsgBuf<'b>) -> Self {
-        Self { events, buf }
+fn poll_recvmsg(
+    events: &Events,
+    ctx: &mut Context<'_>,
+    data: &mut [u8],
+    cmsg: &mut Vec<u8>,
+    fds: &mut [RawFd],
+    creds: &mut Option<Credentials>,
+) -> Poll<Result<(usize, usize)>> {
+    let iovec = [IoVec::from_mut_slice(data); 1];
+    match events.poll_read(ctx, |fd| {
+        socket::recvmsg(fd, &iovec, Some(cmsg), RECV_FLAGS)
+    }) {
+        Poll::Pending => Poll::Pending,
+        Poll::Ready(Ok(received)) => {
+            let numbytes = received.bytes;
+            let (numfds, received_creds) = extract(&received, fds);
+            if received_creds.is_some() {
+                *creds = received_creds;
+            }
+            Poll::Ready(Ok((numbytes, numfds)))
+        }
+        Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+    }
+}
+
+impl<'a, 'b, 'c> RecvFds<'a, 'b, 'c> {
+    pub fn new(
+        events: &'a Events,
+        buf: &'b mut CmsgBuf<'c>,
+        framed: bool,
+    ) -> Self {
+        Self {
+            events,
+            buf,
+            frame: if framed {
+                Some(Frame::incoming())
+            } else {
+                None
+            },
+            numfds: 0,
+        }
     }
 
     pub fn do_poll(
         &mut self,
         ctx: &mut Context<'_>,
     ) -> Poll<Result<(usize, usize)>> {
-        let iovec = [IoVec::from_mut_slice(self.buf.data); 1];
-        let cmsg = &mut self.buf.inner;
-        let fds = &mut self.buf.fds;
-        match self.events.poll_write(ctx, |fd| {
-            socket::recvmsg(fd, &iovec, Some(cmsg), RECV_FLAGS)
-        }) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Ok(received)) => {
-                let numbytes = received.bytes;
-                let numfds = extract_fds(&received, fds);
-                Poll::Ready(Ok((numbytes, numfds)))
+        let events = self.events;
+        let buf = &mut *self.buf;
+        let cmsg = &mut buf.inner;
+        let fds = &mut *buf.fds;
+        let creds = &mut buf.creds;
+        match self.frame {
+            Some(ref mut frame) => {
+                let numfds = &mut self.numfds;
+                let received = frame.poll_recv(ctx, buf.data, |ctx, dest| {
+                    let (bytes, nfds) = ready!(poll_recvmsg(
+                        events,
+                        ctx,
+                        dest,
+                        cmsg,
+                        &mut fds[*numfds..],
+                        creds
+                    ))?;
+                    *numfds += nfds;
+                    Poll::Ready(Ok(bytes))
+                });
+                let numbytes = ready!(received)?;
+                Poll::Ready(Ok((numbytes, self.numfds)))
             }
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            None => poll_recvmsg(events, ctx, buf.data, cmsg, fds, creds),
         }
     }
 }
@@ -124,7 +310,7 @@ impl<'a, 'b> Future for SendFds<'a, 'b> {
     }
 }
 
-impl<'a, 'b> Future for RecvFds<'a, 'b> {
+impl<'a, 'b, 'c> Future for RecvFds<'a, 'b, 'c> {
     type Output = Result<(usize, usize)>;
 
     fn poll(
@@ -139,13 +325,65 @@ pub fn sendfds<'a, 'b>(
     events: &'a Events,
     buf: &'b [u8],
     fds: &'b [RawFd],
+    creds: Option<Credentials>,
+    framed: bool,
 ) -> SendFds<'a, 'b> {
-    SendFds::new(events, buf, fds)
+    SendFds::new(events, buf, fds, creds, framed)
 }
 
-pub fn recvfds<'a, 'b>(
+pub fn recvfds<'a, 'b, 'c>(
     events: &'a Events,
-    buf: &'b mut CmsgBuf<'b>,
-) -> RecvFds<'a, 'b> {
-    RecvFds::new(events, buf)
+    buf: &'b mut CmsgBuf<'c>,
+    framed: bool,
+) -> RecvFds<'a, 'b, 'c> {
+    RecvFds::new(events, buf, framed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+
+    fn is_open(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+    }
+
+    #[test]
+    fn extract_closes_descriptors_that_do_not_fit() {
+        let (left, right) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        let (left, right) = (Fd::new(left), Fd::new(right));
+
+        let sent = [0, 1, 2];
+        let iovec = [IoVec::from_slice(b"x")];
+        sendmsg(left.raw(), &iovec, &sent, None).unwrap();
+
+        let mut data = [0u8; 1];
+        let mut cmsg = vec![0u8; cmsg_space(sent.len())];
+        let iovec = [IoVec::from_mut_slice(&mut data)];
+        let received =
+            socket::recvmsg(right.raw(), &iovec, Some(&mut cmsg), RECV_FLAGS)
+                .unwrap();
+        let passed: Vec<RawFd> = received
+            .cmsgs()
+            .flat_map(|cmsg| match cmsg {
+                ControlMessageOwned::ScmRights(fds) => fds,
+                _ => Vec::new(),
+            })
+            .collect();
+        assert_eq!(passed.len(), sent.len());
+
+        let mut dest = [-1; 1];
+        let (numfds, creds) = extract(&received, &mut dest);
+        assert_eq!((numfds, creds), (1, None));
+        assert_eq!(dest[0], passed[0]);
+        assert!(is_open(passed[0]));
+        assert!(passed[1..].iter().all(|fd| !is_open(*fd)));
+        Fd::new(passed[0]);
+    }
 }
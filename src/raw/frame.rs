@@ -0,0 +1,110 @@
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+use std::task::{Context, Poll};
+
+use futures::ready;
+use nix::sys::uio::IoVec;
+
+// Stream sockets do not keep message boundaries, so every message is
+// preceded by its length as 4 bytes in network order.
+pub const HEADER_SIZE: usize = 4;
+
+pub struct Frame {
+    header: [u8; HEADER_SIZE],
+    done: usize,
+}
+
+impl Frame {
+    pub fn incoming() -> Self {
+        Self {
+            header: [0; HEADER_SIZE],
+            done: 0,
+        }
+    }
+
+    pub fn outgoing(len: usize) -> Self {
+        let len = u32::try_from(len).expect("message does not fit a frame");
+        Self {
+            header: len.to_be_bytes(),
+            done: 0,
+        }
+    }
+
+    fn length(&self) -> usize {
+        u32::from_be_bytes(self.header) as usize
+    }
+
+    /// Receives one whole message into `buf`, `recv` is polled for
+    /// every chunk until the header and the payload are complete.
+    pub fn poll_recv<F>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        buf: &mut [u8],
+        mut recv: F,
+    ) -> Poll<Result<usize>>
+    where
+        F: FnMut(&mut Context<'_>, &mut [u8]) -> Poll<Result<usize>>,
+    {
+        loop {
+            let dest = if self.done < HEADER_SIZE {
+                &mut self.header[self.done..]
+            } else {
+                let len = self.length();
+                if len > buf.len() {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "message too large: {} > {} bytes",
+                            len,
+                            buf.len()
+                        ),
+                    )));
+                }
+                let offset = self.done - HEADER_SIZE;
+                if offset == len {
+                    return Poll::Ready(Ok(len));
+                }
+                &mut buf[offset..len]
+            };
+
+            match ready!(recv(ctx, dest))? {
+                0 if self.done == 0 => return Poll::Ready(Ok(0)),
+                0 => return Poll::Ready(Err(ErrorKind::UnexpectedEof.into())),
+                received => self.done += received,
+            }
+        }
+    }
+
+    /// Sends the header and the whole of `buf`, `send` receives `true`
+    /// only for the first chunk, which may carry ancillary data.
+    pub fn poll_send<F>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        buf: &[u8],
+        mut send: F,
+    ) -> Poll<Result<usize>>
+    where
+        F: FnMut(
+            &mut Context<'_>,
+            &[IoVec<&[u8]>],
+            bool,
+        ) -> Poll<Result<usize>>,
+    {
+        loop {
+            let sent = if self.done < HEADER_SIZE {
+                let iovec = [
+                    IoVec::from_slice(&self.header[self.done..]),
+                    IoVec::from_slice(buf),
+                ];
+                ready!(send(ctx, &iovec, self.done == 0))?
+            } else if self.done - HEADER_SIZE < buf.len() {
+                let iovec =
+                    [IoVec::from_slice(&buf[self.done - HEADER_SIZE..])];
+                ready!(send(ctx, &iovec, false))?
+            } else {
+                return Poll::Ready(Ok(buf.len()));
+            };
+            self.done += sent;
+        }
+    }
+}
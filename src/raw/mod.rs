@@ -4,16 +4,17 @@ mod events;
 mod fd;
 mod fdtransfer;
 pub mod flags;
+mod frame;
 mod ops;
 mod reactor;
 
-pub use nix::sys::uio::IoVec;
-pub use std::io::Result;
 pub use std::os::unix::io::RawFd;
 
 pub use accept::{accept, Accept};
 pub use fd::Fd;
-pub use fdtransfer::{recvfds, sendfds, CmsgBuf, RecvFds, SendFds};
+pub use fdtransfer::{
+    recvfds, sendfds, CmsgBuf, Credentials, RecvFds, SendFds,
+};
 pub use ops::{read, recv, send, write, Read, Recv, Send, Write};
 pub use reactor::Events;
 
@@ -7,6 +7,7 @@ use nix::sys::socket;
 pub use nix::sys::socket::MsgFlags;
 use nix::unistd;
 
+use super::frame::Frame;
 use super::reactor::Events;
 
 pub struct Read<'a, 'b> {
@@ -44,6 +45,7 @@ pub struct Recv<'a, 'b> {
     events: &'a Events,
     buf: &'b mut [u8],
     flags: MsgFlags,
+    frame: Option<Frame>,
 }
 
 impl<'a, 'b> Recv<'a, 'b> {
@@ -51,13 +53,33 @@ impl<'a, 'b> Recv<'a, 'b> {
         events: &'a Events,
         buf: &'b mut [u8],
         flags: MsgFlags,
+        framed: bool,
     ) -> Self {
-        Self { events, buf, flags }
+        Self {
+            events,
+            buf,
+            flags,
+            frame: if framed {
+                Some(Frame::incoming())
+            } else {
+                None
+            },
+        }
     }
 
     pub fn do_poll(&mut self, ctx: &mut Context<'_>) -> Poll<Result<usize>> {
-        self.events
-            .poll_read(ctx, |fd| socket::recv(fd, self.buf, self.flags))
+        let events = self.events;
+        let flags = self.flags;
+        match self.frame {
+            Some(ref mut frame) => {
+                frame.poll_recv(ctx, self.buf, |ctx, dest| {
+                    events.poll_read(ctx, |fd| socket::recv(fd, dest, flags))
+                })
+            }
+            None => {
+                events.poll_read(ctx, |fd| socket::recv(fd, self.buf, flags))
+            }
+        }
     }
 }
 
@@ -65,16 +87,43 @@ pub struct Send<'a, 'b> {
     events: &'a Events,
     buf: &'b [u8],
     flags: MsgFlags,
+    frame: Option<Frame>,
 }
 
 impl<'a, 'b> Send<'a, 'b> {
-    pub fn new(events: &'a Events, buf: &'b [u8], flags: MsgFlags) -> Self {
-        Self { events, buf, flags }
+    pub fn new(
+        events: &'a Events,
+        buf: &'b [u8],
+        flags: MsgFlags,
+        framed: bool,
+    ) -> Self {
+        Self {
+            events,
+            buf,
+            flags,
+            frame: if framed {
+                Some(Frame::outgoing(buf.len()))
+            } else {
+                None
+            },
+        }
     }
 
     pub fn do_poll(&mut self, ctx: &mut Context<'_>) -> Poll<Result<usize>> {
-        self.events
-            .poll_write(ctx, |fd| socket::send(fd, self.buf, self.flags))
+        let events = self.events;
+        let flags = self.flags;
+        match self.frame {
+            Some(ref mut frame) => {
+                frame.poll_send(ctx, self.buf, |ctx, iovec, _first| {
+                    events.poll_write(ctx, |fd| {
+                        socket::sendmsg(fd, iovec, &[], flags, None)
+                    })
+                })
+            }
+            None => {
+                events.poll_write(ctx, |fd| socket::send(fd, self.buf, flags))
+            }
+        }
     }
 }
 
@@ -134,14 +183,16 @@ pub fn recv<'a, 'b>(
     events: &'a Events,
     buf: &'b mut [u8],
     flags: MsgFlags,
+    framed: bool,
 ) -> Recv<'a, 'b> {
-    Recv::new(events, buf, flags)
+    Recv::new(events, buf, flags, framed)
 }
 
 pub fn send<'a, 'b>(
     events: &'a Events,
     buf: &'b [u8],
     flags: MsgFlags,
+    framed: bool,
 ) -> Send<'a, 'b> {
-    Send::new(events, buf, flags)
+    Send::new(events, buf, flags, framed)
 }
@@ -1,5 +1,5 @@
 use std::io::Result;
-pub use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+pub use std::os::unix::io::{AsRawFd, RawFd};
 use std::task::{Context, Poll};
 
 use futures::ready;
@@ -29,6 +29,12 @@ impl Events {
         })
     }
 
+    /// Deregisters from the reactor and closes the descriptor now
+    /// instead of whenever the reactor lets it go.
+    pub fn close(self) -> Result<()> {
+        self.io.into_inner()?.close()
+    }
+
     pub fn poll_read_ready(
         &self,
         ctx: &mut Context<'_>,
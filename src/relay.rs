@@ -0,0 +1,360 @@
+use std::io::{ErrorKind, Read, Result, Write};
+use std::time::Duration;
+
+use futures::{
+    channel::mpsc,
+    executor::block_on,
+    future::{select, try_join, Either},
+    pin_mut,
+    sink::SinkExt,
+    stream::StreamExt,
+};
+use log::{debug, warn};
+
+use crate::capture;
+use crate::child_watcher::{Child, Exit};
+use crate::messages::{self as msg, Files};
+use crate::pipe::{self, PipeRead, PipeWrite};
+use crate::raw::{Fd, RawFd};
+use crate::runtime;
+use crate::socket::Socket;
+
+// Leaves room for message framing within the 4096 byte receive buffers
+const CHUNK_SIZE: usize = 2048;
+
+const OUTPUT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) struct Prefixer<'a> {
+    prefix: &'a [u8],
+    line_start: bool,
+}
+
+impl<'a> Prefixer<'a> {
+    pub fn new(prefix: &'a str) -> Self {
+        Self {
+            prefix: prefix.as_bytes(),
+            line_start: true,
+        }
+    }
+
+    /// Writes `data` prepending prefix only at true line starts,
+    /// so lines split across reads are tagged once.
+    pub fn write(&mut self, dest: &mut impl Write, data: &[u8]) -> Result<()> {
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            if self.line_start {
+                dest.write_all(self.prefix)?;
+            }
+            dest.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn prefix_output(
+    input: PipeRead,
+    prefix: String,
+) -> Result<()> {
+    let mut prefixer = Prefixer::new(&prefix);
+    let mut buffer = vec![0; 4096];
+
+    loop {
+        let received = input.read(&mut buffer).await?;
+        if received == 0 {
+            debug!("output relay finished");
+            return Ok(());
+        }
+
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        prefixer.write(&mut out, &buffer[..received])?;
+        out.flush()?;
+    }
+}
+
+/// Server ends of the pipes standing in for relayed streams.
+pub(crate) struct Pipes {
+    stdin: Option<PipeWrite>,
+    stdout: Option<PipeRead>,
+    stderr: Option<PipeRead>,
+    pty: Option<Fd>,
+}
+
+impl Pipes {
+    /// Master of the pseudo-terminal given as stdout, if any.
+    pub fn take_pty(&mut self) -> Option<Fd> {
+        self.pty.take()
+    }
+}
+
+/// Creates pipes for `relay` streams the client did not pass and
+/// returns descriptors for the child in the order of the new `io` bits.
+pub(crate) fn open_pipes(
+    io: Files,
+    relay: Files,
+    pty: Files,
+    fds: &[RawFd],
+) -> Result<(Option<Pipes>, Files, Vec<RawFd>)> {
+    let relay = relay & (Files::IN | Files::OUT | Files::ERR) & !io;
+    if relay.is_empty() {
+        return Ok((None, io, fds.to_vec()));
+    }
+
+    let mut pipes = Pipes {
+        stdin: None,
+        stdout: None,
+        stderr: None,
+        pty: None,
+    };
+    let mut passed = fds.iter().cloned();
+    let mut childfds: Vec<Option<Fd>> = Vec::with_capacity(fds.len() + 3);
+
+    if io.contains(Files::IN) {
+        childfds.push(None);
+    } else if relay.contains(Files::IN) {
+        let (r, w) = pipe::make_input_pipe()?;
+        pipes.stdin = Some(w);
+        childfds.push(Some(r));
+    }
+    if io.contains(Files::OUT) {
+        childfds.push(None);
+    } else if relay.contains(Files::OUT) {
+        let (r, w) = if pty.contains(Files::OUT) {
+            let (r, w, control) = pipe::make_pty_output()?;
+            pipes.pty = Some(control);
+            (r, w)
+        } else {
+            pipe::make_output_pipe()?
+        };
+        pipes.stdout = Some(r);
+        childfds.push(Some(w));
+    }
+    if io.contains(Files::ERR) {
+        childfds.push(None);
+    } else if relay.contains(Files::ERR) {
+        let (r, w) = pipe::make_output_pipe()?;
+        pipes.stderr = Some(r);
+        childfds.push(Some(w));
+    }
+    if io.contains(Files::CWD) {
+        childfds.push(None);
+    }
+    if io.contains(Files::TTY) {
+        childfds.push(None);
+    }
+
+    // nothing is taken from `fds` until every pipe exists
+    let mut childfds: Vec<RawFd> = childfds
+        .into_iter()
+        .filter_map(|fd| match fd {
+            Some(fd) => Some(fd.into_raw()),
+            None => passed.next(),
+        })
+        .collect();
+    // extra descriptors follow
+    childfds.extend(passed);
+    Ok((Some(pipes), io | relay, childfds))
+}
+
+async fn read_output(
+    pipe: Option<PipeRead>,
+    stream: Files,
+    mut chunks: mpsc::Sender<(Files, Vec<u8>)>,
+) -> Result<()> {
+    let pipe = match pipe {
+        Some(pipe) => pipe,
+        None => return Ok(()),
+    };
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        let received = match pipe.read(&mut buffer).await {
+            // pty master once the slave side is closed
+            Err(ref err) if err.raw_os_error() == Some(libc::EIO) => 0,
+            received => received?,
+        };
+        let chunk = buffer[..received].to_vec();
+        // closed receiver means the client is gone
+        if chunks.send((stream, chunk)).await.is_err() || received == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Silence on every output stream for `timeout` calls `expired`, once.
+pub(crate) struct Watchdog<F> {
+    pub timeout: Duration,
+    pub expired: F,
+}
+
+// Output keeps being read once the client is gone, so that the program
+// does not block and attached clients still get it.
+async fn send_output(
+    sock: &Socket,
+    job: u64,
+    mut chunks: mpsc::Receiver<(Files, Vec<u8>)>,
+    mut watchdog: Option<Watchdog<impl FnOnce()>>,
+) -> Result<()> {
+    let mut sendbuf = Vec::new();
+    let mut connected = true;
+    loop {
+        let chunk = match watchdog.as_ref().map(|w| w.timeout) {
+            Some(timeout) => {
+                match runtime::timeout(timeout, chunks.next()).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        (watchdog.take().unwrap().expired)();
+                        continue;
+                    }
+                }
+            }
+            None => chunks.next().await,
+        };
+        let (stream, data) = match chunk {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        let message = if data.is_empty() {
+            msg::Relay::Eof(stream)
+        } else {
+            capture::publish(job, stream, &data);
+            msg::Relay::Data(stream, &data)
+        };
+        if !connected {
+            continue;
+        }
+        sendbuf.clear();
+        msg::encode_request(&mut sendbuf, &message)?;
+        if let Err(err) = sock.send(&sendbuf).await {
+            debug!("output not sent, client is gone: {}", err);
+            connected = false;
+        }
+    }
+    Ok(())
+}
+
+// Single sender keeps frames of both streams from interleaving
+async fn forward_output(
+    sock: &Socket,
+    job: u64,
+    stdout: Option<PipeRead>,
+    stderr: Option<PipeRead>,
+    watchdog: Option<Watchdog<impl FnOnce()>>,
+) -> Result<()> {
+    let (chunks, received) = mpsc::channel(1);
+    let readers = try_join(
+        read_output(stdout, Files::OUT, chunks.clone()),
+        read_output(stderr, Files::ERR, chunks),
+    );
+    try_join(readers, send_output(sock, job, received, watchdog))
+        .await
+        .map(drop)
+}
+
+async fn forward_input(
+    stdin: Option<PipeWrite>,
+    mut chunks: mpsc::Receiver<Vec<u8>>,
+) -> Result<()> {
+    let stdin = match stdin {
+        Some(stdin) => stdin,
+        None => return Ok(()),
+    };
+
+    while let Some(chunk) = chunks.next().await {
+        let mut data = &chunk[..];
+        while !data.is_empty() {
+            let written = stdin.write(data).await?;
+            data = &data[written..];
+        }
+    }
+    debug!("input relay finished");
+    Ok(())
+}
+
+/// Waits for `child` while relaying its stdio over `sock`, output is
+/// published to attached clients of `job` as well. Output held open by
+/// descendants is abandoned shortly after it exits.
+pub(crate) async fn serve(
+    child: Child,
+    job: u64,
+    sock: &Socket,
+    pipes: Pipes,
+    input: mpsc::Receiver<Vec<u8>>,
+    watchdog: Option<Watchdog<impl FnOnce()>>,
+) -> Result<Exit> {
+    let output =
+        forward_output(sock, job, pipes.stdout, pipes.stderr, watchdog);
+    let input = forward_input(pipes.stdin, input);
+    pin_mut!(output, input);
+
+    let waited = async move {
+        match select(child, input).await {
+            Either::Left((status, _input)) => status,
+            Either::Right((forwarded, child)) => {
+                if let Err(err) = forwarded {
+                    debug!("input relay error: {}", err);
+                }
+                child.await
+            }
+        }
+    };
+    pin_mut!(waited);
+
+    let (forwarded, status) = match select(waited, output).await {
+        Either::Left((status, output)) => {
+            match runtime::timeout(OUTPUT_TIMEOUT, output).await {
+                Ok(forwarded) => (forwarded, status),
+                Err(_) => {
+                    debug!("output still open after {:?}", OUTPUT_TIMEOUT);
+                    (Ok(()), status)
+                }
+            }
+        }
+        Either::Right((forwarded, waited)) => (forwarded, waited.await),
+    };
+    if let Err(err) = forwarded {
+        warn!("output relay error: {}", err);
+    }
+    status
+}
+
+/// Reads stdin on its own thread, it may be a terminal shared with
+/// other processes and cannot be switched to non-blocking mode.
+pub(crate) fn read_stdin() -> mpsc::Receiver<Vec<u8>> {
+    let (mut chunks, received) = mpsc::channel(1);
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let received = match stdin.lock().read(&mut buffer) {
+                Ok(0) => return,
+                Ok(received) => received,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(err) => {
+                    debug!("stdin relay error: {}", err);
+                    return;
+                }
+            };
+            if block_on(chunks.send(buffer[..received].to_vec())).is_err() {
+                return;
+            }
+        }
+    });
+    received
+}
+
+pub(crate) fn write_output(stream: Files, data: &[u8]) -> Result<()> {
+    if stream == Files::ERR {
+        let stderr = std::io::stderr();
+        let mut out = stderr.lock();
+        out.write_all(data)?;
+        out.flush()
+    } else {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        out.write_all(data)?;
+        out.flush()
+    }
+}
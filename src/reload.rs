@@ -0,0 +1,36 @@
+use std::io::Result;
+use std::path::Path;
+
+use log::{debug, error};
+
+use crate::channel::{connect_failed, MessageChannel};
+use crate::messages as msg;
+use crate::raw::blocking::connect;
+use crate::runtime;
+use crate::socket::Socket;
+
+pub(crate) struct Args<'a> {
+    pub connect: &'a Path,
+}
+
+async fn execute(socket: Socket) -> Result<msg::ReloadResult> {
+    MessageChannel::new(socket)
+        .request(&msg::RequestInput::Reload)
+        .await
+}
+
+pub(crate) fn command(args: &Args) -> Result<i32> {
+    debug!("connecting to {:?}", args.connect);
+    match connect(args.connect) {
+        Ok(fd) => runtime::new()?.block_on(async {
+            match execute(Socket::from_fd(fd)?).await?.error {
+                Some(err) => {
+                    error!("server kept previous policy\n{}", err);
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        }),
+        Err(err) => Ok(connect_failed(args.connect, &err)),
+    }
+}
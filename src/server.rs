@@ -1,135 +1,451 @@
-use std::io::Result;
-use std::path::Path;
-use std::process::ExitStatus;
+use std::any::Any;
+use std::cell::Cell;
+use std::io::{Error as IoError, ErrorKind, Result, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use futures::{
-    future::{select, Either, FutureExt},
+    channel::{mpsc, oneshot},
+    future::{self, select, Either, FusedFuture, Future, FutureExt},
     pin_mut,
+    sink::SinkExt,
     stream::StreamExt,
 };
 
-use log::{debug, error, info, warn};
+use log::{debug, error, info, trace, warn};
 use scopeguard::defer;
 use tokio::signal::unix::{signal, SignalKind};
 
-use crate::child::setup_command;
-use crate::child_watcher::{self, Child};
+use crate::capture;
+use crate::child::{self, setup_command};
+use crate::child_watcher::{self, Child, Exit};
+use crate::debug;
+use crate::jobs;
+use crate::logger;
 use crate::messages as msg;
-use crate::raw::{blocking::bind, flags::set_cloexec, CmsgBuf, RawFd};
+use crate::policy::{self, Limits, Policy, Settings};
+use crate::raw::{
+    self,
+    blocking::{bind, is_abstract},
+    flags::set_cloexec,
+    CmsgBuf, Fd, RawFd,
+};
+use crate::relay;
 use crate::runtime;
-use crate::socket::{Shutdown, Socket};
+use crate::socket::{BufferSizes, Shutdown, Socket};
 use crate::system::{self, kill, killpg, Pid, Signal};
+use crate::tty;
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const RESULT_TIMEOUT: Duration = Duration::from_secs(5);
 
-fn pass_signal(pid: Pid, mut sigval: i32, pg_leader: bool) {
+// With `all_to_group` every signal goes to the group the job leads,
+// not just those the client marks negative. Queued values reach the
+// job process only either way.
+fn pass_signal(
+    conn: u64,
+    pid: Pid,
+    signal: msg::Signal,
+    pg_leader: bool,
+    all_to_group: bool,
+) {
+    let mut sigval = signal.signo;
     let send_to_group = if sigval < 0 {
         sigval = -sigval;
         true
     } else {
-        false
+        all_to_group
     };
 
+    if signal.value.is_some() || system::is_realtime(sigval) {
+        let name = system::signal_name(sigval);
+        let group = send_to_group && pg_leader && signal.value.is_none();
+        info!(
+            "conn={} process={} received {}signal={} value={:?}",
+            conn,
+            pid,
+            if group { "group " } else { "" },
+            name,
+            signal.value
+        );
+        if let Err(err) = system::send_signal(pid, sigval, signal.value, group)
+        {
+            warn!(
+                "conn={} process={} failed to send signal={}: {}",
+                conn, pid, name, err
+            );
+        }
+        return;
+    }
+
     match Signal::from_c_int(sigval) {
         Ok(sig) => {
             if send_to_group && pg_leader {
-                info!("process={} received group signal={}", pid, sig);
+                info!(
+                    "conn={} process={} received group signal={}",
+                    conn, pid, sig
+                );
                 killpg(pid, sig);
             } else {
-                info!("process={} received signal={}", pid, sig);
+                info!("conn={} process={} received signal={}", conn, pid, sig);
                 kill(pid, sig);
             }
         }
         Err(_) => {
             warn!(
-                "process={} received invalid signal value {:?}",
-                pid, sigval
+                "conn={} process={} received invalid signal value {:?}",
+                conn, pid, sigval
             );
         }
     }
 }
 
-fn child_finished(pid: Pid, status: ExitStatus) -> msg::ProcessResult {
-    match status.code() {
-        Some(code) => {
-            info!("process={} exited code={:?}", pid, code);
-            msg::ProcessResult::Exit(code as i32)
+fn child_finished(
+    conn: u64,
+    pid: Pid,
+    exit: Exit,
+    duration: Duration,
+) -> msg::ProcessResult {
+    use std::os::unix::process::ExitStatusExt;
+    let result = msg::ProcessResult {
+        exited: exit.status.code(),
+        signaled: exit.status.signal(),
+        core_dumped: exit.core_dumped,
+        duration,
+        rusage: Some(msg::ResourceUsage {
+            user_time: exit.user_time,
+            system_time: exit.system_time,
+            max_rss: exit.max_rss,
+        }),
+    };
+    match (result.exited, result.signaled) {
+        (Some(code), _) => {
+            info!("conn={} process={} exited code={:?}", conn, pid, code);
         }
-        None => {
-            use std::os::unix::process::ExitStatusExt;
-            match status.signal() {
-                Some(sig) => {
-                    info!("process={} exited signal={:?}", pid, sig);
-                    msg::ProcessResult::Signal(sig)
-                }
-                None => {
-                    warn!("process={} exited without reason", pid);
-                    msg::ProcessResult::Undefined
-                }
+        (None, Some(sig)) => {
+            info!(
+                "conn={} process={} exited signal={:?} core_dumped={}",
+                conn, pid, sig, result.core_dumped
+            );
+        }
+        (None, None) => {
+            warn!("conn={} process={} exited without reason", conn, pid);
+        }
+    }
+    result
+}
+
+fn terminate(pid: Pid, sig: Signal, leader: jobs::Leader) {
+    if leader.session && sig == Signal::SIGKILL {
+        system::kill_session(pid, sig);
+    } else if leader.group {
+        system::killpg(pid, sig);
+    } else {
+        system::kill(pid, sig);
+    }
+}
+
+/// Sends `sig` to `targets` and SIGKILL to those still there once
+/// `gone` takes longer than `grace`, then waits for `gone` to finish.
+async fn escalate<F>(
+    targets: &[(Pid, jobs::Leader)],
+    sig: Signal,
+    grace: Duration,
+    mut gone: F,
+) -> F::Output
+where
+    F: Future + Unpin,
+{
+    for (pid, leader) in targets {
+        terminate(*pid, sig, *leader);
+    }
+    if sig == Signal::SIGKILL {
+        return gone.await;
+    }
+    match runtime::timeout(grace, &mut gone).await {
+        Ok(output) => output,
+        Err(_) => {
+            for (pid, leader) in targets {
+                warn!(
+                    "process={} still running after {:?} sending SIGKILL",
+                    pid, grace
+                );
+                terminate(*pid, Signal::SIGKILL, *leader);
             }
+            gone.await
+        }
+    }
+}
+
+/// Waits for a job its client left, --timeout is the client's job while
+/// it is connected.
+async fn reap_detached<F>(
+    conn: u64,
+    pid: Pid,
+    params: &ChildParams,
+    started: Instant,
+    mut child: F,
+) -> Result<Exit>
+where
+    F: Future<Output = Result<Exit>> + Unpin,
+{
+    let timeout = match params.timeout {
+        Some(timeout) => timeout,
+        None => return child.await,
+    };
+    let remaining = timeout.checked_sub(started.elapsed()).unwrap_or_default();
+    match runtime::timeout(remaining, &mut child).await {
+        Ok(exit) => exit,
+        Err(_) => {
+            warn!(
+                "conn={} process={} timed out after {:?} sending SIGTERM",
+                conn, pid, timeout
+            );
+            let targets = [(pid, params.leader)];
+            escalate(&targets, Signal::SIGTERM, params.kill_grace, child).await
         }
     }
 }
 
 async fn handle_child(
+    conn: u64,
+    job: u64,
     sock: Socket,
-    mut child: Child,
+    child: Child,
     mut buffer: Vec<u8>,
-    killsig: system::Signal,
-    process_group_leader: bool,
-) -> Result<()> {
+    params: ChildParams,
+    mut pipes: Option<relay::Pipes>,
+) -> Result<Option<msg::ProcessResult>> {
+    let killsig = params.connsig;
+    let leader = params.leader;
     let mut sendbuf = Vec::with_capacity(16);
+    let pid = system::Pid::from_raw(child.id());
+    let started = child.started();
+    let relayed = pipes.is_some();
+    let pty = pipes.as_mut().and_then(|pipes| pipes.take_pty());
+    // one chunk in flight, the client is not read while the program
+    // takes its time with stdin
+    let (input, chunks) = mpsc::channel(1);
+    let mut input = Some(input);
+    let mut child = match pipes {
+        Some(pipes) => {
+            buffer.resize(BUFFER_SIZE, 0);
+            let watchdog =
+                params.idle_timeout.map(|timeout| relay::Watchdog {
+                    timeout,
+                    expired: move || {
+                        warn!(
+                            "conn={} process={} no output for {:?} \
+                             sending SIGKILL",
+                            conn, pid, timeout
+                        );
+                        terminate(pid, system::SIGKILL, leader);
+                    },
+                });
+            relay::serve(child, job, &sock, pipes, chunks, watchdog).boxed()
+        }
+        None => child.boxed(),
+    };
     let mut signal = sock.recv(&mut buffer);
-    let pid = system::Pid::from_raw(child.id() as i32);
+    let finished = |status: Result<Exit>| {
+        status
+            .ok()
+            .map(|status| child_finished(conn, pid, status, started.elapsed()))
+    };
+    let transition = |state: &str| {
+        trace!("conn={} process={} -> {}", conn, pid, state);
+    };
 
-    loop {
+    let result = loop {
+        transition("waiting");
         let selected = select(child, signal).await;
         let (nchild, nsignal) = match selected {
             Either::Left((Err(waiterror), _signal)) => {
-                warn!("process={} wait error={:?}", pid, waiterror);
+                warn!(
+                    "conn={} process={} wait error={:?}",
+                    conn, pid, waiterror
+                );
                 return Err(waiterror);
             }
             Either::Left((Ok(exitstatus), _signal)) => {
-                let response = child_finished(pid, exitstatus);
+                transition("reaped");
+                let response =
+                    child_finished(conn, pid, exitstatus, started.elapsed());
                 if let Err(err) = sock.shutdown(Shutdown::Read) {
                     warn!(
-                        "process={} failed to shutdown read: {:?}",
-                        pid, err
+                        "conn={} process={} failed to shutdown read: {:?}",
+                        conn, pid, err
                     );
                 };
-                msg::encode_request(&mut sendbuf, &response)?;
-                sock.send(&sendbuf).await?;
-                break;
+                if relayed {
+                    let finished = msg::Relay::Finished(response.clone());
+                    msg::encode_request(&mut sendbuf, &finished)?;
+                } else {
+                    msg::encode_request(&mut sendbuf, &response)?;
+                }
+                transition("sending result");
+                match runtime::timeout(RESULT_TIMEOUT, sock.send(&sendbuf))
+                    .await
+                {
+                    Ok(sent) => {
+                        sent?;
+                        transition("result sent");
+                    }
+                    Err(_) => warn!(
+                        "conn={} process={} client did not take result \
+                         in {:?} dropping connection",
+                        conn, pid, RESULT_TIMEOUT
+                    ),
+                }
+                break Some(response);
             }
             Either::Right((received, child1)) => match received {
-                Err(err) => {
-                    warn!(
-                        "process={} client error={:?} sending SIGKILL",
-                        pid, err
-                    );
-                    if process_group_leader {
-                        system::killpg(pid, system::SIGKILL);
-                    } else {
-                        system::kill(pid, system::SIGKILL);
+                Err(err) => match killsig {
+                    Some(sig) => {
+                        warn!(
+                            "conn={} process={} client error={:?} \
+                             sending signal={}",
+                            conn, pid, err, sig
+                        );
+                        transition("killed, reaping");
+                        let targets = [(pid, leader)];
+                        let grace = params.kill_grace;
+                        let reaped = escalate(&targets, sig, grace, child1);
+                        break finished(reaped.await);
                     }
-                    let _ = child1.await;
-                    break;
-                }
+                    None => {
+                        warn!(
+                            "conn={} process={} client error={:?} \
+                             process detached",
+                            conn, pid, err
+                        );
+                        transition("detached, reaping");
+                        let reaped =
+                            reap_detached(conn, pid, &params, started, child1);
+                        break finished(reaped.await);
+                    }
+                },
                 Ok(0) => {
-                    warn!(
-                        "process={} client disconnected sending signal={}",
-                        pid, killsig
-                    );
-                    if process_group_leader {
-                        system::killpg(pid, killsig);
-                    } else {
-                        system::kill(pid, killsig);
+                    match killsig {
+                        Some(sig) => {
+                            warn!(
+                                "conn={} process={} client disconnected \
+                                 sending signal={}",
+                                conn, pid, sig
+                            );
+                            transition("disconnected, reaping");
+                            let targets = [(pid, leader)];
+                            let grace = params.kill_grace;
+                            let reaped =
+                                escalate(&targets, sig, grace, child1);
+                            break finished(reaped.await);
+                        }
+                        None => {
+                            info!(
+                                "conn={} process={} client disconnected \
+                                 process detached",
+                                conn, pid
+                            );
+                        }
                     }
-                    let _ = child1.await;
-                    break;
+                    transition("disconnected, reaping");
+                    let reaped =
+                        reap_detached(conn, pid, &params, started, child1);
+                    break finished(reaped.await);
+                }
+                Ok(size) if relayed => {
+                    let req: msg::Relay =
+                        { msg::decode_request_ref(&buffer[..size])? };
+                    let mut pending = None;
+                    match req {
+                        msg::Relay::Signal(sig) => {
+                            transition("forwarding signal");
+                            pass_signal(
+                                conn,
+                                pid,
+                                sig,
+                                leader.group,
+                                params.group_signals,
+                            )
+                        }
+                        msg::Relay::Data(msg::Files::IN, data) => {
+                            trace!(
+                                "conn={} process={} stdin {} bytes",
+                                conn,
+                                pid,
+                                data.len()
+                            );
+                            pending = Some(data.to_vec());
+                        }
+                        // the kernel signals only a controlling terminal's
+                        // group on resize, stdout is none
+                        msg::Relay::WinSize(size) => match pty {
+                            Some(ref pty) => {
+                                transition("resizing terminal");
+                                match tty::set_window_size(pty.raw(), &size) {
+                                    Ok(()) => pass_signal(
+                                        conn,
+                                        pid,
+                                        msg::Signal {
+                                            signo: -(Signal::SIGWINCH as i32),
+                                            value: None,
+                                        },
+                                        leader.group,
+                                        params.group_signals,
+                                    ),
+                                    Err(err) => warn!(
+                                        "conn={} process={} failed to \
+                                         resize terminal: {}",
+                                        conn, pid, err
+                                    ),
+                                }
+                            }
+                            None => trace!(
+                                "conn={} process={} has no terminal to resize",
+                                conn,
+                                pid
+                            ),
+                        },
+                        // closes program stdin
+                        msg::Relay::Eof(msg::Files::IN) => {
+                            transition("closing stdin");
+                            input = None
+                        }
+                        other => trace!(
+                            "conn={} process={} ignored {:?}",
+                            conn,
+                            pid,
+                            other
+                        ),
+                    }
+                    // the next message waits for room, a program ending
+                    // meanwhile is picked up by the next select
+                    let child1 = match (pending, input.as_mut()) {
+                        (Some(chunk), Some(input)) => {
+                            match select(child1, input.send(chunk)).await {
+                                Either::Left((exit, _)) => {
+                                    future::ready(exit).boxed()
+                                }
+                                Either::Right((_, child1)) => child1,
+                            }
+                        }
+                        _ => child1,
+                    };
+                    (child1, sock.recv(&mut buffer))
                 }
                 Ok(size) => {
                     let req: msg::Signal =
                         { msg::decode_request(&buffer[..size])? };
-                    pass_signal(pid, req.0, process_group_leader);
+                    transition("forwarding signal");
+                    pass_signal(
+                        conn,
+                        pid,
+                        req,
+                        leader.group,
+                        params.group_signals,
+                    );
                     (child1, sock.recv(&mut buffer))
                 }
             },
@@ -137,107 +453,816 @@ async fn handle_child(
 
         child = nchild;
         signal = nsignal;
+    };
+
+    Ok(result)
+}
+
+#[cfg(target_os = "linux")]
+fn check_credentials(
+    sock: &Socket,
+    request: &msg::ExecRequestOutput,
+    creds: Option<raw::Credentials>,
+) -> Result<()> {
+    if !request.credentials {
+        return Ok(());
+    }
+
+    let denied =
+        |reason| Err(IoError::new(ErrorKind::PermissionDenied, reason));
+    let creds = match creds {
+        Some(creds) => creds,
+        None => return denied("credentials were not received"),
+    };
+    let peer = sock.peer_credentials()?;
+    debug!("asserted credentials {:?} peer {:?}", creds, peer);
+
+    if creds.pid != peer.pid {
+        return denied("asserted credentials do not belong to peer");
+    }
+    if request.uid >= 0 && request.uid as u32 != creds.uid {
+        return denied("requested uid does not match asserted credentials");
+    }
+    if request.gid >= 0 && request.gid as u32 != creds.gid {
+        return denied("requested gid does not match asserted credentials");
+    }
+    // peer credentials carry no supplementary groups to compare with
+    if !request.groups.is_empty() {
+        return denied("requested groups cannot be asserted");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_credentials(
+    _sock: &Socket,
+    request: &msg::ExecRequestOutput,
+    _creds: Option<raw::Credentials>,
+) -> Result<()> {
+    if request.credentials {
+        return Err(IoError::from_raw_os_error(libc::EOPNOTSUPP));
+    }
+    Ok(())
+}
+
+// one per `io` bit, then the extra ones
+const MAX_REQUEST_FDS: usize =
+    msg::Files::all().bits().count_ones() as usize + msg::MAX_EXTRA_FDS;
+
+// Every bit in `io` and every extra target consumes one passed
+// descriptor in order, anything else means the client and the server
+// disagree on the layout.
+fn check_descriptors(
+    request: &msg::ExecRequestOutput,
+    fds: &[RawFd],
+) -> Result<()> {
+    let invalid =
+        |message| Err(IoError::new(ErrorKind::InvalidInput, message));
+    let targets = &request.extra_fds;
+    if targets.len() > msg::MAX_EXTRA_FDS {
+        return invalid(format!(
+            "too many extra descriptors: {} > {}",
+            targets.len(),
+            msg::MAX_EXTRA_FDS
+        ));
+    }
+    for (i, target) in targets.iter().enumerate() {
+        if *target < 3 || targets[..i].contains(target) {
+            return invalid(format!("invalid descriptor target {}", target));
+        }
     }
 
+    let streams = (request.io & msg::Files::all()).bits().count_ones();
+    let expected = streams as usize + targets.len();
+    if expected == fds.len() {
+        return Ok(());
+    }
+    invalid(format!(
+        "request maps {} descriptors but {} were passed",
+        expected,
+        fds.len()
+    ))
+}
+
+fn check_limits(
+    request: &msg::ExecRequestOutput,
+    limits: &Limits,
+) -> Result<()> {
+    let exceeded = |what, value, limit| {
+        Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!("too many {}: {} > {}", what, value, limit),
+        ))
+    };
+
+    if request.argv.len() > limits.args {
+        return exceeded("arguments", request.argv.len(), limits.args);
+    }
+    if request.env.len() > limits.env {
+        return exceeded(
+            "environment variables",
+            request.env.len(),
+            limits.env,
+        );
+    }
+    // as laid out for exec: KEY=VALUE with terminating NUL
+    let env_size: usize =
+        request.env.iter().map(|(k, v)| k.len() + v.len() + 2).sum();
+    if env_size > limits.env_size {
+        return exceeded("environment bytes", env_size, limits.env_size);
+    }
+    Ok(())
+}
+
+// everything the client sent, before any check or policy applies
+fn log_request(
+    conn: u64,
+    request: &msg::ExecRequestOutput,
+    fds: &[RawFd],
+    redact: &[String],
+) {
+    info!(
+        "conn={} exec request program={:?} argv={:?} cwd={:?} chroot={:?} \
+         env={:?} \
+         startup={:?} io={:?} relay={:?} pty={:?} pgid={} uid={} gid={} \
+         groups={:?} umask={:?} deathsig={} connsig={} start_signal={} \
+         idle_timeout={:?} timeout={:?} group_signals={} credentials={} \
+         rlimits={:?} extra_fds={:?} fds={}",
+        conn,
+        debug::bytes(&request.program),
+        debug::list(&request.argv),
+        debug::bytes(&request.cwd),
+        debug::bytes(&request.chroot),
+        debug::env(&request.env, redact),
+        request.startup,
+        request.io,
+        request.relay,
+        request.pty,
+        request.pgid,
+        request.uid,
+        request.gid,
+        request.groups,
+        request.umask.map(|mask| format!("{:03o}", mask)),
+        request.deathsig,
+        request.connsig,
+        request.start_signal,
+        request.idle_timeout,
+        request.timeout,
+        request.group_signals,
+        request.credentials,
+        request.rlimits,
+        request.extra_fds,
+        fds.len(),
+    );
+}
+
+fn check_env(request: &msg::ExecRequestOutput) -> Result<()> {
+    let valid = |name: &[u8]| !name.is_empty() && !name.contains(&b'=');
+    for (name, value) in &request.env {
+        if !valid(name) || name.contains(&0) || value.contains(&0) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid environment variable {:?}",
+                    String::from_utf8_lossy(name)
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_start_signal(request: &msg::ExecRequestOutput) -> Result<()> {
+    match request.start_signal {
+        0 => Ok(()),
+        sig => Signal::from_c_int(sig).map(drop).map_err(|_| {
+            IoError::new(ErrorKind::InvalidInput, "invalid start signal")
+        }),
+    }
+}
+
+fn check_pty(request: &msg::ExecRequestOutput) -> Result<()> {
+    let invalid =
+        |message| Err(IoError::new(ErrorKind::InvalidInput, message));
+    if !(request.pty & !msg::Files::OUT).is_empty() {
+        return invalid("pseudo-terminal is only supported for stdout");
+    }
+    if !request.relay.contains(request.pty) {
+        return invalid("pseudo-terminal needs relayed stdout");
+    }
     Ok(())
 }
 
+fn check_ctty(request: &msg::ExecRequestOutput, fds: &[RawFd]) -> Result<()> {
+    let invalid =
+        |message| Err(IoError::new(ErrorKind::InvalidInput, message));
+    if !request.io.contains(msg::Files::TTY) {
+        return Ok(());
+    }
+    if !request.startup.contains(msg::StartMode::SESSION) {
+        return invalid("controlling terminal needs a new session");
+    }
+    // follows the streams and workdir, count is checked already
+    let before = msg::Files::IN | msg::Files::OUT | msg::Files::ERR;
+    let index = (request.io & (before | msg::Files::CWD))
+        .bits()
+        .count_ones();
+    if !nix::unistd::isatty(fds[index as usize]).unwrap_or(false) {
+        return invalid("controlling terminal descriptor is not a terminal");
+    }
+    Ok(())
+}
+
+fn check_idle_timeout(request: &msg::ExecRequestOutput) -> Result<()> {
+    let relayed = msg::Files::OUT | msg::Files::ERR;
+    if request.idle_timeout.is_some() && !request.relay.intersects(relayed) {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "idle timeout needs relayed output",
+        ));
+    }
+    Ok(())
+}
+
+// Without --setuid the program keeps the server's uid
+fn check_root(request: &msg::ExecRequestOutput, forbid: bool) -> Result<()> {
+    let root = match request.uid {
+        uid if uid >= 0 => uid == 0,
+        _ => nix::unistd::geteuid().is_root(),
+    };
+    if forbid && root {
+        return Err(IoError::new(
+            ErrorKind::PermissionDenied,
+            "running programs as root is forbidden",
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
 struct ChildParams {
-    pub is_pg_leader: bool,
-    pub connsig: Signal,
+    pub leader: jobs::Leader,
+    pub connsig: Option<Signal>,
+    pub persistent: bool,
+    pub idle_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub start_signal: Option<Signal>,
+    pub group_signals: bool,
+    pub kill_grace: Duration,
+}
+
+pub(crate) const DEFAULT_REDACT_ENV: &[&str] =
+    &["KEY", "SECRET", "TOKEN", "PASS", "AUTH", "CREDENTIAL"];
+
+const BUFFER_SIZE: usize = 4096;
+
+struct Config {
+    socket: PathBuf,
+    state: Option<PathBuf>,
+    redact_env: Vec<String>,
+    log_requests: bool,
+    retention: Duration,
+    stream: bool,
+    buffers: BufferSizes,
+    kill_grace: Duration,
+    // set by `stop --kill-grace` for the shutdown it requests
+    stop_grace: Mutex<Option<Duration>>,
+    drain: mpsc::UnboundedSender<()>,
+    defaults: Settings,
+    policy_file: Option<PathBuf>,
+    // requests hold on to the policy they started with
+    policy: RwLock<Arc<Policy>>,
+    // uptime must not jump with the wall clock
+    started: Instant,
+    started_at: SystemTime,
+}
+
+impl Config {
+    fn new(args: &Args, drain: mpsc::UnboundedSender<()>) -> Config {
+        Config {
+            socket: args.server.to_owned(),
+            state: args.state.map(Path::to_owned),
+            redact_env: args
+                .redact_env
+                .iter()
+                .map(|s| s.to_ascii_uppercase())
+                .collect(),
+            log_requests: args.log_requests,
+            retention: args.retention,
+            stream: args.stream,
+            buffers: args.buffers,
+            kill_grace: args.kill_grace,
+            stop_grace: Mutex::new(None),
+            drain,
+            defaults: args.defaults,
+            policy_file: args.policy_file.map(Path::to_owned),
+            policy: RwLock::new(Arc::new(Policy::new(args.settings))),
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        }
+    }
+
+    fn stop_grace(&self) -> Duration {
+        self.stop_grace.lock().unwrap().unwrap_or(self.kill_grace)
+    }
+
+    fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    fn reload(&self) -> Result<()> {
+        let path = match self.policy_file {
+            Some(ref path) => path,
+            None => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    "server was started without --policy",
+                ))
+            }
+        };
+        let settings = policy::load(path, self.defaults)?;
+        *self.policy.write().unwrap() = Arc::new(Policy::new(settings));
+        info!("policy reloaded from {:?}", path);
+        Ok(())
+    }
+
+    fn info(&self, sock: &Socket) -> Result<msg::ServerInfo> {
+        let lossy = |path: &Path| path.to_string_lossy().into_owned();
+        let uid = nix::unistd::geteuid();
+        let policy = self.policy();
+        let limits = &policy.settings.limits;
+        let (socket_sndbuf, socket_rcvbuf) = sock.buffer_sizes()?;
+        Ok(msg::ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            pid: Pid::this().as_raw(),
+            uid: uid.as_raw(),
+            privileged: uid.is_root(),
+            socket: lossy(&self.socket),
+            state_file: self.state.as_deref().map(lossy),
+            log_file: logger::path().as_deref().map(lossy),
+            buffer_size: BUFFER_SIZE,
+            socket_sndbuf,
+            socket_rcvbuf,
+            redact_env: self.redact_env.clone(),
+            status_retention: self.retention,
+            transport: if self.stream { "stream" } else { "seqpacket" }
+                .to_owned(),
+            exec_rate: policy.rate(),
+            exec_burst: policy.burst(),
+            max_args: limits.args,
+            max_env: limits.env,
+            max_env_size: limits.env_size,
+            forbid_root: policy.settings.forbid_root,
+            policy_file: self.policy_file.as_deref().map(lossy),
+            started_at: self
+                .started_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default(),
+            uptime: self.started.elapsed(),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(sock: &Socket) -> Result<u32> {
+    sock.peer_credentials().map(|creds| creds.uid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(sock: &Socket) -> Result<u32> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    match unsafe { libc::getpeereid(sock.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(IoError::last_os_error()),
+    }
+}
+
+// Peer closed or reset the connection, there is no one to answer
+fn client_gone(err: &IoError) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+// Nothing is read from an attached client, it may only go away
+async fn attach(
+    conn: u64,
+    sock: &Socket,
+    selector: msg::JobSelector,
+) -> Result<()> {
+    let job = jobs::find(selector).map(|(job, _, _)| job);
+    let subscription = job.and_then(capture::subscribe);
+    let mut sendbuf = Vec::new();
+    let response = msg::AttachResult {
+        found: job.is_some(),
+        captured: subscription.is_some(),
+    };
+    msg::encode_request(&mut sendbuf, &response)?;
+    sock.send(&sendbuf).await?;
+
+    let (job, (backlog, mut chunks)) = match (job, subscription) {
+        (Some(job), Some(subscription)) => (job, subscription),
+        _ => return Ok(()),
+    };
+    info!("conn={} job={} attached", conn, job);
+
+    for (stream, data) in &backlog {
+        sendbuf.clear();
+        msg::encode_request(
+            &mut sendbuf,
+            &msg::Attached::Data(*stream, data),
+        )?;
+        sock.send(&sendbuf).await?;
+    }
+
+    let mut recvbuf = [0; 16];
+    loop {
+        let (stream, data) =
+            match select(chunks.next(), sock.recv(&mut recvbuf)).await {
+                Either::Left((Some(chunk), _)) => chunk,
+                Either::Left((None, _)) => break,
+                Either::Right(_) => {
+                    info!("conn={} job={} detached", conn, job);
+                    return Ok(());
+                }
+            };
+        sendbuf.clear();
+        msg::encode_request(
+            &mut sendbuf,
+            &msg::Attached::Data(stream, &data),
+        )?;
+        sock.send(&sendbuf).await?;
+    }
+
+    let state = jobs::status(msg::JobSelector::Id(job));
+    debug!("conn={} job={} output ended state={:?}", conn, job, state);
+    sendbuf.clear();
+    msg::encode_request(&mut sendbuf, &msg::Attached::Ended(state))?;
+    sock.send(&sendbuf).await.map(drop)
+}
+
+fn kill_job(conn: u64, request: msg::KillRequest) -> msg::KillResult {
+    let (_, pid, leader) = match jobs::find(request.selector) {
+        Some(job) => job,
+        None => {
+            return msg::KillResult {
+                found: false,
+                errno: 0,
+            }
+        }
+    };
+    if !system::is_signal(request.signal) {
+        return msg::KillResult {
+            found: true,
+            errno: libc::EINVAL,
+        };
+    }
+    if request.group && !(leader.group || leader.session) {
+        warn!("conn={} process={} leads no process group", conn, pid);
+        return msg::KillResult {
+            found: true,
+            errno: libc::ESRCH,
+        };
+    }
+
+    info!(
+        "conn={} process={} sending {}{}{}",
+        conn,
+        pid,
+        system::signal_name(request.signal),
+        match request.value {
+            Some(value) => format!(" value={}", value),
+            None => String::new(),
+        },
+        if request.group { " to group" } else { "" }
+    );
+    let errno = match system::send_signal(
+        pid,
+        request.signal,
+        request.value,
+        request.group,
+    ) {
+        Ok(()) => 0,
+        Err(err) => {
+            warn!("conn={} process={} kill failed: {}", conn, pid, err);
+            err.raw_os_error().unwrap_or(libc::EINVAL)
+        }
+    };
+    msg::KillResult { found: true, errno }
 }
 
-async fn client_session(sock: Socket) -> Result<()> {
-    let mut buffer = vec![0u8; 4096];
+async fn client_session(
+    conn: u64,
+    sock: Socket,
+    config: Arc<Config>,
+) -> Result<()> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
 
-    let req: msg::RequestOutput = {
+    let req: msg::RequestOutput = loop {
         let received = sock.recv(&mut buffer).await?;
-        debug!("request received: {} bytes", received);
-        msg::decode_request(&buffer[..received])?
+        debug!("conn={} request received: {} bytes", conn, received);
+        match msg::decode_request(&buffer[..received])? {
+            msg::RequestOutput::Ping => {
+                debug!("conn={} requested `ping`", conn);
+                let response = msg::Pong {
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                };
+                let mut data = Vec::new();
+                msg::encode_request(&mut data, &response)?;
+                sock.send(&data).await?;
+            }
+            req => break req,
+        }
     };
 
     buffer.clear();
 
     match req {
-        msg::RequestOutput::Stop => {
-            debug!("requested `stop`");
+        msg::RequestOutput::Stop(grace) => {
+            debug!("conn={} requested `stop`", conn);
+            *config.stop_grace.lock().unwrap() = grace;
             system::raise(Signal::SIGINT)
                 .expect("failed to send SIGINT to self");
             Ok(())
         }
+        msg::RequestOutput::Drain => {
+            debug!("conn={} requested `drain`", conn);
+            let _ = config.drain.unbounded_send(());
+            Ok(())
+        }
+        msg::RequestOutput::Reload => {
+            debug!("conn={} requested `reload`", conn);
+            let error = config.reload().err().map(|err| {
+                warn!("conn={} reload failed: {}", conn, err);
+                err.to_string()
+            });
+            let response = msg::ReloadResult { error };
+            msg::encode_request(&mut buffer, &response)?;
+            sock.send(&buffer).await.map(drop)
+        }
+        msg::RequestOutput::LogLevel(verbosity) => {
+            debug!("conn={} requested `loglevel`", conn);
+            if let Some(verbosity) = verbosity {
+                logger::set_verbosity(verbosity);
+                info!(
+                    "conn={} log level set to {}",
+                    conn,
+                    logger::level_name(verbosity)
+                );
+            }
+            let response = msg::LogLevelResult {
+                verbosity: logger::verbosity(),
+            };
+            msg::encode_request(&mut buffer, &response)?;
+            sock.send(&buffer).await.map(drop)
+        }
+        msg::RequestOutput::Info => {
+            debug!("conn={} requested `info`", conn);
+            msg::encode_request(&mut buffer, &config.info(&sock)?)?;
+            sock.send(&buffer).await.map(drop)
+        }
+        msg::RequestOutput::JobStatus(selector) => {
+            debug!("conn={} requested `status` of {:?}", conn, selector);
+            let response = jobs::status(selector);
+            msg::encode_request(&mut buffer, &response)?;
+            sock.send(&buffer).await.map(drop)
+        }
+        msg::RequestOutput::List => {
+            debug!("conn={} requested `list`", conn);
+            let response = msg::JobList { jobs: jobs::list() };
+            msg::encode_request(&mut buffer, &response)?;
+            sock.send(&buffer).await.map(drop)
+        }
+        msg::RequestOutput::Attach(selector) => {
+            debug!("conn={} requested `attach` to {:?}", conn, selector);
+            attach(conn, &sock, selector).await
+        }
+        msg::RequestOutput::Kill(request) => {
+            debug!("conn={} requested `kill` {:?}", conn, request);
+            let response = kill_job(conn, request);
+            msg::encode_request(&mut buffer, &response)?;
+            sock.send(&buffer).await.map(drop)
+        }
+        // answered while reading the request
+        msg::RequestOutput::Ping => unreachable!(),
         msg::RequestOutput::Exec(header) => {
-            debug!("requested `exec`");
-            debug!("exec header size: {}", header.body_size);
+            debug!("conn={} requested `exec`", conn);
+            debug!("conn={} exec header size: {}", conn, header.body_size);
             let (child, params) = {
-                let mut fdbuf = [-1 as RawFd; 3];
+                let mut fdbuf = [-1 as RawFd; MAX_REQUEST_FDS];
                 let exec_request: msg::ExecRequestOutput;
                 let fds: &[RawFd];
                 buffer.resize_with(header.body_size, Default::default);
+                let creds;
                 {
-                    let (data_len, fds_len) = sock
-                        .recvfds(&mut CmsgBuf::new(&mut buffer, &mut fdbuf))
-                        .await?;
+                    let (data_len, fds_len) = {
+                        let mut cmsg = CmsgBuf::new(&mut buffer, &mut fdbuf);
+                        let received = match sock.recvfds(&mut cmsg).await {
+                            Err(ref err) if client_gone(err) => (0, 0),
+                            received => received?,
+                        };
+                        creds = cmsg.credentials();
+                        received
+                    };
 
-                    debug!("received exec data={} fds={}", data_len, fds_len);
+                    if data_len == 0 {
+                        fdbuf[..fds_len].iter().for_each(|fd| {
+                            Fd::new(*fd);
+                        });
+                        info!("conn={} client gone before sending exec", conn);
+                        return Ok(());
+                    }
+
+                    debug!(
+                        "conn={} received exec data={} fds={}",
+                        conn, data_len, fds_len
+                    );
 
                     exec_request =
-                        msg::decode_request_ref(&buffer[..data_len])?;
+                        match msg::decode_request_ref(&buffer[..data_len]) {
+                            Ok(request) => request,
+                            Err(err) => {
+                                fdbuf[..fds_len].iter().for_each(|fd| {
+                                    Fd::new(*fd);
+                                });
+                                return Err(err);
+                            }
+                        };
 
                     fds = &fdbuf[..fds_len]
                 }
 
-                let is_pg_leader = exec_request.startup.contains(
-                    msg::StartMode::PROCESS_GROUP | msg::StartMode::SESSION,
-                );
+                if config.log_requests {
+                    log_request(conn, &exec_request, fds, &config.redact_env);
+                }
+
+                let is_session_leader =
+                    exec_request.startup.contains(msg::StartMode::SESSION);
+                let is_pg_leader = is_session_leader
+                    || (exec_request
+                        .startup
+                        .contains(msg::StartMode::PROCESS_GROUP)
+                        && exec_request.pgid == 0);
+
+                let connsig = match exec_request.connsig {
+                    0 => None,
+                    sig => Some(
+                        Signal::from_c_int(sig).unwrap_or(Signal::SIGKILL),
+                    ),
+                };
+
+                let persistent = exec_request.deathsig == 0;
 
-                let connsig = Signal::from_c_int(exec_request.connsig)
-                    .unwrap_or(Signal::SIGKILL);
+                let leader = jobs::Leader {
+                    group: is_pg_leader,
+                    session: is_session_leader,
+                };
 
                 let child = {
-                    let proc_request: msg::ProcessRequest =
+                    let mut proc_request: msg::ProcessRequest =
                         { (&exec_request).into() };
-                    debug!("fds: {:?} -- request: {:#?}", fds, proc_request);
-                    setup_command(&proc_request, fds)
+                    debug!(
+                        "conn={} fds: {:?} -- request: {:#?}",
+                        conn, fds, proc_request
+                    );
+                    let policy = config.policy();
+                    let settings = &policy.settings;
+                    let checked =
+                        check_credentials(&sock, &exec_request, creds)
+                            .and_then(|()| {
+                                check_descriptors(&exec_request, fds)
+                            })
+                            .and_then(|()| {
+                                check_limits(&exec_request, &settings.limits)
+                            })
+                            .and_then(|()| {
+                                check_root(&exec_request, settings.forbid_root)
+                            })
+                            .and_then(|()| check_idle_timeout(&exec_request))
+                            .and_then(|()| check_pty(&exec_request))
+                            .and_then(|()| check_ctty(&exec_request, fds))
+                            .and_then(|()| check_start_signal(&exec_request))
+                            .and_then(|()| check_env(&exec_request))
+                            .and_then(|()| policy.admit(|| peer_uid(&sock)))
+                            .and_then(|()| {
+                                relay::open_pipes(
+                                    exec_request.io,
+                                    exec_request.relay,
+                                    exec_request.pty,
+                                    fds,
+                                )
+                            });
+                    let spawned = match checked {
+                        Ok((pipes, io, childfds)) => {
+                            proc_request.io = io;
+                            setup_command(&proc_request, &childfds)
+                                .map(|child| (child, pipes))
+                        }
+                        Err(err) => {
+                            for _ in fds.iter().cloned().map(Fd::new) {
+                                //
+                            }
+                            Err(err)
+                        }
+                    };
+                    spawned.map(|(child, pipes)| {
+                        info!(
+                            "conn={} process={} started program={:?} \
+                             argv={:?} cwd={:?} startup={:?} env={:?}",
+                            conn,
+                            child.id(),
+                            debug::bytes(&proc_request.program),
+                            debug::list(proc_request.argv),
+                            debug::bytes(&proc_request.cwd),
+                            proc_request.startup,
+                            debug::env(proc_request.env, &config.redact_env),
+                        );
+                        let job = jobs::register(
+                            child.id(),
+                            proc_request.program,
+                            proc_request.argv,
+                            leader,
+                            persistent,
+                        );
+                        if pipes.is_some() {
+                            capture::open(job);
+                        }
+                        (child, job, pipes)
+                    })
                 };
 
                 (
                     child,
                     ChildParams {
-                        is_pg_leader,
+                        leader,
                         connsig,
+                        persistent,
+                        idle_timeout: exec_request.idle_timeout,
+                        timeout: exec_request.timeout,
+                        start_signal: Signal::from_c_int(
+                            exec_request.start_signal,
+                        )
+                        .ok(),
+                        group_signals: exec_request.group_signals,
+                        kill_grace: config.kill_grace,
                     },
                 )
             };
 
             match child {
-                Ok(child) => {
-                    debug!("process={} started", child.id());
+                Ok((child, job, pipes)) => {
+                    debug!(
+                        "conn={} process={} started job={} persistent={}",
+                        conn,
+                        child.id(),
+                        job,
+                        params.persistent
+                    );
+                    if let Some(sig) = params.start_signal {
+                        info!(
+                            "conn={} process={} sending start signal={}",
+                            conn,
+                            child.id(),
+                            sig
+                        );
+                        kill(Pid::from_raw(child.id()), sig);
+                    }
                     let response = msg::StartedProcess {
                         success: true,
                         message: "",
                         errno: 0,
-                        pid: child.id() as i32,
+                        pid: child.id(),
+                        job,
                     };
                     buffer.clear();
                     msg::encode_request(&mut buffer, &response)?;
-                    sock.send(&buffer).await?;
-                    handle_child(
-                        sock,
-                        child,
-                        buffer,
-                        params.connsig,
-                        params.is_pg_leader,
-                    )
-                    .await
+                    let result = match sock.send(&buffer).await {
+                        Ok(_) => {
+                            handle_child(
+                                conn, job, sock, child, buffer, params, pipes,
+                            )
+                            .await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let exit = result.as_ref().ok().and_then(Option::as_ref);
+                    jobs::unregister(job, exit);
+                    capture::close(job);
+                    result.map(drop)
                 }
                 Err(error) => {
-                    debug!("process failed");
+                    debug!("conn={} process failed", conn);
                     let message = format!("{}", error);
                     let response = msg::StartedProcess {
                         success: false,
                         message: &message,
-                        errno: error.raw_os_error().unwrap_or(-1),
+                        errno: child::errno(&error),
                         pid: -1,
+                        job: 0,
                     };
                     buffer.clear();
                     msg::encode_request(&mut buffer, &response)?;
@@ -248,21 +1273,79 @@ async fn client_session(sock: Socket) -> Result<()> {
     }
 }
 
-async fn handle_client(sock: Socket) {
-    if let Err(err) = client_session(sock).await {
-        error!("error during connection: {:?}", err);
+async fn handle_client(
+    conn: u64,
+    sock: Socket,
+    config: Arc<Config>,
+    _active: mpsc::Sender<()>,
+) {
+    #[cfg(target_os = "linux")]
+    {
+        if let Err(err) = sock.pass_credentials() {
+            warn!("conn={} failed to enable SO_PASSCRED: {:?}", conn, err);
+        }
     }
+
+    isolate(conn, client_session(conn, sock, config)).await
 }
 
-async fn listen(socket: Socket) {
+// a bug one client runs into must not take the server down with it
+async fn isolate<F>(conn: u64, session: F)
+where
+    F: Future<Output = Result<()>>,
+{
+    match AssertUnwindSafe(session).catch_unwind().await {
+        Ok(Err(ref err)) if client_gone(err) => {
+            info!("conn={} client gone: {}", conn, err);
+        }
+        Ok(Err(err)) => {
+            error!("conn={} error during connection: {:?}", conn, err);
+        }
+        Ok(Ok(())) => {}
+        Err(panic) => {
+            error!(
+                "conn={} connection panicked: {}",
+                conn,
+                panic_message(&panic)
+            );
+        }
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown cause", String::as_str),
+    }
+}
+
+async fn listen(
+    socket: &Socket,
+    config: Arc<Config>,
+    active: mpsc::Sender<()>,
+) {
     let mut incoming = socket.accept();
+    let mut next_conn: u64 = 0;
     while let (Some(res), incoming1) = incoming.into_future().await {
         incoming = incoming1;
         match res {
             Ok(sock) => {
-                info!("client connected");
+                next_conn += 1;
+                info!("conn={} client connected", next_conn);
+                let sock = Socket::from_fd(sock).unwrap();
+                if let Err(err) = sock.set_buffer_sizes(config.buffers) {
+                    warn!(
+                        "conn={} failed to set buffer sizes: {}",
+                        next_conn, err
+                    );
+                }
                 runtime::spawn(Box::pin(handle_client(
-                    Socket::from_fd(sock).unwrap(),
+                    next_conn,
+                    sock,
+                    config.clone(),
+                    active.clone(),
                 )));
             }
             Err(err) => {
@@ -272,8 +1355,106 @@ async fn listen(socket: Socket) {
     }
 }
 
+async fn reopen_log(mut sighup: tokio::signal::unix::Signal) {
+    while let Some(()) = sighup.recv().await {
+        match logger::reopen() {
+            Ok(()) => info!("log file reopened"),
+            Err(err) => error!("failed to reopen log file {:?}", err),
+        }
+    }
+}
+
+async fn heartbeat(
+    interval: Duration,
+    started: Instant,
+    stopped: oneshot::Receiver<()>,
+) {
+    let beat = async {
+        loop {
+            runtime::delay_for(interval).await;
+            info!(
+                "alive, {} jobs running, uptime {:?}",
+                jobs::list().len(),
+                started.elapsed()
+            );
+        }
+    };
+    pin_mut!(beat);
+    let _ = select(beat, stopped).await;
+}
+
 pub(crate) struct Args<'a> {
     pub server: &'a Path,
+    pub state: Option<&'a Path>,
+    pub dump_jobs: Option<&'a Path>,
+    pub redact_env: &'a [String],
+    pub retention: Duration,
+    pub stream: bool,
+    pub socket_mode: Option<u32>,
+    pub buffers: BufferSizes,
+    pub kill_grace: Duration,
+    pub defaults: Settings,
+    pub settings: Settings,
+    pub policy_file: Option<&'a Path>,
+    pub print_socket: bool,
+    pub log_requests: bool,
+    pub max_runtime: Option<Duration>,
+    pub heartbeat: Option<Duration>,
+    pub supervise: Option<Supervise<'a>>,
+}
+
+/// Program the server runs itself, the server exits once it does.
+pub(crate) struct Supervise<'a> {
+    pub program: &'a [u8],
+    pub argv: &'a [&'a [u8]],
+    pub propagate_exit: bool,
+    // restarts it, after stopping the running one with the other
+    pub restart_signal: Option<Signal>,
+    pub restart_stop_signal: Signal,
+}
+
+// gets copies of the server's stdio, which is close-on-exec here
+fn spawn_supervised(sup: &Supervise) -> Result<Child> {
+    use nix::fcntl::{fcntl, FcntlArg};
+
+    let mut fds = Vec::with_capacity(3);
+    for fd in 0..3 {
+        match fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0)) {
+            Ok(copy) => fds.push(copy),
+            Err(err) => {
+                for _ in fds.into_iter().map(Fd::new) {
+                    //
+                }
+                return Err(raw::nixerror(err));
+            }
+        }
+    }
+
+    let request = msg::ProcessRequest {
+        program: sup.program,
+        argv: sup.argv,
+        cwd: b"",
+        chroot: b"",
+        env: &[],
+        startup: msg::StartMode::empty(),
+        io: msg::Files::IN | msg::Files::OUT | msg::Files::ERR,
+        pgid: 0,
+        uid: -1,
+        gid: -1,
+        groups: &[],
+        umask: None,
+        deathsig: Signal::SIGKILL as i32,
+        rlimits: &[],
+        extra_fds: &[],
+    };
+    let child = setup_command(&request, &fds)?;
+    info!(
+        "process={} supervised program={:?} argv={:?}",
+        child.id(),
+        debug::bytes(&request.program),
+        debug::list(request.argv),
+    );
+    Ok(child)
 }
 
 fn first_invalid_fd(fr: i32, to: i32) -> i32 {
@@ -282,7 +1463,7 @@ fn first_invalid_fd(fr: i32, to: i32) -> i32 {
             return i;
         }
     }
-    return to;
+    to
 }
 
 pub(crate) fn command(args: &Args) -> Result<i32> {
@@ -299,15 +1480,41 @@ pub(crate) fn command(args: &Args) -> Result<i32> {
         runtime
     };
 
+    jobs::set_retention(args.retention);
+    if let Some(state) = args.state {
+        debug!("restoring jobs from {:?}", state);
+        jobs::restore(state)?;
+    }
+
     info!("server starting at {:?}", args.server);
-    let fd = bind(args.server)?;
-    defer!({
+    let fd = bind(args.server, args.stream, args.socket_mode)?;
+    let remove_socket = || {
+        if is_abstract(args.server) {
+            return;
+        }
         debug!("removing server socket at {:?}", args.server);
         std::fs::remove_file(args.server).unwrap_or_else(|err| {
             error!("failed to remove socket file {:?}", err)
         })
+    };
+    // draining server gives the path away to its replacement early
+    let socket_removed = Cell::new(false);
+    defer!({
+        if !socket_removed.get() {
+            remove_socket();
+        }
     });
 
+    // socket is listening already, connecting clients queue up
+    if args.print_socket {
+        let path = if is_abstract(args.server) {
+            args.server.to_owned()
+        } else {
+            std::env::current_dir()?.join(args.server)
+        };
+        writeln!(std::io::stdout(), "{}", path.display())?;
+    }
+
     debug!("runtime starting");
     let res = runtime.block_on(async {
         info!("server started");
@@ -317,8 +1524,103 @@ pub(crate) fn command(args: &Args) -> Result<i32> {
         let mut sigterm = signal(SignalKind::terminate())?;
         let sigchld = child_watcher::signal_queue()?;
 
-        runtime::spawn(child_watcher::listen(sigchld));
-        runtime::spawn(listen(sock));
+        let (stop_watcher, watcher_stopped) = oneshot::channel();
+        let watcher =
+            runtime::spawn(child_watcher::listen(sigchld, watcher_stopped));
+
+        if logger::is_file() {
+            let sighup = signal(SignalKind::hangup())?;
+            runtime::spawn(reopen_log(sighup));
+        }
+
+        // handled before the program can tell anyone it runs, the default
+        // action would end the server
+        let restart_signal =
+            args.supervise.as_ref().and_then(|sup| sup.restart_signal);
+        let mut restart = match restart_signal {
+            Some(sig) => Some(signal(SignalKind::from_raw(sig as i32))?),
+            None => None,
+        };
+        let supervised = match args.supervise {
+            Some(ref sup) => Some(spawn_supervised(sup)?),
+            None => None,
+        };
+        let supervised_pid = Cell::new(supervised.as_ref().map(Child::id));
+        let propagate_exit = match args.supervise {
+            Some(ref sup) => sup.propagate_exit,
+            None => false,
+        };
+        let exit_code = Cell::new(0);
+        let supervised = async {
+            let mut child = match supervised {
+                Some(child) => child,
+                None => return future::pending().await,
+            };
+            let code = loop {
+                let (pid, started) =
+                    (Pid::from_raw(child.id()), child.started());
+                let restart_requested = async {
+                    match restart {
+                        Some(ref mut sig) => sig.recv().await,
+                        None => future::pending().await,
+                    }
+                };
+                pin_mut!(restart_requested);
+                let exited = match select(&mut child, restart_requested).await
+                {
+                    Either::Left((exited, _)) => exited,
+                    Either::Right(_) => {
+                        let sup = args.supervise.as_ref().unwrap();
+                        info!("process={} restarting supervised program", pid);
+                        let targets = [(pid, jobs::Leader::default())];
+                        let sig = sup.restart_stop_signal;
+                        let grace = args.kill_grace;
+                        if let Ok(exit) =
+                            escalate(&targets, sig, grace, &mut child).await
+                        {
+                            child_finished(0, pid, exit, started.elapsed());
+                        }
+                        match spawn_supervised(sup) {
+                            Ok(next) => {
+                                supervised_pid.set(Some(next.id()));
+                                child = next;
+                                continue;
+                            }
+                            Err(err) => {
+                                error!("failed to restart program: {}", err);
+                                break None;
+                            }
+                        }
+                    }
+                };
+                break match exited {
+                    Ok(exit) => {
+                        child_finished(0, pid, exit, started.elapsed())
+                            .exit_code()
+                    }
+                    Err(err) => {
+                        error!("process={} failed to wait: {}", pid, err);
+                        None
+                    }
+                };
+            };
+            if propagate_exit {
+                exit_code.set(code.unwrap_or(1));
+            }
+        }
+        .fuse();
+        pin_mut!(supervised);
+
+        let (active, mut finished) = mpsc::channel::<()>(0);
+        let (drain, mut drain_requested) = mpsc::unbounded::<()>();
+        let config = Arc::new(Config::new(args, drain));
+        let listener = Box::pin(listen(&sock, config.clone(), active));
+
+        let (stop_heartbeat, heartbeat_stopped) = oneshot::channel();
+        if let Some(interval) = args.heartbeat {
+            let started = config.started;
+            runtime::spawn(heartbeat(interval, started, heartbeat_stopped));
+        }
 
         let si = sigint.recv();
         let st = sigterm.recv();
@@ -333,18 +1635,244 @@ pub(crate) fn command(args: &Args) -> Result<i32> {
             }
         });
 
-        match received.await {
-            Some(sig) => {
-                info!("received signal {:?}", sig);
-                Ok(0)
+        // lifetime limit drains the same way `drain` does
+        let max_runtime = args.max_runtime;
+        let expired = async move {
+            match max_runtime {
+                Some(limit) => {
+                    runtime::delay_for(limit).await;
+                    info!("maximum runtime of {:?} reached", limit);
+                }
+                None => future::pending().await,
             }
-            None => {
-                warn!("received no signal");
-                Ok(0)
+        };
+        pin_mut!(expired);
+
+        // supervised program exiting drains as well
+        let stop = select(
+            received,
+            select(
+                drain_requested.next(),
+                select(expired, supervised.as_mut()),
+            ),
+        );
+
+        let draining = match select(listener, stop).await {
+            Either::Left(((), _)) => {
+                warn!("listener stopped");
+                None
             }
+            Either::Right((Either::Left((received, _)), _)) => {
+                match received {
+                    Some(sig) => info!("received signal {:?}", sig),
+                    None => warn!("received no signal"),
+                }
+                None
+            }
+            Either::Right((Either::Right((_, received)), _)) => Some(received),
+        };
+
+        // closed before the file goes away, so late clients are refused
+        // instead of queueing on a socket nobody accepts from
+        if let Err(err) = sock.close() {
+            warn!("failed to close server socket: {}", err);
         }
+
+        let forced = match draining {
+            None => true,
+            Some(received) => {
+                remove_socket();
+                socket_removed.set(true);
+                info!("draining, waiting for running jobs");
+                match select(finished.next(), received).await {
+                    Either::Left(_) => false,
+                    Either::Right((sig, _)) => {
+                        info!("received signal {:?} while draining", sig);
+                        true
+                    }
+                }
+            }
+        };
+
+        let supervised_running =
+            supervised_pid.get().is_some() && !supervised.is_terminated();
+
+        // taken before terminating, the dump tells how each one ended
+        let tracked = jobs::list();
+
+        // jobs that would not outlive the server get a chance to exit
+        let mut targets = jobs::terminable();
+        if supervised_running {
+            let pid = Pid::from_raw(supervised_pid.get().unwrap());
+            targets.push((pid, jobs::Leader::default()));
+        }
+
+        if forced && !targets.is_empty() {
+            let grace = config.stop_grace();
+            info!("terminating {} jobs", targets.len());
+            let gone = async {
+                finished.next().await;
+                if supervised_running {
+                    supervised.as_mut().await;
+                }
+            };
+            pin_mut!(gone);
+            let limit = grace + DRAIN_TIMEOUT;
+            let stopped = escalate(&targets, Signal::SIGTERM, grace, gone);
+            if runtime::timeout(limit, stopped).await.is_err() {
+                warn!("connections still active after {:?}", limit);
+            }
+        } else if forced {
+            debug!("draining connections");
+            if runtime::timeout(DRAIN_TIMEOUT, finished.next())
+                .await
+                .is_err()
+            {
+                warn!("connections still active after {:?}", DRAIN_TIMEOUT);
+            }
+        } else if supervised_running {
+            // draining waits for it like for any other job
+            info!("waiting for supervised process");
+            supervised.await;
+        }
+
+        if let Some(path) = args.dump_jobs {
+            debug!("dumping {} jobs to {:?}", tracked.len(), path);
+            if let Err(err) = jobs::dump(path, &tracked) {
+                error!("failed to dump jobs to {:?}: {}", path, err);
+            }
+        }
+
+        let _ = stop_heartbeat.send(());
+        let _ = stop_watcher.send(());
+        let _ = watcher.await;
+        Ok(exit_code.get())
     });
 
     info!("server shutdown");
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(io: msg::Files, extra_fds: &[i32]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let request = msg::ExecRequestInput {
+            program: b"true",
+            argv: &[],
+            cwd: b"",
+            chroot: b"",
+            env: &[],
+            startup: msg::StartMode::empty(),
+            io,
+            relay: msg::Files::empty(),
+            pty: msg::Files::empty(),
+            pgid: 0,
+            uid: -1,
+            gid: -1,
+            groups: &[],
+            umask: None,
+            deathsig: 0,
+            connsig: 0,
+            start_signal: 0,
+            idle_timeout: None,
+            timeout: None,
+            group_signals: false,
+            credentials: false,
+            rlimits: &[],
+            extra_fds,
+        };
+        msg::encode_request(&mut buffer, &request).unwrap();
+        buffer
+    }
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Child, Command, Stdio};
+
+    // polls for this one child only, the watcher would reap any
+    async fn exited(child: &mut Child) -> Option<i32> {
+        loop {
+            match child.try_wait().unwrap() {
+                Some(status) => return status.signal(),
+                None => runtime::delay_for(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    // started once the trap is set, sleep inherits the ignored SIGTERM
+    fn sleeper(trap: &str) -> Child {
+        use std::io::Read;
+
+        let script = format!("{} echo; exec sleep 60", trap);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut ready = [0u8; 1];
+        child.stdout.take().unwrap().read_exact(&mut ready).unwrap();
+        child
+    }
+
+    fn escalated(mut child: Child, grace: Duration) -> Option<i32> {
+        let pid = Pid::from_raw(child.id() as i32);
+        let targets = [(pid, jobs::Leader::default())];
+        let gone = Box::pin(exited(&mut child));
+        let mut rt = runtime::new().unwrap();
+        rt.block_on(escalate(&targets, Signal::SIGTERM, grace, gone))
+    }
+
+    #[test]
+    fn escalate_kills_after_grace() {
+        let child = sleeper("trap '' TERM;");
+        let grace = Duration::from_millis(200);
+        let started = Instant::now();
+        assert_eq!(escalated(child, grace), Some(libc::SIGKILL));
+        assert!(started.elapsed() >= grace);
+    }
+
+    #[test]
+    fn escalate_stops_at_first_signal() {
+        let child = sleeper("");
+        let grace = Duration::from_secs(30);
+        assert_eq!(escalated(child, grace), Some(libc::SIGTERM));
+    }
+
+    #[test]
+    fn panicking_session_leaves_server_running() {
+        let mut rt = runtime::new().unwrap();
+        rt.block_on(async {
+            let bad =
+                future::lazy(|_| -> Result<()> { panic!("bad request") });
+            assert!(runtime::spawn(isolate(1, bad)).await.is_ok());
+            // the next connection is served on the same runtime
+            let next = future::lazy(|_| Ok(()));
+            assert!(runtime::spawn(isolate(2, next)).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn descriptors_fit_with_every_stream_and_extra() {
+        let extra: Vec<i32> = (3..3 + msg::MAX_EXTRA_FDS as i32).collect();
+        let data = request(msg::Files::all(), &extra);
+        let request: msg::ExecRequestOutput =
+            msg::decode_request_ref(&data).unwrap();
+        let fds = [-1 as RawFd; MAX_REQUEST_FDS];
+        assert_eq!(MAX_REQUEST_FDS, 21);
+        assert!(check_descriptors(&request, &fds).is_ok());
+        assert!(check_descriptors(&request, &fds[1..]).is_err());
+    }
+
+    #[test]
+    fn descriptors_reject_too_many_extra() {
+        let extra: Vec<i32> = (3..4 + msg::MAX_EXTRA_FDS as i32).collect();
+        let data = request(msg::Files::empty(), &extra);
+        let request: msg::ExecRequestOutput =
+            msg::decode_request_ref(&data).unwrap();
+        let fds = [-1 as RawFd; msg::MAX_EXTRA_FDS + 1];
+        assert!(check_descriptors(&request, &fds).is_err());
+    }
+}
@@ -11,22 +11,69 @@ use nix::unistd;
 use signal_hook_registry::{register_sigaction, unregister, SigId};
 
 use crate::pipe;
-use crate::raw::{invalid_argument, nixerror, RawFd};
+use crate::raw::{invalid_argument, RawFd};
+use crate::system;
 
 type SigVal = libc::c_int;
-const SIGSZ: usize = mem::size_of::<SigVal>();
+const INTSZ: usize = mem::size_of::<SigVal>();
+// signal number, whether it was queued and its value
+const SIGSZ: usize = 3 * INTSZ;
+
+/// Signal as caught, with the value it was queued with if any.
+#[derive(Clone, Copy, Debug)]
+pub struct Caught {
+    pub signo: SigVal,
+    pub value: Option<SigVal>,
+}
+
+impl Caught {
+    /// Regular signal, `None` for realtime ones.
+    pub fn signal(&self) -> Option<Signal> {
+        Signal::from_c_int(self.signo).ok()
+    }
+}
+
+// the pipe never leaves the process, native byte order is enough
+fn sig_to_buf(signo: SigVal, value: Option<SigVal>) -> [u8; SIGSZ] {
+    let mut buf = [0u8; SIGSZ];
+    buf[..INTSZ].copy_from_slice(&signo.to_ne_bytes());
+    if let Some(value) = value {
+        buf[INTSZ..2 * INTSZ].copy_from_slice(&SigVal::to_ne_bytes(1));
+        buf[2 * INTSZ..].copy_from_slice(&value.to_ne_bytes());
+    }
+    buf
+}
+
+fn buf_to_sig(buf: [u8; SIGSZ]) -> Caught {
+    let field = |i: usize| {
+        let mut val = [0u8; INTSZ];
+        val.copy_from_slice(&buf[i * INTSZ..(i + 1) * INTSZ]);
+        SigVal::from_ne_bytes(val)
+    };
+    Caught {
+        signo: field(0),
+        value: if field(1) != 0 { Some(field(2)) } else { None },
+    }
+}
 
-fn sig_to_buf(val: SigVal) -> [u8; SIGSZ] {
-    unsafe { mem::transmute_copy(&val) }
+#[cfg(target_os = "linux")]
+fn queued_value(info: &siginfo_t) -> Option<SigVal> {
+    if info.si_code != libc::SI_QUEUE {
+        return None;
+    }
+    let value = unsafe { info.si_value() };
+    Some(value.sival_ptr as isize as SigVal)
 }
 
-fn buf_to_sig(val: [u8; SIGSZ]) -> SigVal {
-    unsafe { mem::transmute_copy(&val) }
+#[cfg(not(target_os = "linux"))]
+fn queued_value(_info: &siginfo_t) -> Option<SigVal> {
+    None
 }
 
 fn make_callback(wraw: RawFd) -> impl Fn(&siginfo_t) + Send + Sync {
     move |info: &siginfo_t| {
-        let _ = unistd::write(wraw, &sig_to_buf(info.si_signo));
+        let buf = sig_to_buf(info.si_signo, queued_value(info));
+        let _ = unistd::write(wraw, &buf);
     }
 }
 
@@ -41,17 +88,18 @@ impl SignalHandler {
         let (r, w) = pipe::make_pipe()?;
         let mut actions = Vec::new();
 
-        for sig in Signal::iterator() {
-            match sig {
-                SIGKILL | SIGSTOP | SIGILL | SIGFPE | SIGSEGV => continue,
-                value => {
-                    let sigval = value as libc::c_int;
-                    let callback = make_callback(w.as_raw_fd());
-                    let sigid =
-                        unsafe { register_sigaction(sigval, callback) }?;
-                    actions.push(sigid);
-                }
-            }
+        let regular = Signal::iterator().filter_map(|sig| match sig {
+            SIGKILL | SIGSTOP | SIGILL | SIGFPE | SIGSEGV => None,
+            value => Some(value as libc::c_int),
+        });
+        let realtime = system::realtime_signals()
+            .into_iter()
+            .flat_map(|(min, max)| min..=max);
+
+        for sigval in regular.chain(realtime) {
+            let callback = make_callback(w.as_raw_fd());
+            let sigid = unsafe { register_sigaction(sigval, callback) }?;
+            actions.push(sigid);
         }
 
         Ok(SignalHandler {
@@ -61,7 +109,7 @@ impl SignalHandler {
         })
     }
 
-    pub fn wait(&self) -> WaitSignal {
+    pub fn wait(&self) -> WaitSignal<'_> {
         WaitSignal::new(&self._read)
     }
 }
@@ -75,14 +123,12 @@ impl<'a> WaitSignal<'a> {
         Self { inner: pipe }
     }
 
-    fn do_poll(&mut self, ctx: &mut Context<'_>) -> Poll<Result<Signal>> {
-        let mut buf = [0u8; mem::size_of::<libc::c_int>()];
+    fn do_poll(&mut self, ctx: &mut Context<'_>) -> Poll<Result<Caught>> {
+        let mut buf = [0u8; SIGSZ];
         match self.inner.poll_read(&mut buf, ctx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(val) => Poll::Ready(match val {
-                Ok(SIGSZ) => {
-                    Signal::from_c_int(buf_to_sig(buf)).map_err(nixerror)
-                }
+                Ok(SIGSZ) => Ok(buf_to_sig(buf)),
                 Ok(_) => Err(invalid_argument()),
                 Err(err) => Err(err),
             }),
@@ -91,7 +137,7 @@ impl<'a> WaitSignal<'a> {
 }
 
 impl<'a> Future for WaitSignal<'a> {
-    type Output = Result<Signal>;
+    type Output = Result<Caught>;
 
     fn poll(
         self: Pin<&mut Self>,
@@ -108,3 +154,21 @@ impl Drop for SignalHandler {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caught_round_trip() {
+        let plain = buf_to_sig(sig_to_buf(libc::SIGTERM, None));
+        assert_eq!((plain.signo, plain.value), (libc::SIGTERM, None));
+
+        let queued = buf_to_sig(sig_to_buf(libc::SIGUSR1, Some(-7)));
+        assert_eq!((queued.signo, queued.value), (libc::SIGUSR1, Some(-7)));
+
+        // a zero value is still a queued one
+        let zero = buf_to_sig(sig_to_buf(libc::SIGUSR2, Some(0)));
+        assert_eq!((zero.signo, zero.value), (libc::SIGUSR2, Some(0)));
+    }
+}
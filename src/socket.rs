@@ -5,15 +5,26 @@ use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 use crate::raw;
 use nix::sys::socket::{self, MsgFlags};
 
+/// Requested SO_SNDBUF and SO_RCVBUF, `None` keeps the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferSizes {
+    pub send: Option<usize>,
+    pub recv: Option<usize>,
+}
+
 #[derive(Debug)]
 pub struct Socket {
     inner: raw::Events,
+    framed: bool,
 }
 
 impl Socket {
     pub fn from_fd(fd: raw::Fd) -> Result<Self> {
+        let framed =
+            raw::blocking::socket_type(fd.raw())? == libc::SOCK_STREAM;
         Ok(Self {
             inner: raw::Events::from_fd(fd)?,
+            framed,
         })
     }
 
@@ -28,26 +39,67 @@ impl Socket {
     }
 
     pub fn send<'a, 'b>(&'a self, buf: &'b [u8]) -> raw::Send<'a, 'b> {
-        raw::send(self.as_events(), buf, MsgFlags::empty())
+        raw::send(self.as_events(), buf, MsgFlags::empty(), self.framed)
     }
 
     pub fn recv<'a, 'b>(&'a self, buf: &'b mut [u8]) -> raw::Recv<'a, 'b> {
-        raw::recv(self.as_events(), buf, MsgFlags::empty())
+        raw::recv(self.as_events(), buf, MsgFlags::empty(), self.framed)
     }
 
     pub fn sendfds<'a, 'b>(
         &'a self,
         buf: &'b [u8],
         fds: &'b [RawFd],
+        creds: Option<raw::Credentials>,
     ) -> raw::SendFds<'a, 'b> {
-        raw::sendfds(self.as_events(), buf, fds)
+        raw::sendfds(self.as_events(), buf, fds, creds, self.framed)
     }
 
-    pub fn recvfds<'a, 'b>(
+    pub fn recvfds<'a, 'b, 'c>(
         &'a self,
-        buf: &'b mut raw::CmsgBuf<'b>,
-    ) -> raw::RecvFds<'a, 'b> {
-        raw::recvfds(self.as_events(), buf)
+        buf: &'b mut raw::CmsgBuf<'c>,
+    ) -> raw::RecvFds<'a, 'b, 'c> {
+        raw::recvfds(self.as_events(), buf, self.framed)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn pass_credentials(&self) -> Result<()> {
+        socket::setsockopt(self.as_raw_fd(), socket::sockopt::PassCred, &true)
+            .map_err(raw::nixerror)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn peer_credentials(&self) -> Result<raw::Credentials> {
+        socket::getsockopt(self.as_raw_fd(), socket::sockopt::PeerCredentials)
+            .map(|creds| raw::Credentials {
+                pid: creds.pid(),
+                uid: creds.uid(),
+                gid: creds.gid(),
+            })
+            .map_err(raw::nixerror)
+    }
+
+    pub fn set_buffer_sizes(&self, sizes: BufferSizes) -> Result<()> {
+        let fd = self.as_raw_fd();
+        if let Some(ref size) = sizes.send {
+            socket::setsockopt(fd, socket::sockopt::SndBuf, size)
+                .map_err(raw::nixerror)?;
+        }
+        if let Some(ref size) = sizes.recv {
+            socket::setsockopt(fd, socket::sockopt::RcvBuf, size)
+                .map_err(raw::nixerror)?;
+        }
+        Ok(())
+    }
+
+    /// Effective sizes, the kernel doubles and clamps requested ones.
+    pub fn buffer_sizes(&self) -> Result<(usize, usize)> {
+        let fd = self.as_raw_fd();
+        let send = socket::getsockopt(fd, socket::sockopt::SndBuf)
+            .map_err(raw::nixerror)?;
+        let recv = socket::getsockopt(fd, socket::sockopt::RcvBuf)
+            .map_err(raw::nixerror)?;
+        Ok((send, recv))
     }
 
     #[allow(dead_code)]
@@ -64,6 +116,10 @@ impl Socket {
         &self.inner
     }
 
+    pub fn close(self) -> Result<()> {
+        self.inner.close()
+    }
+
     pub fn shutdown(&self, how: Shutdown) -> Result<()> {
         let nhow: socket::Shutdown = match how {
             Shutdown::Read => socket::Shutdown::Read,
@@ -0,0 +1,48 @@
+use std::io::{Result, Write};
+use std::path::Path;
+
+use log::debug;
+
+use crate::channel::{connect_failed, MessageChannel};
+use crate::messages as msg;
+use crate::raw::blocking::connect;
+use crate::runtime;
+use crate::socket::Socket;
+
+pub(crate) struct Args<'a> {
+    pub connect: &'a Path,
+    pub selector: msg::JobSelector,
+}
+
+async fn execute(
+    socket: Socket,
+    selector: msg::JobSelector,
+) -> Result<msg::JobState> {
+    MessageChannel::new(socket)
+        .request(&msg::RequestInput::JobStatus(selector))
+        .await
+}
+
+fn print(dest: &mut impl Write, state: msg::JobState) -> Result<()> {
+    match state {
+        msg::JobState::Running => writeln!(dest, "running"),
+        msg::JobState::Exited(code) => writeln!(dest, "exited {}", code),
+        msg::JobState::Signaled(sig) => writeln!(dest, "signaled {}", sig),
+        msg::JobState::Unknown => writeln!(dest, "unknown"),
+    }
+}
+
+pub(crate) fn command(args: &Args) -> Result<i32> {
+    debug!("connecting to {:?}", args.connect);
+    match connect(args.connect) {
+        Ok(fd) => runtime::new()?.block_on(async {
+            let state = execute(Socket::from_fd(fd)?, args.selector).await?;
+            print(&mut std::io::stdout().lock(), state)?;
+            match state {
+                msg::JobState::Unknown => Ok(1),
+                _ => Ok(0),
+            }
+        }),
+        Err(err) => Ok(connect_failed(args.connect, &err)),
+    }
+}
@@ -1,10 +1,10 @@
 use std::io::Result;
 use std::path::Path;
-
-use futures::TryFutureExt;
+use std::time::Duration;
 
 use log::{debug, error};
 
+use crate::channel::{connect_failed, MessageChannel};
 use crate::messages as msg;
 use crate::raw::blocking::connect;
 use crate::runtime;
@@ -12,34 +12,69 @@ use crate::socket::Socket;
 
 pub(crate) struct Args<'a> {
     pub connect: &'a Path,
+    pub timeout: Option<Duration>,
+    pub kill_grace: Option<Duration>,
+    pub drain: bool,
 }
 
-async fn execute(socket: Socket) -> Result<()> {
-    let mut buffer = Vec::with_capacity(16);
+// Whatever owns the path must answer a ping before it is told to stop
+async fn execute(socket: Socket, args: &Args<'_>) -> Result<bool> {
+    let request = if args.drain {
+        msg::RequestInput::Drain
+    } else {
+        msg::RequestInput::Stop(args.kill_grace)
+    };
 
+    let mut channel = MessageChannel::new(socket);
+    match channel
+        .request::<_, msg::Pong>(&msg::RequestInput::Ping)
+        .await
     {
-        let request = msg::RequestInput::Stop;
-        msg::encode_request(&mut buffer, &request)?;
+        Ok(pong) => debug!("server version {}", pong.version),
+        Err(err) => {
+            debug!("ping failed: {}", err);
+            return Ok(false);
+        }
     }
+    channel.send(&request).await?;
+
+    // server closes connection once request is handled
+    channel.recv::<()>().await.map(|_| true)
+}
 
-    socket.send(&buffer).map_ok(|_| ()).await
+fn finished(args: &Args, stopped: bool) -> i32 {
+    if stopped {
+        return 0;
+    }
+    error!(
+        "not a sidecar server\n    \
+         socket: {}",
+        args.connect.to_string_lossy(),
+    );
+    128
 }
 
 pub(crate) fn command(args: &Args) -> Result<i32> {
     debug!("connecting to {:?}", args.connect);
     match connect(args.connect) {
         Ok(fd) => runtime::new()?.block_on(async {
-            execute(Socket::from_fd(fd)?).await.map(|_| 0)
+            let socket = Socket::from_fd(fd)?;
+            match args.timeout {
+                Some(limit) => {
+                    match runtime::timeout(limit, execute(socket, args)).await
+                    {
+                        Ok(result) => result.map(|ok| finished(args, ok)),
+                        Err(_) => {
+                            error!("server did not respond in {:?}", limit);
+                            Ok(124)
+                        }
+                    }
+                }
+                None => {
+                    execute(socket, args).await.map(|ok| finished(args, ok))
+                }
+            }
         }),
-        Err(err) => {
-            error!(
-                "failed to connect\n    \
-                 socket: {}\n    \
-                 error:  {}",
-                args.connect.to_string_lossy(),
-                err,
-            );
-            Ok(128)
-        }
+        Err(err) => Ok(connect_failed(args.connect, &err)),
     }
 }
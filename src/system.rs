@@ -1,10 +1,11 @@
-use log::{error, warn};
+use log::{debug, error, warn};
 use nix::errno::Errno;
 use nix::sys::signal::{kill as _kill, killpg as _killpg, raise as _raise};
 use nix::unistd::{setpgid, setsid};
 use nix::Error as NixError;
 use std::io::Error as IoError;
 
+use crate::messages::{Resource, ResourceLimit};
 use crate::raw;
 use crate::raw::nixerror as error;
 pub(crate) use nix::sys::signal::Signal::{self, *};
@@ -29,6 +30,33 @@ pub(crate) fn set_death_signal(sig: Signal) -> Result<(), IoError> {
     prctl(PR_SET_PDEATHSIG, sigval, 0, 0, 0)
 }
 
+#[cfg(target_os = "linux")]
+pub(crate) fn death_signal() -> Result<i32, IoError> {
+    const PR_GET_PDEATHSIG: libc::c_int = 2;
+    let mut sigval: libc::c_int = 0;
+    let ptr = &mut sigval as *mut libc::c_int as libc::c_ulong;
+    prctl(PR_GET_PDEATHSIG, ptr, 0, 0, 0).map(|()| sigval)
+}
+
+/// Deliver `sig` to the calling process when `parent` exits.
+#[cfg(target_os = "linux")]
+pub(crate) fn bind_to_parent(parent: Pid, sig: Signal) -> Result<(), IoError> {
+    set_death_signal(sig)?;
+    // parent may have exited before the signal was armed
+    if Pid::parent() != parent {
+        return Err(IoError::from_raw_os_error(libc::ESRCH));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn bind_to_parent(
+    _parent: Pid,
+    _sig: Signal,
+) -> Result<(), IoError> {
+    Err(IoError::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
 pub(crate) fn signal_from_str(text: &str) -> Result<Signal, IoError> {
     match text.parse::<u32>() {
         Ok(signum) => Signal::from_c_int(signum as libc::c_int),
@@ -37,6 +65,80 @@ pub(crate) fn signal_from_str(text: &str) -> Result<Signal, IoError> {
     .map_err(error)
 }
 
+/// First and last realtime signal, none where they are not supported.
+#[cfg(target_os = "linux")]
+pub(crate) fn realtime_signals() -> Option<(i32, i32)> {
+    Some((libc::SIGRTMIN(), libc::SIGRTMAX()))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn realtime_signals() -> Option<(i32, i32)> {
+    None
+}
+
+pub(crate) fn is_realtime(signo: i32) -> bool {
+    match realtime_signals() {
+        Some((min, max)) => min <= signo && signo <= max,
+        None => false,
+    }
+}
+
+/// Either a regular signal or a realtime one.
+pub(crate) fn is_signal(signo: i32) -> bool {
+    Signal::from_c_int(signo).is_ok() || is_realtime(signo)
+}
+
+/// Realtime signals are named relative to `SIGRTMIN` as `kill -l` does.
+pub(crate) fn signal_name(signo: i32) -> String {
+    match Signal::from_c_int(signo) {
+        Ok(sig) => sig.to_string(),
+        Err(_) => match realtime_signals() {
+            Some((min, max)) if min <= signo && signo <= max => {
+                format!("SIGRTMIN+{}", signo - min)
+            }
+            _ => signo.to_string(),
+        },
+    }
+}
+
+/// Same as `signal_from_str` with `SIGRTMIN+N` and `SIGRTMAX-N` added.
+pub(crate) fn signal_number_from_str(text: &str) -> Result<i32, IoError> {
+    let realtime = |base: &str, sign: i32| -> Option<i32> {
+        let (min, max) = realtime_signals()?;
+        let start = if base == "SIGRTMIN" { min } else { max };
+        let rest = &text[base.len()..];
+        let offset = match rest.strip_prefix(if sign > 0 { '+' } else { '-' })
+        {
+            Some(digits) if digits.bytes().all(|b| b.is_ascii_digit()) => {
+                digits.parse::<i32>().ok()?
+            }
+            Some(_) => return None,
+            None if rest.is_empty() => 0,
+            None => return None,
+        };
+        start
+            .checked_add(sign * offset)
+            .filter(|signo| is_realtime(*signo))
+    };
+
+    let invalid = || {
+        IoError::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid realtime signal {:?}", text),
+        )
+    };
+    if text.starts_with("SIGRTMIN") {
+        return realtime("SIGRTMIN", 1).ok_or_else(invalid);
+    }
+    if text.starts_with("SIGRTMAX") {
+        return realtime("SIGRTMAX", -1).ok_or_else(invalid);
+    }
+    match text.parse::<i32>() {
+        Ok(signo) if is_realtime(signo) => Ok(signo),
+        _ => signal_from_str(text).map(|sig| sig as i32),
+    }
+}
+
 pub(crate) fn raise(sig: Signal) -> Result<(), IoError> {
     _raise(sig).map_err(error)
 }
@@ -49,6 +151,246 @@ pub(crate) fn new_session() -> Result<(), IoError> {
     setsid().map(|_| ()).map_err(error)
 }
 
+pub(crate) fn set_group(gid: u32) -> Result<(), IoError> {
+    Errno::result(unsafe { libc::setgid(gid) })
+        .map(drop)
+        .map_err(error)
+}
+
+/// Passwd entry fields a login environment is made of.
+pub(crate) struct Account {
+    pub name: Vec<u8>,
+    pub home: Vec<u8>,
+    pub shell: Vec<u8>,
+    pub gid: u32,
+}
+
+pub(crate) fn account(uid: u32) -> Result<Account, IoError> {
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    loop {
+        let res = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        };
+        match res {
+            0 => break,
+            libc::ERANGE if buffer.len() < 1 << 20 => {
+                let len = buffer.len() * 2;
+                buffer.resize(len, 0);
+            }
+            errno => return Err(IoError::from_raw_os_error(errno)),
+        }
+    }
+    if found.is_null() {
+        return Err(IoError::new(
+            std::io::ErrorKind::NotFound,
+            format!("no passwd entry for uid {}", uid),
+        ));
+    }
+
+    let field = |ptr: *const libc::c_char| {
+        if ptr.is_null() {
+            return Vec::new();
+        }
+        unsafe { std::ffi::CStr::from_ptr(ptr) }.to_bytes().to_vec()
+    };
+    Ok(Account {
+        name: field(entry.pw_name),
+        home: field(entry.pw_dir),
+        shell: field(entry.pw_shell),
+        gid: entry.pw_gid,
+    })
+}
+
+/// Groups `name` is a member of in the group database, `gid` first.
+pub(crate) fn group_list(name: &[u8], gid: u32) -> Result<Vec<u32>, IoError> {
+    let name = std::ffi::CString::new(name)
+        .map_err(|_| IoError::from_raw_os_error(libc::EINVAL))?;
+    let mut groups = vec![0u32; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let res = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                gid as _,
+                groups.as_mut_ptr() as *mut _,
+                &mut count,
+            )
+        };
+        if res >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        if groups.len() >= 1 << 16 {
+            return Err(IoError::from_raw_os_error(libc::ERANGE));
+        }
+        let len = (count as usize).max(groups.len() * 2);
+        groups.resize(len, 0);
+    }
+}
+
+pub(crate) fn set_umask(mask: u32) {
+    unsafe { libc::umask(mask as libc::mode_t) };
+}
+
+/// Replaces supplementary groups, an empty list drops them all.
+pub(crate) fn set_groups(groups: &[u32]) -> Result<(), IoError> {
+    Errno::result(unsafe {
+        libc::setgroups(groups.len() as _, groups.as_ptr() as *const _)
+    })
+    .map(drop)
+    .map_err(error)
+}
+
+pub(crate) fn set_user(uid: u32) -> Result<(), IoError> {
+    Errno::result(unsafe { libc::setuid(uid) })
+        .map(drop)
+        .map_err(error)
+}
+
+pub(crate) fn change_root(path: &std::ffi::CStr) -> Result<(), IoError> {
+    Errno::result(unsafe { libc::chroot(path.as_ptr()) })
+        .map(drop)
+        .map_err(error)
+}
+
+pub(crate) fn change_dir(path: &std::ffi::CStr) -> Result<(), IoError> {
+    Errno::result(unsafe { libc::chdir(path.as_ptr()) })
+        .map(drop)
+        .map_err(error)
+}
+
+pub(crate) fn disable_core_dumps() -> Result<(), IoError> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    Errno::result(unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) })
+        .map(drop)
+        .map_err(error)
+}
+
+fn resource_id(resource: Resource) -> i32 {
+    let id = match resource {
+        Resource::Core => libc::RLIMIT_CORE,
+        Resource::Cpu => libc::RLIMIT_CPU,
+        Resource::Data => libc::RLIMIT_DATA,
+        Resource::FileSize => libc::RLIMIT_FSIZE,
+        Resource::NoFile => libc::RLIMIT_NOFILE,
+        Resource::Stack => libc::RLIMIT_STACK,
+        Resource::AddressSpace => libc::RLIMIT_AS,
+        Resource::NProc => libc::RLIMIT_NPROC,
+        Resource::MemLock => libc::RLIMIT_MEMLOCK,
+    };
+    id as i32
+}
+
+/// Resource named as in `ulimit` and `prlimit`, e.g. `nofile`.
+pub(crate) fn resource_from_name(name: &str) -> Option<Resource> {
+    Some(match name {
+        "core" => Resource::Core,
+        "cpu" => Resource::Cpu,
+        "data" => Resource::Data,
+        "fsize" => Resource::FileSize,
+        "nofile" => Resource::NoFile,
+        "stack" => Resource::Stack,
+        "as" => Resource::AddressSpace,
+        "nproc" => Resource::NProc,
+        "memlock" => Resource::MemLock,
+        _ => return None,
+    })
+}
+
+// rlim_t is not u64 everywhere
+#[allow(clippy::unnecessary_cast)]
+fn from_rlim(value: libc::rlim_t) -> Option<u64> {
+    match value {
+        libc::RLIM_INFINITY => None,
+        value => Some(value as u64),
+    }
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn to_rlim(value: Option<u64>) -> libc::rlim_t {
+    value.map_or(libc::RLIM_INFINITY, |v| v as libc::rlim_t)
+}
+
+pub(crate) fn get_resource_limit(
+    resource: Resource,
+) -> Result<ResourceLimit, IoError> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let id = resource_id(resource);
+    Errno::result(unsafe { libc::getrlimit(id as _, &mut limit) })
+        .map(|_| ResourceLimit {
+            resource,
+            soft: from_rlim(limit.rlim_cur),
+            hard: from_rlim(limit.rlim_max),
+        })
+        .map_err(error)
+}
+
+pub(crate) fn set_resource_limit(
+    limit: &ResourceLimit,
+) -> Result<(), IoError> {
+    let value = libc::rlimit {
+        rlim_cur: to_rlim(limit.soft),
+        rlim_max: to_rlim(limit.hard),
+    };
+    let id = resource_id(limit.resource);
+    Errno::result(unsafe { libc::setrlimit(id as _, &value) })
+        .map(drop)
+        .map_err(error)
+}
+
+pub(crate) const INHERITED_LIMITS: &[Resource] = &[
+    Resource::Core,
+    Resource::Cpu,
+    Resource::Data,
+    Resource::FileSize,
+    Resource::NoFile,
+    Resource::Stack,
+    Resource::AddressSpace,
+    Resource::NProc,
+    Resource::MemLock,
+];
+
+pub(crate) fn change_dir_fd(fd: raw::RawFd) -> Result<(), IoError> {
+    Errno::result(unsafe { libc::fchdir(fd) })
+        .map(drop)
+        .map_err(error)
+}
+
+/// Places each `(source, target)` descriptor at `target`. Sources are
+/// first copied above every target, so targets may overlap them;
+/// `scratch` holds a slot per pair as pre_exec must not allocate.
+pub(crate) fn map_fds(
+    pairs: &[(raw::RawFd, raw::RawFd)],
+    scratch: &mut [raw::RawFd],
+) -> Result<(), IoError> {
+    let above = pairs.iter().map(|p| p.1).max().unwrap_or(0) + 1;
+    for (slot, (source, _)) in scratch.iter_mut().zip(pairs) {
+        *slot = Errno::result(unsafe {
+            libc::fcntl(*source, libc::F_DUPFD_CLOEXEC, above)
+        })
+        .map_err(error)?;
+    }
+    // copies above are close-on-exec, targets are not
+    for (copy, (_, target)) in scratch.iter().zip(pairs) {
+        Errno::result(unsafe { libc::dup2(*copy, *target) }).map_err(error)?;
+    }
+    Ok(())
+}
+
 pub(crate) fn nohup() -> Result<(), IoError> {
     match unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN) } {
         libc::SIG_ERR => Err(IoError::last_os_error()),
@@ -66,20 +408,47 @@ pub(crate) fn kill(child: Pid, signal: Signal) {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn queue_signal(pid: Pid, signo: i32, value: i32) -> Result<(), IoError> {
+    let value = libc::sigval {
+        sival_ptr: value as isize as *mut libc::c_void,
+    };
+    let res = unsafe { libc::sigqueue(pid.as_raw(), signo, value) };
+    Errno::result(res).map(drop).map_err(error)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn queue_signal(_pid: Pid, _signo: i32, _value: i32) -> Result<(), IoError> {
+    Err(IoError::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+/// Sends `signo` to `pid` or to the group it leads, failures are left
+/// to the caller. A `value` is queued along with it, which only a
+/// single process can receive.
+pub(crate) fn send_signal(
+    pid: Pid,
+    signo: i32,
+    value: Option<i32>,
+    group: bool,
+) -> Result<(), IoError> {
+    let res = match (value, group) {
+        (Some(_), true) => return Err(raw::invalid_argument()),
+        (Some(value), false) => return queue_signal(pid, signo, value),
+        (None, true) => unsafe { libc::killpg(pid.as_raw(), signo) },
+        (None, false) => unsafe { libc::kill(pid.as_raw(), signo) },
+    };
+    Errno::result(res).map(drop).map_err(error)
+}
+
 pub(crate) fn killpg(child: Pid, signal: Signal) {
-    match _killpg(child, signal) {
-        Err(NixError::Sys(Errno::ESRCH)) => {
-            warn!(
-                "failed to send signal to group process={:?} no such group",
-                child.as_raw()
+    match signal_group(child, signal) {
+        Ok(true) => (),
+        Ok(false) => {
+            debug!(
+                "process group={:?} already gone, signal={} not sent",
+                child.as_raw(),
+                signal
             );
-            if let Err(err) = _kill(child, signal) {
-                error!(
-                    "failed to send signal to process={:?} err={:?}",
-                    child.as_raw(),
-                    err
-                );
-            }
         }
         Err(err) => {
             warn!(
@@ -88,18 +457,212 @@ pub(crate) fn killpg(child: Pid, signal: Signal) {
                 err
             );
         }
-        _ => (),
     };
 }
 
+/// Whether the group still had anyone to receive `signal`.
+fn signal_group(child: Pid, signal: Signal) -> Result<bool, NixError> {
+    match _killpg(child, signal) {
+        Ok(()) => Ok(true),
+        // group is empty, leader is gone as well
+        Err(NixError::Sys(Errno::ESRCH)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_session(pid: i32) -> Option<i32> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // pid (comm) state ppid pgrp session ...
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(3)?.parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+fn session_members(sid: Pid) -> Vec<Pid> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("failed to scan /proc: {}", err);
+            return Vec::new();
+        }
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| process_session(*pid) == Some(sid.as_raw()))
+        .map(Pid::from_raw)
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn kill_session(sid: Pid, signal: Signal) {
+    killpg(sid, signal);
+    for pid in session_members(sid) {
+        match _kill(pid, signal) {
+            Ok(()) => debug!(
+                "session={:?} process={:?} sent signal={}",
+                sid.as_raw(),
+                pid.as_raw(),
+                signal
+            ),
+            Err(NixError::Sys(Errno::ESRCH)) => (),
+            Err(err) => warn!(
+                "failed to send signal to process={:?} err={}",
+                pid.as_raw(),
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn kill_session(sid: Pid, signal: Signal) {
+    killpg(sid, signal);
+}
+
+pub(crate) fn is_alive(pid: Pid) -> bool {
+    !matches!(_kill(pid, None), Err(NixError::Sys(Errno::ESRCH)))
+}
+
 pub(crate) fn is_valid_fd(fd: raw::RawFd) -> bool {
     let ret = unsafe { libc::fcntl(fd, libc::F_GETFD) };
     ret != -1 || nix::errno::errno() != libc::EBADF
 }
 
+/// What `fd` refers to for diagnostics, `None` when it is not open.
+pub(crate) fn fd_kind(fd: raw::RawFd) -> Option<&'static str> {
+    use nix::sys::stat::{fstat, SFlag};
+
+    let stat = fstat(fd).ok()?;
+    let kind = match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+        SFlag::S_IFREG => "file",
+        SFlag::S_IFDIR => "directory",
+        SFlag::S_IFIFO => "pipe",
+        SFlag::S_IFSOCK => "socket",
+        SFlag::S_IFBLK => "block device",
+        SFlag::S_IFCHR if nix::unistd::isatty(fd).unwrap_or(false) => {
+            "terminal"
+        }
+        SFlag::S_IFCHR => "character device",
+        _ => "unknown file",
+    };
+    Some(kind)
+}
+
 pub(crate) fn disable_inherit_stdio() -> Result<(), IoError> {
     for fd in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
         raw::flags::set_cloexec(*fd)?;
     }
     Ok(())
 }
+
+/// Creates a fresh directory named after `prefix` with random suffix,
+/// accessible to the owner only.
+pub(crate) fn make_temp_dir(
+    prefix: &std::path::Path,
+) -> Result<std::path::PathBuf, IoError> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let mut template = prefix.as_os_str().as_bytes().to_vec();
+    template.extend_from_slice(b"XXXXXX\0");
+    let res = unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut _) };
+    if res.is_null() {
+        return Err(IoError::last_os_error());
+    }
+    template.pop();
+    Ok(OsString::from_vec(template).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command};
+
+    fn group_leader(script: &str) -> Child {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        unsafe {
+            cmd.pre_exec(|| {
+                setpgid(Pid::from_raw(0), Pid::from_raw(0))
+                    .map_err(|_| IoError::last_os_error())
+            });
+        }
+        cmd.spawn().unwrap()
+    }
+
+    #[test]
+    fn killpg_group_gone_with_leader() {
+        let mut child = group_leader("exit 0");
+        let pid = Pid::from_raw(child.id() as i32);
+        assert!(child.wait().unwrap().success());
+        assert_eq!(signal_group(pid, Signal::SIGTERM), Ok(false));
+    }
+
+    #[test]
+    fn killpg_reaches_live_group() {
+        let mut child = group_leader("exec sleep 60");
+        let pid = Pid::from_raw(child.id() as i32);
+        assert_eq!(signal_group(pid, Signal::SIGKILL), Ok(true));
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn killpg_reaches_group_without_leader() {
+        let mut child = group_leader("sleep 60 & exit 0");
+        let pid = Pid::from_raw(child.id() as i32);
+        assert!(child.wait().unwrap().success());
+        assert_eq!(signal_group(pid, Signal::SIGKILL), Ok(true));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn realtime_signal_names() {
+        let (min, max) = realtime_signals().unwrap();
+        let parse = |text: &str| signal_number_from_str(text).ok();
+        assert_eq!(parse("SIGRTMIN"), Some(min));
+        assert_eq!(parse("SIGRTMIN+3"), Some(min + 3));
+        assert_eq!(parse("SIGRTMAX"), Some(max));
+        assert_eq!(parse("SIGRTMAX-1"), Some(max - 1));
+        assert_eq!(parse(&min.to_string()), Some(min));
+
+        for text in &[
+            "SIGRTMAX+1",
+            "SIGRTMIN+99",
+            "SIGRTMIN-1",
+            "SIGRTMIN+",
+            "SIGRTMIN++3",
+            "SIGRTMIN+2147483647",
+            "SIGRTMAX-2147483647",
+            "SIGRTMINE",
+        ] {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn signal_name_round_trip() {
+        assert_eq!(
+            signal_number_from_str("SIGTERM").ok(),
+            Some(libc::SIGTERM)
+        );
+        assert_eq!(signal_number_from_str("9").ok(), Some(libc::SIGKILL));
+
+        let regular = Signal::iterator().map(|sig| sig as i32);
+        let realtime = match realtime_signals() {
+            Some((min, max)) => min..max + 1,
+            None => 0..0,
+        };
+        for signo in regular.chain(realtime) {
+            let name = signal_name(signo);
+            assert_eq!(
+                signal_number_from_str(&name).ok(),
+                Some(signo),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -6,6 +6,7 @@ use libc::{self, ioctl};
 use nix::fcntl::{open, OFlag};
 use nix::sys::stat::Mode;
 
+use crate::messages::WinSize;
 use crate::raw::{nixerror as error, Fd};
 
 #[cfg(target_os = "linux")]
@@ -21,14 +22,18 @@ mod private {
     pub(super) const TIOCNOTTY: u64 = 0x20007471;
 }
 
-#[cfg(target_os = "bsd")]
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
 mod private {
     pub(super) use libc::{TIOCNOTTY, TIOCSCTTY};
 }
 
 use private::{TIOCNOTTY, TIOCSCTTY};
 
-#[allow(dead_code)]
 pub(crate) fn set_controlling_terminal(fd: RawFd) -> Result<(), IoError> {
     if unsafe { ioctl(fd, TIOCSCTTY, 1) } != 0 {
         Err(IoError::last_os_error())
@@ -57,7 +62,69 @@ pub(crate) fn disconnect_controlling_terminal() -> Result<(), IoError> {
     }
 }
 
-#[allow(dead_code)]
 pub(crate) fn ttyfd() -> Result<Fd, IoError> {
     tty_open(OFlag::O_RDWR)
 }
+
+pub(crate) fn window_size(fd: RawFd) -> Result<WinSize, IoError> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { ioctl(fd, libc::TIOCGWINSZ, &mut size) } != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(WinSize {
+        rows: size.ws_row,
+        cols: size.ws_col,
+        x: size.ws_xpixel,
+        y: size.ws_ypixel,
+    })
+}
+
+pub(crate) fn set_window_size(
+    fd: RawFd,
+    size: &WinSize,
+) -> Result<(), IoError> {
+    let size = libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: size.x,
+        ws_ypixel: size.y,
+    };
+    if unsafe { ioctl(fd, libc::TIOCSWINSZ, &size) } != 0 {
+        Err(IoError::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn slave_name(master: &nix::pty::PtyMaster) -> nix::Result<String> {
+    nix::pty::ptsname_r(master)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn slave_name(master: &nix::pty::PtyMaster) -> nix::Result<String> {
+    // not reentrant, only the runtime thread opens terminals
+    unsafe { nix::pty::ptsname(master) }
+}
+
+/// Opens a pseudo-terminal pair, both ends close-on-exec. Output is not
+/// post-processed, lines keep their `\n` endings as with a pipe.
+pub(crate) fn open_pty() -> Result<(Fd, Fd), IoError> {
+    use nix::pty::{grantpt, posix_openpt, unlockpt};
+    use nix::sys::termios::{tcgetattr, tcsetattr, OutputFlags, SetArg};
+    use std::os::unix::io::IntoRawFd;
+
+    let flags = OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC;
+    let master = posix_openpt(flags).map_err(error)?;
+    grantpt(&master).map_err(error)?;
+    unlockpt(&master).map_err(error)?;
+    let name = slave_name(&master).map_err(error)?;
+    let slave = open(name.as_str(), flags, Mode::empty())
+        .map(Fd::new)
+        .map_err(error)?;
+
+    let mut termios = tcgetattr(slave.raw()).map_err(error)?;
+    termios.output_flags.remove(OutputFlags::OPOST);
+    tcsetattr(slave.raw(), SetArg::TCSANOW, &termios).map_err(error)?;
+    Ok((Fd::new(master.into_raw_fd()), slave))
+}
@@ -0,0 +1,155 @@
+//! Runs the built binary against a server of its own per test.
+
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+pub const SIDECAR: &str = env!("CARGO_BIN_EXE_sidecar");
+
+pub struct Server {
+    pub dir: PathBuf,
+    pub socket: PathBuf,
+    child: Child,
+    // kept open, the server may print more than the socket path
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Server {
+    /// Listens in a fresh directory named after the test, returns once
+    /// the socket is bound.
+    pub fn start<S: AsRef<OsStr>>(name: &str, args: &[S]) -> Server {
+        Server::supervise(name, args, &[])
+    }
+
+    /// Same as `start` with `program` run by the server, it shares the
+    /// server's stdout.
+    pub fn supervise<S: AsRef<OsStr>>(
+        name: &str,
+        args: &[S],
+        program: &[&str],
+    ) -> Server {
+        let dir = test_dir(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("sock");
+
+        let mut child = Command::new(SIDECAR)
+            .arg("start")
+            .arg("--print-socket")
+            .arg("--log-file")
+            .arg(dir.join("server.log"))
+            .args(args)
+            .arg(&socket)
+            .args(program_args(program))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        assert!(!line.is_empty(), "server did not start");
+
+        Server {
+            dir,
+            socket,
+            child,
+            stdout,
+        }
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.child.id() as i32
+    }
+
+    /// Next line the server or its supervised program printed.
+    pub fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.stdout.read_line(&mut line).unwrap();
+        line
+    }
+
+    pub fn signal(&self, signal: libc::c_int) {
+        assert_eq!(unsafe { libc::kill(self.pid(), signal) }, 0);
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Server log written so far, warnings and errors only.
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.path("server.log")).unwrap_or_default()
+    }
+
+    /// `sidecar exec` connected to this server.
+    pub fn exec<S: AsRef<OsStr>>(&self, args: &[S]) -> Command {
+        let mut cmd = Command::new(SIDECAR);
+        cmd.arg("exec")
+            .arg("--connect")
+            .arg(&self.socket)
+            .args(args);
+        cmd
+    }
+
+    /// Any other subcommand, the socket goes last.
+    pub fn command(&self, name: &str, args: &[&str]) -> Command {
+        let mut cmd = Command::new(SIDECAR);
+        cmd.arg(name).args(args).arg(&self.socket);
+        cmd
+    }
+
+    /// Waits up to `limit` for the server to exit on its own.
+    pub fn wait(&mut self, limit: Duration) -> Option<ExitStatus> {
+        let mut status = None;
+        let child = &mut self.child;
+        wait_until(limit, || {
+            status = child.try_wait().unwrap();
+            status.is_some()
+        });
+        status
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn program_args<'a>(program: &[&'a str]) -> Vec<&'a str> {
+    if program.is_empty() {
+        return Vec::new();
+    }
+    let mut args = vec!["--"];
+    args.extend_from_slice(program);
+    args
+}
+
+/// Directory of the server `name`, for paths given before it starts.
+pub fn test_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "sidecar-{}-{}",
+        name,
+        std::process::id()
+    ))
+}
+
+/// Polls `ready` for up to `limit`.
+pub fn wait_until(limit: Duration, mut ready: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + limit;
+    while Instant::now() < deadline {
+        if ready() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
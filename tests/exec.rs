@@ -0,0 +1,70 @@
+mod common;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::unistd::{close, pipe2, write};
+
+use common::{Server, SIDECAR};
+
+#[test]
+fn extra_descriptor_reaches_child() {
+    let server = Server::start::<&str>("extra-fd", &[]);
+    let (read, writer) = pipe2(OFlag::O_CLOEXEC).unwrap();
+    write(writer, b"through fd 3").unwrap();
+    close(writer).unwrap();
+
+    let fd = format!("--fd=3={}", read);
+    let mut exec = server.exec(&[fd.as_str(), "--", "sh", "-c", "cat <&3"]);
+    exec.stdin(Stdio::null());
+    // only the client gets the read end
+    unsafe {
+        exec.pre_exec(move || {
+            fcntl(read, FcntlArg::F_SETFD(FdFlag::empty()))
+                .map(drop)
+                .map_err(|_| std::io::Error::last_os_error())
+        });
+    }
+    let output = exec.output().unwrap();
+    close(read).unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"through fd 3");
+}
+
+#[test]
+fn non_utf8_program_and_argument() {
+    let server = Server::start::<&str>("non-utf8", &[]);
+    let program = server.path("").join(OsStr::from_bytes(b"prog-\xff"));
+    fs::write(&program, "#!/bin/sh\nprintf %s \"$1\"\n").unwrap();
+    fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let args = [
+        OsStr::new("--"),
+        program.as_os_str(),
+        OsStr::from_bytes(b"arg-\xfe"),
+    ];
+    let output = server.exec(&args).output().unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"arg-\xfe");
+}
+
+#[test]
+fn too_many_extra_descriptors_are_refused() {
+    let mut args = vec!["exec", "--connect", "/nonexistent/sock"];
+    let fds: Vec<String> =
+        (3..20).map(|target| format!("--fd={}=2", target)).collect();
+    args.extend(fds.iter().map(String::as_str));
+    args.extend(&["--", "true"]);
+    let output = Command::new(SIDECAR).args(&args).output().unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("at most 16"), "{}", stderr);
+}
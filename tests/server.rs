@@ -0,0 +1,141 @@
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Stdio};
+use std::time::Duration;
+
+use common::{test_dir, wait_until, Server};
+
+// enough for DRAIN_TIMEOUT and the usual grace of a test job
+const SHUTDOWN: Duration = Duration::from_secs(10);
+
+#[test]
+fn exit_during_sigterm_reaches_client() {
+    let mut server = Server::start::<&str>("sigterm-exit", &[]);
+    // the job asks for the shutdown itself and exits right away
+    let script = "trap '' TERM; kill -TERM $PPID; exit 7";
+    let output = server.exec(&["--", "sh", "-c", script]).output().unwrap();
+
+    assert_eq!(output.status.code(), Some(7), "{:?}", output);
+    let status = server.wait(SHUTDOWN).expect("server still running");
+    assert!(status.success());
+}
+
+// spawned client of a job that says when it runs, then takes `secs`
+fn running_job(server: &Server, code: i32, secs: f32) -> Child {
+    let script = format!("echo started; sleep {}; exit {}", secs, code);
+    let mut client = server
+        .exec(&["--", "sh", "-c", &script])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    let stdout = client.stdout.as_mut().unwrap();
+    BufReader::new(stdout).read_line(&mut line).unwrap();
+    assert_eq!(line, "started\n");
+    client
+}
+
+#[test]
+fn drain_lets_running_job_finish() {
+    let mut server = Server::start::<&str>("drain-job", &[]);
+    let client = running_job(&server, 3, 1.0);
+
+    let drain = server.command("drain", &[]).status().unwrap();
+    assert!(drain.success());
+    assert!(server.wait(Duration::from_millis(300)).is_none());
+
+    let output = client.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let status = server.wait(SHUTDOWN).expect("server still running");
+    assert!(status.success());
+}
+
+#[test]
+fn unread_result_releases_connection() {
+    let mut server =
+        Server::start("unread-result", &["--socket-sndbuf", "4096"]);
+    // fills the client's stdout pipe that nobody reads, then the
+    // connection, the rest fits into the job's own stdout pipe
+    let written = server.path("written");
+    let script = format!("head -c 100000 /dev/zero; touch {:?}", written);
+    let mut client = server
+        .exec(&["--relay", "--", "sh", "-c", &script])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    assert!(wait_until(SHUTDOWN, || written.exists()));
+
+    let drain = server.command("drain", &[]).status().unwrap();
+    assert!(drain.success());
+    let status = server.wait(SHUTDOWN).expect("connection not released");
+    assert!(status.success());
+    assert!(server.log().contains("client did not take result"));
+
+    client.kill().unwrap();
+    client.wait().unwrap();
+}
+
+#[test]
+fn exit_during_shutdown_is_reaped() {
+    let dump = test_dir("shutdown-reap").join("jobs.json");
+    let dump_arg = dump.to_str().unwrap();
+    let mut server =
+        Server::start("shutdown-reap", &["--dump-jobs", dump_arg]);
+    // left by its client, exits once the server is shutting down
+    let script = "trap '' TERM; sleep 0.2; kill -TERM $PPID; exit 5";
+    let started = server
+        .exec(&["--no-wait", "--", "sh", "-c", script])
+        .status()
+        .unwrap();
+    assert!(started.success());
+
+    let status = server.wait(SHUTDOWN).expect("server still running");
+    assert!(status.success());
+    let jobs = std::fs::read_to_string(&dump).unwrap();
+    assert!(jobs.contains(r#""status":"exited","code":5"#), "{}", jobs);
+}
+
+#[test]
+fn draining_socket_is_closed_and_removed() {
+    let mut server = Server::start::<&str>("drain-socket", &[]);
+    let client = running_job(&server, 0, 1.0);
+    assert!(server.command("drain", &[]).status().unwrap().success());
+
+    // still serving its job, but neither bound nor reachable
+    assert!(server.wait(Duration::from_millis(100)).is_none());
+    assert!(!server.socket.exists());
+    let refused = server.exec(&["--", "true"]).output().unwrap();
+    assert!(!refused.status.success());
+
+    assert!(client.wait_with_output().unwrap().status.success());
+    assert!(server
+        .wait(SHUTDOWN)
+        .expect("server still running")
+        .success());
+}
+
+#[test]
+fn stopped_server_removes_socket() {
+    let mut server = Server::start::<&str>("stop-socket", &[]);
+    assert!(server.command("stop", &[]).status().unwrap().success());
+    assert!(server
+        .wait(SHUTDOWN)
+        .expect("server still running")
+        .success());
+    assert!(!server.socket.exists());
+}
+
+#[test]
+fn restart_signal_replaces_supervised_program() {
+    let args = ["--restart-on-signal", "SIGUSR1"];
+    let program = ["sh", "-c", "echo $$; exec sleep 60"];
+    let mut server = Server::supervise("restart", &args, &program);
+    let first = server.read_line();
+    assert!(!first.is_empty());
+
+    server.signal(libc::SIGUSR1);
+    let second = server.read_line();
+    assert!(!second.is_empty());
+    assert_ne!(first, second);
+}